@@ -106,6 +106,7 @@ impl Client {
                     channels: Vec::new(),
                 }));
         }
+        self.0.session.flush().await?;
 
         Ok(user)
     }
@@ -163,6 +164,7 @@ impl Client {
                 // if there's a need to connect back to the old DC after having logged in.
                 self.0.handle.disconnect_from_dc(old_dc_id);
                 self.0.session.set_home_dc_id(new_dc_id);
+                self.0.session.flush().await?;
                 self.invoke(&request).await?
             }
             Err(e) => return Err(e.into()),
@@ -243,6 +245,7 @@ impl Client {
                 // if there's a need to connect back to the old DC after having logged in.
                 self.0.handle.disconnect_from_dc(old_dc_id);
                 self.0.session.set_home_dc_id(new_dc_id);
+                self.0.session.flush().await?;
                 match self.invoke(&request).await? {
                     SC::Code(code) => code,
                     SC::Success(_) => panic!("should not have logged in yet"),
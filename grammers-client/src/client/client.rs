@@ -6,7 +6,11 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 use std::sync::Arc;
+use std::time::Duration;
 
+use super::middleware::Middleware;
+use super::rate_limiter::RateLimiter;
+use super::takeout::TakeoutSession;
 use grammers_mtsender::SenderPoolHandle;
 use grammers_session::Session;
 
@@ -34,6 +38,34 @@ pub struct ClientConfiguration {
     /// The cached peers are then usable by other methods such as [`Client::resolve_peer`]
     /// for as long as the same persisted session is used.
     pub auto_cache_peers: bool,
+
+    /// How long to wait for the response to each request before giving up on it.
+    ///
+    /// Requests that take longer are cancelled and fail with [`InvocationError::Timeout`].
+    /// The time spent sleeping on flood-waits counts towards the timeout, so those errors
+    /// are returned instead if the sleep would not finish in time.
+    ///
+    /// By default, requests have no timeout. It can also be set for individual requests
+    /// with [`Client::invoke_with_timeout`].
+    ///
+    /// [`InvocationError::Timeout`]: grammers_mtsender::InvocationError::Timeout
+    pub request_timeout: Option<Duration>,
+
+    /// Middlewares that every request goes through before being sent, in order.
+    ///
+    /// They run before the built-in handling of flood-waits, and can be used to customize
+    /// how requests are retried, rate-limited or logged. Refer to [`Middleware`] for details.
+    ///
+    /// By default, there are no middlewares.
+    pub middlewares: Vec<Arc<dyn Middleware>>,
+
+    /// Rate limiter used to delay requests before they would run into flood-waits.
+    ///
+    /// It applies to every request sent, including retries after sleeping on a flood-wait,
+    /// and learns from all flood-waits that occur. Refer to [`RateLimiter`] for details.
+    ///
+    /// By default, requests are not rate-limited.
+    pub rate_limiter: Option<RateLimiter>,
 }
 
 pub struct UpdatesConfiguration {
@@ -84,13 +116,20 @@ pub(crate) struct ClientInner {
 ///
 /// [`Session`]: grammers_session::Session
 #[derive(Clone)]
-pub struct Client(pub(crate) Arc<ClientInner>);
+pub struct Client(
+    pub(crate) Arc<ClientInner>,
+    /// Takeout session every request is wrapped in while it's not finished, if any.
+    pub(crate) Option<Arc<TakeoutSession>>,
+);
 
 impl Default for ClientConfiguration {
     fn default() -> Self {
         Self {
             flood_sleep_threshold: 60,
             auto_cache_peers: true,
+            request_timeout: None,
+            middlewares: Vec::new(),
+            rate_limiter: None,
         }
     }
 }
@@ -256,7 +256,7 @@ impl Client {
         let part_index = Arc::new(tokio::sync::Mutex::new(0));
         let mut tasks = vec![];
         let home_dc_id = self.0.session.home_dc_id();
-        for _ in 0..workers {
+        for worker in 0..workers {
             let location = location.clone();
             let tx = tx.clone();
             let part_index = part_index.clone();
@@ -287,7 +287,7 @@ impl Client {
                         offset,
                         limit: MAX_CHUNK_SIZE,
                     };
-                    match client.invoke_in_dc(dc, request).await {
+                    match client.invoke_in_dc_lane(dc, worker, request).await {
                         Ok(tl::enums::upload::File::File(file)) => {
                             tx.send((offset as u64, file.bytes)).unwrap();
                         }
@@ -398,18 +398,23 @@ impl Client {
         if big_file {
             let parts = Arc::new(parts);
             let mut tasks = FuturesUnordered::new();
-            for _ in 0..WORKER_COUNT {
+            let home_dc_id = self.0.session.home_dc_id();
+            for worker in 0..WORKER_COUNT {
                 let handle = self.clone();
                 let parts = Arc::clone(&parts);
                 let task = async move {
                     while let Some((part, bytes)) = parts.next_part().await? {
                         let ok = handle
-                            .invoke(&tl::functions::upload::SaveBigFilePart {
-                                file_id,
-                                file_part: part,
-                                file_total_parts: total_parts,
-                                bytes,
-                            })
+                            .invoke_in_dc_lane(
+                                home_dc_id,
+                                worker,
+                                &tl::functions::upload::SaveBigFilePart {
+                                    file_id,
+                                    file_part: part,
+                                    file_total_parts: total_parts,
+                                    bytes,
+                                },
+                            )
                             .await
                             .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
 
@@ -0,0 +1,192 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Extension point to customize how every request made by the [`Client`] is invoked.
+//!
+//! [`Client`]: crate::Client
+use super::Client;
+use futures_util::future::BoxFuture;
+use grammers_mtsender::InvocationError;
+use grammers_tl_types as tl;
+use std::sync::Arc;
+use tokio::time::Instant;
+
+/// A serialized request on its way to be invoked, as seen by the [`Middleware`]s.
+#[derive(Clone, Debug)]
+pub struct Invocation {
+    /// Datacenter where the request will be invoked.
+    pub dc_id: i32,
+    /// Connection to the datacenter that will be used, or the least busy one if `None`.
+    pub lane: Option<usize>,
+    /// Moment by which the response must have arrived, if any.
+    pub deadline: Option<Instant>,
+    /// The serialized request.
+    pub body: Vec<u8>,
+}
+
+/// Hook that wraps the invocation of every request made by the [`Client`].
+///
+/// Middlewares are configured via [`ClientConfiguration::middlewares`], and run in order.
+/// Each of them decides whether, when and how many times to call the [`Next`] one,
+/// and may change the [`Invocation`] or the result in the process. After the last one,
+/// the built-in flood-wait handling takes place before the request is finally sent.
+///
+/// This makes it possible to implement custom retry policies, rate limiting, metrics,
+/// logging or error rewriting without having to wrap every call to the [`Client`].
+///
+/// # Examples
+///
+/// Retrying requests that fail due to internal server errors:
+///
+/// ```
+/// use futures_util::future::BoxFuture;
+/// use grammers_client::InvocationError;
+/// use grammers_client::client::middleware::{Invocation, Middleware, Next};
+///
+/// struct RetryOnServerError;
+///
+/// impl Middleware for RetryOnServerError {
+///     fn handle<'a>(
+///         &'a self,
+///         invocation: Invocation,
+///         next: Next<'a>,
+///     ) -> BoxFuture<'a, Result<Vec<u8>, InvocationError>> {
+///         Box::pin(async move {
+///             match next.clone().run(invocation.clone()).await {
+///                 Err(InvocationError::Rpc(err)) if err.code == 500 || err.code == -503 => {
+///                     next.run(invocation).await
+///                 }
+///                 result => result,
+///             }
+///         })
+///     }
+/// }
+/// ```
+///
+/// [`Client`]: crate::Client
+/// [`ClientConfiguration::middlewares`]: crate::ClientConfiguration::middlewares
+pub trait Middleware: Send + Sync {
+    /// Handle the invocation, usually by running the [`Next`] middleware at some point.
+    fn handle<'a>(
+        &'a self,
+        invocation: Invocation,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, Result<Vec<u8>, InvocationError>>;
+}
+
+/// The remaining [`Middleware`]s, followed by the actual invocation of the request.
+///
+/// It can be cloned to run them more than once.
+#[derive(Clone)]
+pub struct Next<'a> {
+    client: &'a Client,
+    middlewares: &'a [Arc<dyn Middleware>],
+}
+
+impl Invocation {
+    /// The [`tl::Identifiable::CONSTRUCTOR_ID`] of the request, if the body has one.
+    pub fn constructor_id(&self) -> Option<u32> {
+        self.body
+            .get(..4)
+            .map(|id| u32::from_le_bytes(id.try_into().unwrap()))
+    }
+
+    /// Name of the request as defined in the TL schema, such as `"messages.sendMessage"`,
+    /// or `"?"` if it is not known.
+    pub fn name(&self) -> &'static str {
+        self.constructor_id().map_or("?", tl::name_for_id)
+    }
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(client: &'a Client, middlewares: &'a [Arc<dyn Middleware>]) -> Self {
+        Self {
+            client,
+            middlewares,
+        }
+    }
+
+    /// Run the remaining middlewares and invoke the request, returning its serialized response.
+    pub async fn run(self, invocation: Invocation) -> Result<Vec<u8>, InvocationError> {
+        match self.middlewares.split_first() {
+            Some((middleware, middlewares)) => {
+                let next = Self::new(self.client, middlewares);
+                middleware.handle(invocation, next).await
+            }
+            None => self.client.invoke_with_flood_sleep(invocation).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ClientConfiguration;
+    use grammers_mtsender::SenderPool;
+    use grammers_session::storages::MemorySession;
+    use grammers_tl_types::Serializable;
+    use std::sync::Mutex;
+
+    /// Records the name of the requests it sees, and answers them itself if `respond`.
+    struct Record {
+        tag: &'static str,
+        seen: Arc<Mutex<Vec<String>>>,
+        respond: bool,
+    }
+
+    impl Middleware for Record {
+        fn handle<'a>(
+            &'a self,
+            invocation: Invocation,
+            next: Next<'a>,
+        ) -> BoxFuture<'a, Result<Vec<u8>, InvocationError>> {
+            Box::pin(async move {
+                self.seen
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", self.tag, invocation.name()));
+                if self.respond {
+                    Ok(true.to_bytes())
+                } else {
+                    next.run(invocation).await
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn middlewares_run_in_order() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let pool = SenderPool::new(Arc::new(MemorySession::default()), 0);
+        let client = Client::with_configuration(
+            &pool,
+            ClientConfiguration {
+                middlewares: vec![
+                    Arc::new(Record {
+                        tag: "outer",
+                        seen: Arc::clone(&seen),
+                        respond: false,
+                    }),
+                    Arc::new(Record {
+                        tag: "inner",
+                        seen: Arc::clone(&seen),
+                        respond: true,
+                    }),
+                ],
+                ..Default::default()
+            },
+        );
+
+        let request = tl::functions::account::UpdateStatus { offline: true };
+        assert!(client.invoke(&request).await.unwrap());
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["outer account.updateStatus", "inner account.updateStatus"]
+        );
+    }
+}
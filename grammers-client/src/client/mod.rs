@@ -13,7 +13,10 @@ pub mod client;
 pub mod dialogs;
 pub mod files;
 pub mod messages;
+pub mod middleware;
 pub mod net;
+pub mod rate_limiter;
+pub mod takeout;
 pub mod updates;
 
 pub use auth::SignInError;
@@ -5,13 +5,18 @@
 // <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
+use super::middleware::{Invocation, Next};
 use super::{Client, ClientInner};
 use crate::client::client::ClientConfiguration;
 use grammers_mtsender::{InvocationError, RpcError, SenderPool};
 use grammers_tl_types::{self as tl, Deserializable};
 use log::info;
 use std::sync::Arc;
-use tokio::{sync::Mutex, time::sleep};
+use std::time::Duration;
+use tokio::{
+    sync::Mutex,
+    time::{Instant, sleep},
+};
 
 /// Method implementations directly related with network connectivity.
 impl Client {
@@ -55,13 +60,16 @@ impl Client {
         configuration: ClientConfiguration,
     ) -> Self {
         // TODO Sender doesn't have a way to handle backpressure yet
-        Self(Arc::new(ClientInner {
-            session: Arc::clone(&sender_pool.runner.session),
-            api_id: sender_pool.runner.api_id,
-            handle: sender_pool.handle.clone(),
-            configuration,
-            auth_copied_to_dcs: Mutex::new(Vec::new()),
-        }))
+        Self(
+            Arc::new(ClientInner {
+                session: Arc::clone(&sender_pool.runner.session),
+                api_id: sender_pool.runner.api_id,
+                handle: sender_pool.handle.clone(),
+                configuration,
+                auth_copied_to_dcs: Mutex::new(Vec::new()),
+            }),
+            None,
+        )
     }
 
     /// Invoke a raw API call. This directly sends the request to Telegram's servers.
@@ -92,18 +100,134 @@ impl Client {
         request: &R,
     ) -> Result<R::Return, InvocationError> {
         let dc_id = self.0.session.home_dc_id();
-        self.do_invoke_in_dc(dc_id, request.to_bytes())
+        let timeout = self.0.configuration.request_timeout;
+        self.do_invoke_in_dc(dc_id, None, timeout, request.to_bytes())
             .await
             .and_then(|body| R::Return::from_bytes(&body).map_err(|e| e.into()))
     }
 
+    /// Like [`Self::invoke`], but the request fails with [`InvocationError::Timeout`]
+    /// if its response does not arrive within the given `timeout`.
+    ///
+    /// This overrides the [`ClientConfiguration::request_timeout`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn f(client: grammers_client::Client) -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::time::Duration;
+    /// use grammers_tl_types as tl;
+    ///
+    /// let pong = client
+    ///     .invoke_with_timeout(&tl::functions::Ping { ping_id: 0 }, Duration::from_secs(5))
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn invoke_with_timeout<R: tl::RemoteCall>(
+        &self,
+        request: &R,
+        timeout: Duration,
+    ) -> Result<R::Return, InvocationError> {
+        let dc_id = self.0.session.home_dc_id();
+        self.do_invoke_in_dc(dc_id, None, Some(timeout), request.to_bytes())
+            .await
+            .and_then(|body| R::Return::from_bytes(&body).map_err(|e| e.into()))
+    }
+
+    /// Invoke multiple raw API calls at once, such that the server executes them in order.
+    ///
+    /// This avoids waiting for the response of each request before sending the next, such as
+    /// when sending several messages to the same chat that should arrive in order.
+    ///
+    /// The result of each request is returned in the same order as the input. A request failing
+    /// does not prevent the next ones from executing. The requests do not go through the
+    /// [`ClientConfiguration::middlewares`] and are not retried on flood-waits, as doing so
+    /// would no longer preserve their order, but they are subject to the rate limiter.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn f(client: grammers_client::Client) -> Result<(), Box<dyn std::error::Error>> {
+    /// use grammers_tl_types as tl;
+    ///
+    /// let pings = [0, 1, 2].map(|ping_id| tl::functions::Ping { ping_id });
+    /// for pong in client.invoke_ordered(&pings).await {
+    ///     dbg!(pong?);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn invoke_ordered<R: tl::RemoteCall>(
+        &self,
+        requests: &[R],
+    ) -> Vec<Result<R::Return, InvocationError>> {
+        let dc_id = self.0.session.home_dc_id();
+        let bodies = requests
+            .iter()
+            .map(|request| request.to_bytes())
+            .collect::<Vec<_>>();
+
+        let rate_limiter = self.0.configuration.rate_limiter.as_ref();
+        let flood_sleep_threshold = self.0.configuration.flood_sleep_threshold;
+        if let Some(rate_limiter) = rate_limiter {
+            for body in bodies.iter() {
+                if let Err(e) = rate_limiter
+                    .acquire(body, None, flood_sleep_threshold)
+                    .await
+                {
+                    return requests.iter().map(|_| Err(e.clone())).collect();
+                }
+            }
+        }
+
+        let results = self
+            .0
+            .handle
+            .invoke_ordered_in_dc(
+                dc_id,
+                bodies
+                    .iter()
+                    .map(|body| self.wrap_in_takeout(body))
+                    .collect(),
+            )
+            .await;
+
+        bodies
+            .iter()
+            .zip(results)
+            .map(|(body, result)| {
+                if let Some(rate_limiter) = rate_limiter {
+                    rate_limiter.observe(body, &result);
+                }
+                result.and_then(|body| R::Return::from_bytes(&body).map_err(|e| e.into()))
+            })
+            .collect()
+    }
+
     /// Like [`Self::invoke`], but in the specified DC.
     pub async fn invoke_in_dc<R: tl::RemoteCall>(
         &self,
         dc_id: i32,
         request: &R,
     ) -> Result<R::Return, InvocationError> {
-        self.do_invoke_in_dc(dc_id, request.to_bytes())
+        let timeout = self.0.configuration.request_timeout;
+        self.do_invoke_in_dc(dc_id, None, timeout, request.to_bytes())
+            .await
+            .and_then(|body| R::Return::from_bytes(&body).map_err(|e| e.into()))
+    }
+
+    /// Like [`Self::invoke_in_dc`], but always through the same connection to the DC.
+    ///
+    /// See [`grammers_mtsender::SenderPoolHandle::invoke_in_dc_lane`] for details.
+    pub async fn invoke_in_dc_lane<R: tl::RemoteCall>(
+        &self,
+        dc_id: i32,
+        lane: usize,
+        request: &R,
+    ) -> Result<R::Return, InvocationError> {
+        let timeout = self.0.configuration.request_timeout;
+        self.do_invoke_in_dc(dc_id, Some(lane), timeout, request.to_bytes())
             .await
             .and_then(|body| R::Return::from_bytes(&body).map_err(|e| e.into()))
     }
@@ -111,25 +235,72 @@ impl Client {
     async fn do_invoke_in_dc(
         &self,
         dc_id: i32,
+        lane: Option<usize>,
+        timeout: Option<Duration>,
         request_body: Vec<u8>,
     ) -> Result<Vec<u8>, InvocationError> {
+        let invocation = Invocation {
+            dc_id,
+            lane,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            body: request_body,
+        };
+        Next::new(self, &self.0.configuration.middlewares)
+            .run(invocation)
+            .await
+    }
+
+    /// Invoke the request once all [`Middleware`]s have run, sleeping on flood-waits if allowed.
+    ///
+    /// Requests are delayed as needed by the [`ClientConfiguration::rate_limiter`].
+    ///
+    /// [`Middleware`]: super::middleware::Middleware
+    pub(crate) async fn invoke_with_flood_sleep(
+        &self,
+        invocation: Invocation,
+    ) -> Result<Vec<u8>, InvocationError> {
+        let Invocation {
+            dc_id,
+            lane,
+            deadline,
+            body,
+        } = invocation;
         let mut slept_flood = false;
 
+        let rate_limiter = self.0.configuration.rate_limiter.as_ref();
+        let request_body = self.wrap_in_takeout(&body);
+
         loop {
-            match self
+            if let Some(rate_limiter) = rate_limiter {
+                rate_limiter
+                    .acquire(&body, deadline, self.0.configuration.flood_sleep_threshold)
+                    .await?;
+            }
+
+            let result = self
                 .0
                 .handle
-                .invoke_in_dc(dc_id, request_body.clone())
-                .await
-            {
+                .invoke_in_dc_with(dc_id, lane, deadline, request_body.clone())
+                .await;
+
+            if let Some(rate_limiter) = rate_limiter {
+                rate_limiter.observe(&body, &result);
+            }
+
+            match result {
                 Ok(response) => break Ok(response),
                 Err(InvocationError::Rpc(RpcError {
                     name,
                     code: 420,
                     value: Some(seconds),
                     ..
-                })) if !slept_flood && seconds <= self.0.configuration.flood_sleep_threshold => {
-                    let delay = std::time::Duration::from_secs(seconds as _);
+                })) if !slept_flood
+                    && seconds <= self.0.configuration.flood_sleep_threshold
+                    && deadline.is_none_or(|deadline| {
+                        Instant::now() + Duration::from_secs(seconds as _) < deadline
+                    }) =>
+                {
+                    let delay = Duration::from_secs(seconds as _);
                     info!("sleeping on {} for {:?} before retrying", name, delay,);
                     sleep(delay).await;
                     slept_flood = true;
@@ -0,0 +1,471 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Client-side rate limiting to avoid running into flood-waits in the first place.
+use grammers_mtsender::{InvocationError, RpcError};
+use grammers_tl_types::{self as tl, Cursor, Deserializable, Identifiable};
+use log::info;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::{Instant, sleep_until};
+
+/// The most a rate limit can be slowed down after repeatedly running into flood-waits.
+const MAX_PENALTY: u32 = 16;
+
+/// How long it takes for a slowed down rate limit to speed back up by a factor of two.
+const PENALTY_RECOVERY: Duration = Duration::from_secs(5 * 60);
+
+/// How many buckets may be kept before discarding those that no longer affect any request.
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+/// Family of requests that share the same rate limit.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MethodFamily {
+    /// Messages sent to the same chat, limited separately for every chat.
+    ///
+    /// This includes `messages.sendMessage`, `messages.sendMedia`,
+    /// `messages.sendMultiMedia` and `messages.forwardMessages`.
+    SendMessagesPerChat,
+    /// Messages sent to any chat, limited globally.
+    ///
+    /// This includes the same requests as [`MethodFamily::SendMessagesPerChat`].
+    SendMessages,
+    /// Fetching the message history of chats with `messages.getHistory`.
+    GetHistory,
+    /// Resolving usernames with `contacts.resolveUsername`.
+    ResolveUsername,
+}
+
+/// Proactive rate limiter delaying outgoing requests so that they don't trigger flood-waits.
+///
+/// Every [`MethodFamily`] can be given a limit of requests allowed per period of time.
+/// Requests exceeding the limit wait until they're allowed, although short bursts
+/// of up to the configured amount of requests are sent without delay.
+///
+/// The limiter also learns from the flood-waits that still occur. Until the wait is over,
+/// further requests to the same method or family wait too, rather than failing again.
+/// Waits longer than [`ClientConfiguration::flood_sleep_threshold`] are not slept on;
+/// the requests fail with the same flood-wait error without being sent instead.
+/// Limited families are slowed down after each flood-wait, recovering over time.
+///
+/// Configure it via [`ClientConfiguration::rate_limiter`].
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use grammers_client::client::rate_limiter::{MethodFamily, RateLimiter};
+///
+/// let limiter = RateLimiter::new()
+///     .limit(MethodFamily::SendMessagesPerChat, 1, Duration::from_secs(1))
+///     .limit(MethodFamily::SendMessages, 30, Duration::from_secs(1))
+///     .limit(MethodFamily::ResolveUsername, 10, Duration::from_secs(60));
+/// ```
+///
+/// [`ClientConfiguration::rate_limiter`]: crate::ClientConfiguration::rate_limiter
+/// [`ClientConfiguration::flood_sleep_threshold`]: crate::ClientConfiguration::flood_sleep_threshold
+#[derive(Default)]
+pub struct RateLimiter {
+    limits: HashMap<MethodFamily, Limit>,
+    buckets: Mutex<HashMap<Key, Bucket>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Limit {
+    requests: u32,
+    period: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    /// A family of requests, along with the chat they're for if they're limited per chat.
+    Family(MethodFamily, i64),
+    /// A specific method, only used to remember flood-waits.
+    Method(u32),
+}
+
+#[derive(Clone, Debug)]
+struct Bucket {
+    /// When the next request would be sent if requests were sent at exactly the limit.
+    next_arrival: Instant,
+    /// When the last flood-wait for these requests is over.
+    blocked_until: Instant,
+    /// The error that caused the flood-wait lasting until `blocked_until`.
+    flood_error: Option<RpcError>,
+    /// Factor by which the limit is currently slowed down.
+    penalty: u32,
+    /// When the penalty last changed.
+    penalized_at: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter without any limits.
+    ///
+    /// It will still delay requests that are known to be subject to a flood-wait.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow at most `requests` for the family of methods in each `period` of time.
+    pub fn limit(mut self, family: MethodFamily, requests: u32, period: Duration) -> Self {
+        self.limits.insert(
+            family,
+            Limit {
+                requests: requests.max(1),
+                period,
+            },
+        );
+        self
+    }
+
+    /// Wait until the request can be sent without exceeding any limit.
+    ///
+    /// Fails with the flood-wait error, updated to the seconds that remain, without waiting
+    /// if the request is blocked by a flood-wait longer than `flood_sleep_threshold` seconds.
+    /// Fails with [`InvocationError::Timeout`] without waiting if the request could not be
+    /// sent before the `deadline`.
+    pub(crate) async fn acquire(
+        &self,
+        body: &[u8],
+        deadline: Option<Instant>,
+        flood_sleep_threshold: u32,
+    ) -> Result<(), InvocationError> {
+        let keys = self.keys(body);
+        let now = Instant::now();
+
+        let at = {
+            let mut buckets = self.buckets.lock().unwrap();
+            let at = keys
+                .iter()
+                .filter_map(|key| Some(buckets.get_mut(key)?.earliest(self.limit_for(key), now)))
+                .fold(now, Instant::max);
+
+            let flood_error = keys
+                .iter()
+                .filter_map(|key| buckets.get(key)?.flood_error(now))
+                .max_by_key(|error| error.value);
+            if let Some(error) = flood_error
+                && error
+                    .value
+                    .is_some_and(|seconds| seconds > flood_sleep_threshold)
+            {
+                return Err(InvocationError::Rpc(error));
+            }
+
+            if deadline.is_some_and(|deadline| at > deadline) {
+                return Err(InvocationError::Timeout);
+            }
+
+            for key in keys.iter() {
+                if let Some(limit) = self.limit_for(key) {
+                    buckets
+                        .entry(*key)
+                        .or_insert_with(|| Bucket::new(now))
+                        .reserve(limit, at);
+                }
+            }
+
+            if buckets.len() > MAX_IDLE_BUCKETS {
+                buckets.retain(|_, bucket| !bucket.is_idle(now));
+            }
+            at
+        };
+
+        if at > now {
+            info!(
+                "delaying request {} for {:?} to avoid flood-waits",
+                tl::name_for_id(method_id(body).unwrap_or_default()),
+                at - now
+            );
+            sleep_until(at).await;
+        }
+        Ok(())
+    }
+
+    /// Learn from the result of the request, in case it ran into a flood-wait.
+    pub(crate) fn observe(&self, body: &[u8], result: &Result<Vec<u8>, InvocationError>) {
+        if let Err(InvocationError::Rpc(
+            error @ RpcError {
+                code: 420,
+                value: Some(_),
+                ..
+            },
+        )) = result
+        {
+            self.on_flood_wait(body, error);
+        }
+    }
+
+    /// Remember that the request must wait as long as the flood-wait `error` says before being retried.
+    pub(crate) fn on_flood_wait(&self, body: &[u8], error: &RpcError) {
+        let now = Instant::now();
+        let blocked_until = now + Duration::from_secs(error.value.unwrap_or_default() as _);
+
+        let mut buckets = self.buckets.lock().unwrap();
+        for key in self.keys(body) {
+            let bucket = buckets.entry(key).or_insert_with(|| Bucket::new(now));
+            if blocked_until >= bucket.blocked_until {
+                bucket.blocked_until = blocked_until;
+                bucket.flood_error = Some(error.clone());
+            }
+            if self.limit_for(&key).is_some() {
+                bucket.penalize(now);
+            }
+        }
+    }
+
+    fn limit_for(&self, key: &Key) -> Option<Limit> {
+        match key {
+            Key::Family(family, _) => self.limits.get(family).copied(),
+            Key::Method(_) => None,
+        }
+    }
+
+    /// Determine which rate limits apply to the serialized request.
+    fn keys(&self, body: &[u8]) -> Vec<Key> {
+        let Some(method) = method_id(body) else {
+            return Vec::new();
+        };
+
+        let mut keys = vec![Key::Method(method)];
+        match method {
+            tl::functions::messages::SendMessage::CONSTRUCTOR_ID
+            | tl::functions::messages::SendMedia::CONSTRUCTOR_ID
+            | tl::functions::messages::SendMultiMedia::CONSTRUCTOR_ID
+            | tl::functions::messages::ForwardMessages::CONSTRUCTOR_ID => {
+                if let Some(chat) = destination_chat(method, body) {
+                    keys.push(Key::Family(MethodFamily::SendMessagesPerChat, chat));
+                }
+                keys.push(Key::Family(MethodFamily::SendMessages, 0));
+            }
+            tl::functions::messages::GetHistory::CONSTRUCTOR_ID => {
+                keys.push(Key::Family(MethodFamily::GetHistory, 0));
+            }
+            tl::functions::contacts::ResolveUsername::CONSTRUCTOR_ID => {
+                keys.push(Key::Family(MethodFamily::ResolveUsername, 0));
+            }
+            _ => {}
+        }
+        keys
+    }
+}
+
+impl Bucket {
+    fn new(now: Instant) -> Self {
+        Self {
+            next_arrival: now,
+            blocked_until: now,
+            flood_error: None,
+            penalty: 1,
+            penalized_at: now,
+        }
+    }
+
+    /// Slow down the limit after running into a flood-wait.
+    fn penalize(&mut self, now: Instant) {
+        self.recover(now);
+        self.penalty = (self.penalty * 2).min(MAX_PENALTY);
+        self.penalized_at = now;
+    }
+
+    /// Speed the limit back up for every recovery period that passed since the last penalty.
+    fn recover(&mut self, now: Instant) {
+        while self.penalty > 1 && now >= self.penalized_at + PENALTY_RECOVERY {
+            self.penalty /= 2;
+            self.penalized_at += PENALTY_RECOVERY;
+        }
+    }
+
+    /// How long requests are spaced apart, and how early they may be sent in bursts.
+    fn interval_and_tolerance(&self, limit: Limit) -> (Duration, Duration) {
+        let period = limit.period * self.penalty;
+        let interval = period / limit.requests;
+        (interval, period - interval)
+    }
+
+    /// The earliest moment a request could be sent.
+    fn earliest(&mut self, limit: Option<Limit>, now: Instant) -> Instant {
+        self.recover(now);
+        let allowed = match limit {
+            Some(limit) => {
+                let (_, tolerance) = self.interval_and_tolerance(limit);
+                self.next_arrival
+                    .checked_sub(tolerance)
+                    .unwrap_or(self.next_arrival)
+            }
+            None => now,
+        };
+        allowed.max(self.blocked_until)
+    }
+
+    /// The flood-wait error still blocking these requests, with the seconds that remain of it.
+    fn flood_error(&self, now: Instant) -> Option<RpcError> {
+        let remaining = self.blocked_until.checked_duration_since(now)?;
+        if remaining.is_zero() {
+            return None;
+        }
+        let mut error = self.flood_error.clone()?;
+        error.value = Some(remaining.as_secs_f64().ceil() as u32);
+        Some(error)
+    }
+
+    /// Account for a request being sent `at` the given moment.
+    fn reserve(&mut self, limit: Limit, at: Instant) {
+        let (interval, _) = self.interval_and_tolerance(limit);
+        self.next_arrival = self.next_arrival.max(at) + interval;
+    }
+
+    /// Whether the bucket is in its initial state, and may as well be discarded.
+    fn is_idle(&mut self, now: Instant) -> bool {
+        self.recover(now);
+        self.penalty == 1 && self.next_arrival <= now && self.blocked_until <= now
+    }
+}
+
+/// The constructor identifier of the serialized request, if it has one.
+fn method_id(body: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(body.get(..4)?.try_into().unwrap()))
+}
+
+/// The chat a request sending messages is for, identified as in the Bot API.
+fn destination_chat(method: u32, body: &[u8]) -> Option<i64> {
+    let mut cursor = Cursor::from_slice(body.get(4..)?);
+    let _flags = u32::deserialize(&mut cursor).ok()?;
+    if method == tl::functions::messages::ForwardMessages::CONSTRUCTOR_ID {
+        let _from_peer = tl::enums::InputPeer::deserialize(&mut cursor).ok()?;
+        let _id = Vec::<i32>::deserialize(&mut cursor).ok()?;
+        let _random_id = Vec::<i64>::deserialize(&mut cursor).ok()?;
+    }
+
+    use tl::enums::InputPeer;
+    Some(match InputPeer::deserialize(&mut cursor).ok()? {
+        InputPeer::Empty | InputPeer::PeerSelf => 0,
+        InputPeer::Chat(chat) => -chat.chat_id,
+        InputPeer::User(user) => user.user_id,
+        InputPeer::UserFromMessage(user) => user.user_id,
+        InputPeer::Channel(channel) => -(1000000000000 + channel.channel_id),
+        InputPeer::ChannelFromMessage(channel) => -(1000000000000 + channel.channel_id),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tl::Serializable;
+
+    fn send_message(user_id: i64) -> Vec<u8> {
+        tl::functions::messages::SendMessage {
+            no_webpage: false,
+            silent: false,
+            background: false,
+            clear_draft: false,
+            noforwards: false,
+            update_stickersets_order: false,
+            invert_media: false,
+            allow_paid_floodskip: false,
+            peer: tl::types::InputPeerUser {
+                user_id,
+                access_hash: 0,
+            }
+            .into(),
+            reply_to: None,
+            message: "hi".into(),
+            random_id: 0,
+            reply_markup: None,
+            entities: None,
+            schedule_date: None,
+            schedule_repeat_period: None,
+            send_as: None,
+            quick_reply_shortcut: None,
+            effect: None,
+            allow_paid_stars: None,
+            suggested_post: None,
+        }
+        .to_bytes()
+    }
+
+    fn flood_wait(seconds: u32) -> RpcError {
+        RpcError {
+            code: 420,
+            name: "FLOOD_WAIT".into(),
+            value: Some(seconds),
+            caused_by: Some(tl::functions::messages::SendMessage::CONSTRUCTOR_ID),
+        }
+    }
+
+    #[test]
+    fn keys_for_messages_include_chat() {
+        let limiter = RateLimiter::new();
+        assert_eq!(
+            limiter.keys(&send_message(123)),
+            [
+                Key::Method(tl::functions::messages::SendMessage::CONSTRUCTOR_ID),
+                Key::Family(MethodFamily::SendMessagesPerChat, 123),
+                Key::Family(MethodFamily::SendMessages, 0),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limit_allows_bursts() {
+        let limiter = RateLimiter::new().limit(
+            MethodFamily::SendMessagesPerChat,
+            2,
+            Duration::from_secs(10),
+        );
+
+        let start = Instant::now();
+        limiter.acquire(&send_message(1), None, 60).await.unwrap();
+        limiter.acquire(&send_message(1), None, 60).await.unwrap();
+        assert_eq!(Instant::now(), start);
+
+        // Other chats are not affected.
+        limiter.acquire(&send_message(2), None, 60).await.unwrap();
+        assert_eq!(Instant::now(), start);
+
+        limiter.acquire(&send_message(1), None, 60).await.unwrap();
+        assert_eq!(Instant::now(), start + Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flood_wait_is_remembered() {
+        let limiter = RateLimiter::new();
+        let start = Instant::now();
+        limiter.on_flood_wait(&send_message(1), &flood_wait(3));
+
+        let deadline = start + Duration::from_secs(1);
+        assert!(matches!(
+            limiter.acquire(&send_message(1), Some(deadline), 60).await,
+            Err(InvocationError::Timeout)
+        ));
+
+        limiter.acquire(&send_message(1), None, 60).await.unwrap();
+        assert_eq!(Instant::now(), start + Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn long_flood_wait_fails_fast() {
+        let limiter = RateLimiter::new();
+        let start = Instant::now();
+        limiter.on_flood_wait(&send_message(1), &flood_wait(100));
+        tokio::time::advance(Duration::from_secs(30)).await;
+
+        match limiter.acquire(&send_message(1), None, 60).await {
+            Err(InvocationError::Rpc(error)) => assert_eq!(error, flood_wait(70)),
+            result => panic!("unexpected result: {result:?}"),
+        }
+        assert_eq!(Instant::now(), start + Duration::from_secs(30));
+
+        // Once the remaining wait is within the threshold, it is slept on instead.
+        tokio::time::advance(Duration::from_secs(20)).await;
+        limiter.acquire(&send_message(1), None, 60).await.unwrap();
+        assert_eq!(Instant::now(), start + Duration::from_secs(100));
+    }
+}
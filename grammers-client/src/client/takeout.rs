@@ -0,0 +1,250 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Methods related to [takeout sessions](https://core.telegram.org/api/takeout),
+//! used to export account data with more lenient flood limits.
+use super::Client;
+use grammers_mtsender::InvocationError;
+use grammers_tl_types::{self as tl, Identifiable, Serializable};
+use log::warn;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Builder to choose which data will be exported before starting a [`Takeout`] session.
+///
+/// Created with [`Client::takeout`]. Nothing is exported by default.
+pub struct TakeoutBuilder {
+    client: Client,
+    request: tl::functions::account::InitTakeoutSession,
+}
+
+/// A started takeout session.
+///
+/// This derefs to a [`Client`] whose requests, including those made by its iterators such as
+/// [`Client::iter_messages`], [`Client::iter_dialogs`] or [`Client::iter_participants`],
+/// are all wrapped in [`tl::functions::InvokeWithTakeout`].
+///
+/// The session should be explicitly ended with [`Takeout::finish`]. Otherwise, it is finished
+/// unsuccessfully in the background when dropped, as long as it happens inside a Tokio runtime.
+pub struct Takeout {
+    client: Client,
+    id: i64,
+    finished: bool,
+}
+
+/// State of a takeout session shared by every [`Client`] that wraps its requests in it.
+pub(crate) struct TakeoutSession {
+    pub(crate) id: i64,
+    /// Set once the session is finished, so that clones of the takeout client
+    /// which outlive it go back to sending plain requests.
+    pub(crate) finished: AtomicBool,
+}
+
+impl Client {
+    /// Prepare a new [takeout session](https://core.telegram.org/api/takeout) to export
+    /// the data of the logged-in account.
+    ///
+    /// Requests made through the takeout session are subject to more lenient flood limits.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn f(client: grammers_client::Client) -> Result<(), Box<dyn std::error::Error>> {
+    /// let takeout = client
+    ///     .takeout()
+    ///     .contacts(true)
+    ///     .message_users(true)
+    ///     .start()
+    ///     .await?;
+    ///
+    /// let mut dialogs = takeout.iter_dialogs();
+    /// while let Some(dialog) = dialogs.next().await? {
+    ///     println!("{}", dialog.peer().name().unwrap_or_default());
+    /// }
+    ///
+    /// takeout.finish(true).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn takeout(&self) -> TakeoutBuilder {
+        TakeoutBuilder {
+            client: self.clone(),
+            request: tl::functions::account::InitTakeoutSession {
+                contacts: false,
+                message_users: false,
+                message_chats: false,
+                message_megagroups: false,
+                message_channels: false,
+                files: false,
+                file_max_size: None,
+            },
+        }
+    }
+
+    /// Wrap the serialized request in [`tl::functions::InvokeWithTakeout`] if this client
+    /// belongs to a takeout session.
+    pub(crate) fn wrap_in_takeout(&self, body: &[u8]) -> Vec<u8> {
+        match &self.1 {
+            Some(takeout) if !takeout.finished.load(Ordering::Acquire) => {
+                let mut wrapped = Vec::with_capacity(12 + body.len());
+                tl::functions::InvokeWithTakeout::<()>::CONSTRUCTOR_ID.serialize(&mut wrapped);
+                takeout.id.serialize(&mut wrapped);
+                wrapped.extend_from_slice(body);
+                wrapped
+            }
+            _ => body.to_vec(),
+        }
+    }
+}
+
+impl TakeoutBuilder {
+    /// Whether contacts will be exported.
+    pub fn contacts(mut self, value: bool) -> Self {
+        self.request.contacts = value;
+        self
+    }
+
+    /// Whether messages in private chats will be exported.
+    pub fn message_users(mut self, value: bool) -> Self {
+        self.request.message_users = value;
+        self
+    }
+
+    /// Whether messages in small group chats will be exported.
+    pub fn message_chats(mut self, value: bool) -> Self {
+        self.request.message_chats = value;
+        self
+    }
+
+    /// Whether messages in megagroups will be exported.
+    pub fn message_megagroups(mut self, value: bool) -> Self {
+        self.request.message_megagroups = value;
+        self
+    }
+
+    /// Whether messages in broadcast channels will be exported.
+    pub fn message_channels(mut self, value: bool) -> Self {
+        self.request.message_channels = value;
+        self
+    }
+
+    /// Whether files will be exported, optionally only up to the given size in bytes.
+    pub fn files(mut self, value: bool, max_size: Option<i64>) -> Self {
+        self.request.files = value;
+        self.request.file_max_size = max_size.filter(|_| value);
+        self
+    }
+
+    /// Start the takeout session with the chosen scopes.
+    ///
+    /// Telegram may require waiting before a takeout session can be started, in which case
+    /// a `TAKEOUT_INIT_DELAY` error is returned, and the user has to confirm the export.
+    pub async fn start(self) -> Result<Takeout, InvocationError> {
+        let tl::enums::account::Takeout::Takeout(takeout) =
+            self.client.invoke(&self.request).await?;
+
+        Ok(Takeout {
+            client: Client(
+                self.client.0,
+                Some(Arc::new(TakeoutSession {
+                    id: takeout.id,
+                    finished: AtomicBool::new(false),
+                })),
+            ),
+            id: takeout.id,
+            finished: false,
+        })
+    }
+}
+
+impl Takeout {
+    /// Identifier of the takeout session.
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    /// End the takeout session, indicating whether the export was `successful`.
+    pub async fn finish(mut self, success: bool) -> Result<(), InvocationError> {
+        self.finished = true;
+        finish_session(&self.client, success).await
+    }
+}
+
+/// Finish the takeout session of the `client`, no longer wrapping requests from any of its clones
+/// once it's done, even if it failed.
+async fn finish_session(client: &Client, success: bool) -> Result<(), InvocationError> {
+    let result = client
+        .invoke(&tl::functions::account::FinishTakeoutSession { success })
+        .await;
+    if let Some(takeout) = &client.1 {
+        takeout.finished.store(true, Ordering::Release);
+    }
+    result.map(drop)
+}
+
+impl Deref for Takeout {
+    type Target = Client;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+impl Drop for Takeout {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            warn!("takeout session dropped outside of a runtime and could not be finished");
+            return;
+        };
+        let client = self.client.clone();
+        runtime.spawn(async move {
+            if let Err(e) = finish_session(&client, false).await {
+                warn!("failed to finish dropped takeout session: {e}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientConfiguration;
+    use grammers_mtsender::SenderPool;
+    use grammers_session::storages::MemorySession;
+
+    #[test]
+    fn takeout_wraps_requests() {
+        let pool = SenderPool::new(Arc::new(MemorySession::default()), 0);
+        let client = Client::with_configuration(&pool, ClientConfiguration::default());
+        let body = tl::functions::Ping { ping_id: 7 }.to_bytes();
+        assert_eq!(client.wrap_in_takeout(&body), body);
+
+        let session = Arc::new(TakeoutSession {
+            id: 42,
+            finished: AtomicBool::new(false),
+        });
+        let takeout = Client(client.0, Some(Arc::clone(&session)));
+        let clone = takeout.clone();
+        assert_eq!(
+            clone.wrap_in_takeout(&body),
+            tl::functions::InvokeWithTakeout {
+                takeout_id: 42,
+                query: tl::functions::Ping { ping_id: 7 },
+            }
+            .to_bytes()
+        );
+
+        session.finished.store(true, Ordering::Release);
+        assert_eq!(clone.wrap_in_takeout(&body), body);
+    }
+}
@@ -96,6 +96,10 @@ impl UpdateStream {
     pub async fn next_raw(
         &mut self,
     ) -> Result<(tl::enums::Update, State, Arc<PeerMap>), InvocationError> {
+        // Persist the changes made since the last update, such as cached peers, before
+        // handing out more. If it fails, no update is lost, and it will be retried next time.
+        self.client.0.session.flush().await?;
+
         if self.should_get_state {
             self.should_get_state = false;
             match self
@@ -266,6 +270,8 @@ impl UpdateStream {
     }
 
     /// Synchronize the updates state to the session.
+    ///
+    /// Storages that defer writes will persist it the next time the session is flushed.
     pub fn sync_update_state(&self) {
         self.client
             .0
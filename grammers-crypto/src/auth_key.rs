@@ -53,6 +53,11 @@ impl AuthKey {
         self.data
     }
 
+    /// The identifier of the authorization key, as sent along encrypted messages.
+    pub fn key_id(&self) -> i64 {
+        i64::from_le_bytes(self.key_id)
+    }
+
     /// Calculates the new nonce hash based on the current attributes.
     pub fn calc_new_nonce_hash(&self, new_nonce: &[u8; 32], number: u8) -> [u8; 16] {
         let data = {
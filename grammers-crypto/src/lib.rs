@@ -193,6 +193,75 @@ pub fn decrypt_data_v2<'b>(
     Ok(&mut buffer[8 + 16..])
 }
 
+/// Calculate the AES key and IV used by the (deprecated) MTProto 1.0 for messages sent by the client.
+fn calc_key_v1(auth_key: &AuthKey, msg_key: &[u8; 16]) -> ([u8; 32], [u8; 32]) {
+    let x = Side::Client.x();
+
+    let sha1_a = sha1!(msg_key, &auth_key.data[x..x + 32]);
+    let sha1_b = sha1!(
+        &auth_key.data[32 + x..48 + x],
+        msg_key,
+        &auth_key.data[48 + x..64 + x]
+    );
+    let sha1_c = sha1!(&auth_key.data[64 + x..96 + x], msg_key);
+    let sha1_d = sha1!(msg_key, &auth_key.data[96 + x..128 + x]);
+
+    // aes_key = substr (sha1_a, 0, 8) + substr (sha1_b, 8, 12) + substr (sha1_c, 4, 12);
+    let aes_key = {
+        let mut buffer = [0; 32];
+        buffer[0..8].copy_from_slice(&sha1_a[0..8]);
+        buffer[8..8 + 12].copy_from_slice(&sha1_b[8..8 + 12]);
+        buffer[20..20 + 12].copy_from_slice(&sha1_c[4..4 + 12]);
+        buffer
+    };
+
+    // aes_iv = substr (sha1_a, 8, 12) + substr (sha1_b, 0, 8) + substr (sha1_c, 16, 4) + substr (sha1_d, 0, 8);
+    let aes_iv = {
+        let mut buffer = [0; 32];
+        buffer[0..12].copy_from_slice(&sha1_a[8..8 + 12]);
+        buffer[12..12 + 8].copy_from_slice(&sha1_b[0..8]);
+        buffer[20..20 + 4].copy_from_slice(&sha1_c[16..16 + 4]);
+        buffer[24..24 + 8].copy_from_slice(&sha1_d[0..8]);
+        buffer
+    };
+
+    (aes_key, aes_iv)
+}
+
+fn do_encrypt_data_v1(plaintext: &[u8], auth_key: &AuthKey, random_padding: &[u8; 16]) -> Vec<u8> {
+    // msg_key = substr (SHA1 (plaintext), 4, 16);
+    let msg_key = {
+        let mut buffer = [0; 16];
+        buffer.copy_from_slice(&sha1!(plaintext)[4..4 + 16]);
+        buffer
+    };
+
+    let (key, iv) = calc_key_v1(auth_key, &msg_key);
+
+    let padding_len = (16 - (plaintext.len() % 16)) % 16;
+    let mut ciphertext = Vec::with_capacity(8 + 16 + plaintext.len() + padding_len);
+    ciphertext.extend(&auth_key.key_id);
+    ciphertext.extend(&msg_key);
+    ciphertext.extend(plaintext);
+    ciphertext.extend(&random_padding[..padding_len]);
+    aes::ige_encrypt(&mut ciphertext[8 + 16..], &key, &iv);
+    ciphertext
+}
+
+/// Encrypt the data using the (deprecated) MTProto 1.0 scheme.
+///
+/// Only used for the binding message of temporary authorization keys,
+/// which Telegram still expects to be encrypted this way.
+pub fn encrypt_data_v1(plaintext: &[u8], auth_key: &AuthKey) -> Vec<u8> {
+    let random_padding = {
+        let mut rnd = [0; 16];
+        getrandom::fill(&mut rnd).expect("failed to generate a secure padding");
+        rnd
+    };
+
+    do_encrypt_data_v1(plaintext, auth_key, &random_padding)
+}
+
 /// Generate the AES key and initialization vector from the server nonce
 /// and the new client nonce. This is done after the DH exchange.
 pub fn generate_key_data_from_nonce(
@@ -223,6 +292,31 @@ pub fn generate_key_data_from_nonce(
     (key, iv)
 }
 
+/// Calculate the HMAC-SHA256 of `data` using the given `key`.
+///
+/// Used to sign and verify the handshake of MTProxies emulating TLS.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Derive a 256-bit key from the given `password` and `salt` using PBKDF2-HMAC-SHA256.
+///
+/// Used to encrypt sessions at rest with a key derived from a passphrase.
+pub fn pbkdf2_sha256(password: &[u8], salt: &[u8], rounds: u32) -> [u8; 32] {
+    use hmac::Hmac;
+    use sha2::Sha256;
+
+    let mut key = [0; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(password, salt, rounds, &mut key)
+        .expect("hmac accepts keys of any size");
+    key
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -389,6 +483,37 @@ mod tests {
         );
     }
 
+    #[test]
+    fn encrypt_client_data_v1() {
+        let auth_key = get_test_auth_key();
+        let plaintext = b"Hello, world! This data should remain secure!".to_vec();
+        let random_padding = [0; 16];
+
+        let mut ciphertext = do_encrypt_data_v1(&plaintext, &auth_key, &random_padding);
+        assert_eq!(&ciphertext[..8], &auth_key.key_id);
+        assert_eq!(ciphertext.len(), 8 + 16 + 48);
+
+        let msg_key = ciphertext[8..24].try_into().unwrap();
+        let (key, iv) = calc_key_v1(&auth_key, &msg_key);
+        aes::ige_decrypt(&mut ciphertext[24..], &key, &iv);
+        assert_eq!(&ciphertext[24..24 + plaintext.len()], &plaintext[..]);
+        assert_eq!(&sha1!(&plaintext)[4..], &msg_key);
+    }
+
+    #[test]
+    fn verify_hmac_sha256() {
+        // RFC 4231, test case 2
+        let expected = [
+            91, 220, 193, 70, 191, 96, 117, 78, 106, 4, 36, 38, 8, 149, 117, 199, 90, 0, 63, 8,
+            157, 39, 57, 131, 157, 236, 88, 185, 100, 236, 56, 67,
+        ];
+
+        assert_eq!(
+            hmac_sha256(b"Jefe", b"what do ya want for nothing?"),
+            expected
+        );
+    }
+
     #[test]
     fn verify_ige_encryption() {
         let mut plaintext = get_test_aes_key_or_iv(); // Encrypting the key with itself
@@ -420,4 +545,15 @@ mod tests {
 
         assert_eq!(plaintext.as_slice(), &expected);
     }
+
+    #[test]
+    fn derive_pbkdf2_sha256() {
+        assert_eq!(
+            pbkdf2_sha256(b"password", b"salt", 2),
+            [
+                174, 77, 12, 149, 175, 107, 70, 211, 45, 10, 223, 249, 40, 240, 109, 208, 42, 48,
+                63, 142, 243, 194, 81, 223, 214, 226, 216, 90, 149, 71, 76, 67,
+            ]
+        );
+    }
 }
@@ -9,6 +9,8 @@
 #[allow(deprecated)] // see https://github.com/RustCrypto/block-ciphers/issues/509
 use aes::cipher::{KeyIvInit, StreamCipher, generic_array::GenericArray};
 
+use crate::sha256;
+
 /// This implements the AES-256-CTR cipher used by Telegram to encrypt data
 /// when using the obfuscated transport.
 ///
@@ -34,6 +36,25 @@ impl ObfuscatedCipher {
         }
     }
 
+    /// Like [`ObfuscatedCipher::new`], but mixes the `secret` of an MTProxy into the keys,
+    /// as required when connecting through one.
+    pub fn with_secret(init: &[u8; 64], secret: &[u8; 16]) -> Self {
+        let init_rev = init.iter().copied().rev().collect::<Vec<_>>();
+        let rx_key = sha256!(&init_rev[8..40], secret);
+        let tx_key = sha256!(&init[8..40], secret);
+        #[allow(deprecated)] // see https://github.com/RustCrypto/block-ciphers/issues/509
+        Self {
+            rx: ctr::Ctr128BE::<aes::Aes256>::new(
+                GenericArray::from_slice(&rx_key),
+                GenericArray::from_slice(&init_rev[40..56]),
+            ),
+            tx: ctr::Ctr128BE::<aes::Aes256>::new(
+                GenericArray::from_slice(&tx_key),
+                GenericArray::from_slice(&init[40..56]),
+            ),
+        }
+    }
+
     pub fn encrypt(&mut self, buffer: &mut [u8]) {
         self.tx.apply_keystream(buffer);
     }
@@ -33,6 +33,7 @@
 //!     Ok(())
 //! }
 //! ```
+use crate::MsgId;
 use grammers_crypto::hex;
 use grammers_crypto::{AuthKey, factorize, rsa};
 use grammers_tl_types::{self as tl, Cursor, Deserializable, Serializable};
@@ -123,6 +124,9 @@ pub enum Error {
         /// The expected nonce.
         expected: [u8; 16],
     },
+
+    /// The server refused to bind the temporary key to the permanent one.
+    TempAuthKeyNotBound,
 }
 
 impl std::error::Error for Error {}
@@ -162,10 +166,20 @@ impl fmt::Display for Error {
                 f,
                 "invalid new nonce hash: got {got:?}, expected {expected:?}"
             ),
+            Self::TempAuthKeyNotBound => {
+                write!(f, "the temporary auth key could not be bound")
+            }
         }
     }
 }
 
+/// Parameters of a temporary Authorization Key, used by [`step2_temp`].
+#[derive(Clone, Copy)]
+struct TempKey {
+    dc_id: i32,
+    expires_in: i32,
+}
+
 /// The data generated by [`step1`], needed for [`step2`].
 ///
 /// [`step1`]: fn.step1.html
@@ -228,6 +242,30 @@ fn do_step1(random_bytes: &[u8; 16]) -> Result<(tl::functions::ReqPqMulti, Step1
 pub fn step2(
     data: Step1,
     response: tl::enums::ResPq,
+) -> Result<(tl::functions::ReqDhParams, Step2), Error> {
+    step2_inner(data, response, None)
+}
+
+/// The second step of the process to generate a temporary Authorization Key.
+///
+/// The key will be valid for use in the datacenter `dc_id` for `expires_in` seconds,
+/// after which a new one must be generated. The key is useless until it is bound to
+/// a permanent one via [`bind_temp_auth_key`].
+///
+/// The remaining steps are the same as those used to generate a permanent key.
+pub fn step2_temp(
+    data: Step1,
+    response: tl::enums::ResPq,
+    dc_id: i32,
+    expires_in: i32,
+) -> Result<(tl::functions::ReqDhParams, Step2), Error> {
+    step2_inner(data, response, Some(TempKey { dc_id, expires_in }))
+}
+
+fn step2_inner(
+    data: Step1,
+    response: tl::enums::ResPq,
+    temp: Option<TempKey>,
 ) -> Result<(tl::functions::ReqDhParams, Step2), Error> {
     if TRACE_AUTH_GEN {
         println!("< {}", hex::to_hex(&response.to_bytes()));
@@ -243,7 +281,7 @@ pub fn step2(
         println!("r {}", hex::to_hex(&random_bytes));
     }
 
-    let res = do_step2(data, response, &random_bytes);
+    let res = do_step2(data, response, &random_bytes, temp);
     if TRACE_AUTH_GEN {
         if let Ok((x, _)) = &res {
             println!("> {}", hex::to_hex(&x.to_bytes()));
@@ -256,6 +294,7 @@ fn do_step2(
     data: Step1,
     response: tl::enums::ResPq,
     random_bytes: &[u8; 32 + 224],
+    temp: Option<TempKey>,
 ) -> Result<(tl::functions::ReqDhParams, Step2), Error> {
     // Step 2. Validate the PQ response. Return `(p, q)` if it's valid.
     let Step1 { nonce } = data;
@@ -308,14 +347,28 @@ fn do_step2(
 
     // "pq is a representation of a natural number (in binary big endian format)"
     // https://core.telegram.org/mtproto/auth_key#dh-exchange-initiation
-    let pq_inner_data = tl::enums::PQInnerData::Data(tl::types::PQInnerData {
-        pq: pq.to_be_bytes().to_vec(),
-        p: p_bytes.clone(),
-        q: q_bytes.clone(),
-        nonce,
-        server_nonce: res_pq.server_nonce,
-        new_nonce,
-    })
+    let pq_inner_data = match temp {
+        None => tl::enums::PQInnerData::Data(tl::types::PQInnerData {
+            pq: pq.to_be_bytes().to_vec(),
+            p: p_bytes.clone(),
+            q: q_bytes.clone(),
+            nonce,
+            server_nonce: res_pq.server_nonce,
+            new_nonce,
+        }),
+        Some(TempKey { dc_id, expires_in }) => {
+            tl::enums::PQInnerData::TempDc(tl::types::PQInnerDataTempDc {
+                pq: pq.to_be_bytes().to_vec(),
+                p: p_bytes.clone(),
+                q: q_bytes.clone(),
+                nonce,
+                server_nonce: res_pq.server_nonce,
+                new_nonce,
+                dc: dc_id,
+                expires_in,
+            })
+        }
+    }
     .to_bytes();
 
     // sha_digest + data + random_bytes
@@ -651,6 +704,75 @@ pub fn create_key(
     }
 }
 
+/// Create the request to bind a temporary Authorization Key to a permanent one,
+/// enabling [Perfect Forward Secrecy].
+///
+/// The request must be sent encrypted with the temporary key, by the session with ID
+/// `temp_session_id`, and using `msg_id` as its message identifier. The binding
+/// will be valid until the Unix timestamp `expires_at`.
+///
+/// [Perfect Forward Secrecy]: https://core.telegram.org/api/pfs
+pub fn bind_temp_auth_key(
+    perm_auth_key: &[u8; 256],
+    temp_auth_key: &[u8; 256],
+    temp_session_id: i64,
+    msg_id: MsgId,
+    expires_at: i32,
+) -> tl::functions::auth::BindTempAuthKey {
+    let random_bytes = {
+        let mut buffer = [0; 8 + 16];
+        getrandom::fill(&mut buffer).expect("failed to generate secure data for auth key");
+        buffer
+    };
+
+    do_bind_temp_auth_key(
+        perm_auth_key,
+        temp_auth_key,
+        temp_session_id,
+        msg_id,
+        expires_at,
+        &random_bytes,
+    )
+}
+
+fn do_bind_temp_auth_key(
+    perm_auth_key: &[u8; 256],
+    temp_auth_key: &[u8; 256],
+    temp_session_id: i64,
+    msg_id: MsgId,
+    expires_at: i32,
+    random_bytes: &[u8; 8 + 16],
+) -> tl::functions::auth::BindTempAuthKey {
+    let perm_auth_key = AuthKey::from_bytes(*perm_auth_key);
+    let temp_auth_key = AuthKey::from_bytes(*temp_auth_key);
+    let nonce = i64::from_le_bytes(random_bytes[..8].try_into().unwrap());
+
+    let inner = tl::enums::BindAuthKeyInner::Inner(tl::types::BindAuthKeyInner {
+        nonce,
+        temp_auth_key_id: temp_auth_key.key_id(),
+        perm_auth_key_id: perm_auth_key.key_id(),
+        temp_session_id,
+        expires_at,
+    })
+    .to_bytes();
+
+    // The binding message is a regular message, except with random data in place of
+    // the salt and session ID, and with the same message ID as the request carrying it.
+    let mut message = Vec::with_capacity(16 + 8 + 4 + 4 + inner.len());
+    message.extend(&random_bytes[8..]);
+    msg_id.0.serialize(&mut message);
+    0i32.serialize(&mut message);
+    (inner.len() as i32).serialize(&mut message);
+    message.extend(inner);
+
+    tl::functions::auth::BindTempAuthKey {
+        perm_auth_key_id: perm_auth_key.key_id(),
+        nonce,
+        expires_at,
+        encrypted_message: grammers_crypto::encrypt_data_v1(&message, &perm_auth_key),
+    }
+}
+
 /// Helper function to avoid the boilerplate of checking for invalid nonce.
 fn check_nonce(got: &[u8; 16], expected: &[u8; 16]) -> Result<(), Error> {
     if got == expected {
@@ -752,7 +874,7 @@ mod tests {
         assert_eq!(request.to_bytes(), step1_request);
         let response = tl::enums::ResPq::from_bytes(&step1_response).unwrap();
 
-        let (request, data) = do_step2(data, response, &step2_random)?;
+        let (request, data) = do_step2(data, response, &step2_random, None)?;
         assert_eq!(request.to_bytes(), step2_request);
         let response = tl::enums::ServerDhParams::from_bytes(&step2_response).unwrap();
 
@@ -773,4 +895,29 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn bind_temp_auth_key_request() {
+        let perm_auth_key = [1; 256];
+        let temp_auth_key = [2; 256];
+        let random_bytes = [3; 8 + 16];
+        let request = do_bind_temp_auth_key(
+            &perm_auth_key,
+            &temp_auth_key,
+            1234,
+            MsgId(5678 << 32),
+            1700000000,
+            &random_bytes,
+        );
+
+        let perm_auth_key_id = AuthKey::from_bytes(perm_auth_key).key_id();
+        assert_eq!(request.perm_auth_key_id, perm_auth_key_id);
+        assert_eq!(request.nonce, i64::from_le_bytes([3; 8]));
+        assert_eq!(request.expires_at, 1700000000);
+
+        // perm_auth_key_id + msg_key + (random + msg_id + seq_no + len + inner + padding)
+        let message = &request.encrypted_message;
+        assert_eq!(&message[..8], &perm_auth_key_id.to_le_bytes());
+        assert_eq!(message.len(), 8 + 16 + 80);
+    }
 }
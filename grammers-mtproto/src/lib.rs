@@ -32,3 +32,10 @@ pub const DEFAULT_COMPRESSION_THRESHOLD: Option<usize> = Some(512);
 /// that was assigned to the request to determine which request the response is for.
 #[derive(Copy, Clone, Debug, Hash, PartialEq, PartialOrd, Eq, Ord)]
 pub struct MsgId(i64);
+
+impl MsgId {
+    /// The raw value of the identifier, such as needed to refer to the message in other requests.
+    pub fn value(self) -> i64 {
+        self.0
+    }
+}
@@ -177,11 +177,10 @@ impl Deserializable for MessageContainer {
 /// msg_copy#e06046b2 orig_message:Message = MessageCopy;
 /// ```
 ///
-/// Note that this is "not used", in favour of `msg_container`.
-// Even though we use `MessageCopy::CONSTRUCTOR_ID, the dead code lint fires.
-#[allow(dead_code)]
+/// Note that this is "not used", in favour of `msg_container`,
+/// but the server is still allowed to send it.
 pub(crate) struct MessageCopy {
-    pub orig_message: Vec<Message>,
+    pub orig_message: Message,
 }
 
 impl Identifiable for MessageCopy {
@@ -189,6 +188,18 @@ impl Identifiable for MessageCopy {
     const CONSTRUCTOR_ID: u32 = 0xe06046b2;
 }
 
+impl Deserializable for MessageCopy {
+    fn deserialize(buf: &mut Cursor) -> Result<Self, tl::deserialize::Error> {
+        let constructor_id = u32::deserialize(buf)?;
+        if constructor_id != Self::CONSTRUCTOR_ID {
+            return Err(tl::deserialize::Error::UnexpectedConstructor { id: constructor_id });
+        }
+
+        let orig_message = Message::deserialize(buf)?;
+        Ok(Self { orig_message })
+    }
+}
+
 /// This struct represents the following TL definition:
 ///
 /// ```tl
@@ -13,6 +13,7 @@ use crate::{MsgId, manual_tl};
 use grammers_crypto::{AuthKey, DequeBuffer, decrypt_data_v2, encrypt_data_v2};
 use grammers_tl_types::{self as tl, Cursor, Deserializable, Identifiable, Serializable};
 use log::info;
+use std::collections::VecDeque;
 use std::mem;
 use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
@@ -27,6 +28,11 @@ const NUM_FUTURE_SALTS: i32 = 64;
 /// Used to prevent small fluctuations in the system clock.
 const SALT_USE_DELAY: i32 = 60;
 
+/// How many of the identifiers of the messages received from the server are remembered.
+///
+/// Used to answer the server when it asks about the status of the messages it sent.
+const MAX_RECEIVED_MSG_IDS: usize = 512;
+
 static UPDATE_IDS: [u32; 8] = [
     tl::types::UpdateShortMessage::CONSTRUCTOR_ID,
     tl::types::UpdateShortChatMessage::CONSTRUCTOR_ID,
@@ -91,6 +97,12 @@ pub struct Encrypted {
     /// [Content-related Message]: https://core.telegram.org/mtproto/description#content-related-message
     pending_ack: Vec<i64>,
 
+    /// Identifiers of the most recent messages received from the server, oldest first.
+    received_msg_ids: VecDeque<i64>,
+
+    /// Answers to the server's requests for the status of its messages, pending to be sent.
+    pending_state_info: Vec<tl::types::MsgsStateInfo>,
+
     /// If present, the threshold in bytes at which a message will be
     /// considered large enough to attempt compressing it. Otherwise,
     /// outgoing messages will never be compressed.
@@ -142,6 +154,8 @@ impl Builder {
             sequence: 0,
             last_msg_id: 0,
             pending_ack: vec![],
+            received_msg_ids: VecDeque::with_capacity(MAX_RECEIVED_MSG_IDS),
+            pending_state_info: vec![],
             compression_threshold: self.compression_threshold,
             deserialization: Vec::new(),
             msg_count: 0,
@@ -164,6 +178,39 @@ impl Encrypted {
         self.auth_key.to_bytes()
     }
 
+    /// The identifier of the session used by this instance.
+    pub fn session_id(&self) -> i64 {
+        self.session_id
+    }
+
+    /// The difference in seconds between the server's clock and the local one.
+    pub fn time_offset(&self) -> i32 {
+        self.time_offset
+    }
+
+    /// How many server salts are known, including the one currently in use.
+    pub fn salt_count(&self) -> usize {
+        self.salts.len()
+    }
+
+    /// Pushes a request whose body depends on the message ID it will be sent with
+    /// into the internal buffer, such as [`tl::functions::auth::BindTempAuthKey`].
+    ///
+    /// Unlike [`Mtp::push`], the request is always serialized, and neither salts
+    /// nor acknowledgements are included, so it should be the first message sent
+    /// by this instance. [`Mtp::finalize`] must be called afterwards as usual.
+    pub fn push_with_msg_id(
+        &mut self,
+        buffer: &mut DequeBuffer<u8>,
+        request: impl FnOnce(MsgId) -> Vec<u8>,
+    ) -> MsgId {
+        let msg_id = MsgId(self.get_new_msg_id());
+        let body = request(msg_id);
+        assert!(body.len().is_multiple_of(4));
+        self.serialize_msg_with_id(buffer, msg_id, &body, true);
+        msg_id
+    }
+
     /// Correct our time offset based on a known valid message ID.
     fn correct_time_offset(&mut self, msg_id: i64) {
         let now = SystemTime::now()
@@ -211,15 +258,24 @@ impl Encrypted {
         body: &[u8],
         content_related: bool,
     ) -> MsgId {
-        let msg_id = self.get_new_msg_id();
+        let msg_id = MsgId(self.get_new_msg_id());
+        self.serialize_msg_with_id(buffer, msg_id, body, content_related);
+        msg_id
+    }
 
-        msg_id.serialize(buffer);
+    fn serialize_msg_with_id(
+        &mut self,
+        buffer: &mut DequeBuffer<u8>,
+        msg_id: MsgId,
+        body: &[u8],
+        content_related: bool,
+    ) {
+        msg_id.0.serialize(buffer);
         self.get_seq_no(content_related).serialize(buffer);
         (body.len() as i32).serialize(buffer);
         buffer.extend(body);
 
         self.msg_count += 1;
-        MsgId(msg_id)
     }
 
     fn get_current_salt(&self) -> i64 {
@@ -247,10 +303,43 @@ impl Encrypted {
         }
     }
 
+    /// Status of a message sent by the server, as used in `msgs_state_info`.
+    fn message_state(&self, msg_id: i64) -> u8 {
+        if self.received_msg_ids.contains(&msg_id) {
+            // Message received, and already acknowledged unless it's still pending.
+            return if self.pending_ack.contains(&msg_id) {
+                4
+            } else {
+                4 + 8
+            };
+        }
+
+        match (
+            self.received_msg_ids.iter().min(),
+            self.received_msg_ids.iter().max(),
+        ) {
+            (Some(&oldest), _) if msg_id < oldest => 1,
+            (_, Some(&newest)) if msg_id < newest => 2,
+            _ => 3,
+        }
+    }
+
+    /// Serialize the pending answers to `msgs_state_req`, as long as they fit in the container.
+    fn serialize_state_info(&mut self, buffer: &mut DequeBuffer<u8>) {
+        while self.msg_count < manual_tl::MessageContainer::MAXIMUM_LENGTH {
+            let Some(state_info) = self.pending_state_info.pop() else {
+                break;
+            };
+            let body = tl::enums::MsgsStateInfo::Info(state_info).to_bytes();
+            self.serialize_msg(buffer, &body, false);
+        }
+    }
+
     /// `finalize`, but without encryption.
     ///
     /// The buffer is *not* cleared, but is instead returned.
     fn finalize_plain(&mut self, buffer: &mut DequeBuffer<u8>) {
+        self.serialize_state_info(buffer);
         if self.msg_count == 0 {
             return;
         }
@@ -288,6 +377,10 @@ impl Encrypted {
         if message.requires_ack() {
             self.pending_ack.push(message.msg_id);
         }
+        if self.received_msg_ids.len() == MAX_RECEIVED_MSG_IDS {
+            self.received_msg_ids.pop_front();
+        }
+        self.received_msg_ids.push_back(message.msg_id);
 
         // Handle all the possible Service Messages:
         // * https://core.telegram.org/mtproto/service_messages
@@ -566,9 +659,13 @@ impl Encrypted {
     /// transmits a stand-alone acknowledgment.
     ///
     /// [Acknowledgment of Receipt]: https://core.telegram.org/mtproto/service_messages_about_messages#acknowledgment-of-receipt
-    fn handle_ack(&self, message: manual_tl::Message) -> Result<(), DeserializeError> {
-        // TODO notify about this somehow
-        let _ack = tl::enums::MsgsAck::from_bytes(&message.body)?;
+    fn handle_ack(&mut self, message: manual_tl::Message) -> Result<(), DeserializeError> {
+        let tl::enums::MsgsAck::Ack(ack) = tl::enums::MsgsAck::from_bytes(&message.body)?;
+        self.deserialization.extend(
+            ack.msg_ids
+                .into_iter()
+                .map(|msg_id| Deserialization::Ack(MsgId(msg_id))),
+        );
         Ok(())
     }
 
@@ -712,8 +809,19 @@ impl Encrypted {
     /// ```
     ///
     /// [Request for Message Status Information]: https://core.telegram.org/mtproto/service_messages_about_messages#request-for-message-status-information
-    fn handle_state_req(&self, _message: manual_tl::Message) -> Result<(), DeserializeError> {
-        // TODO implement
+    fn handle_state_req(&mut self, message: manual_tl::Message) -> Result<(), DeserializeError> {
+        let tl::enums::MsgsStateReq::Req(state_req) =
+            tl::enums::MsgsStateReq::from_bytes(&message.body)?;
+
+        let info = state_req
+            .msg_ids
+            .iter()
+            .map(|&msg_id| self.message_state(msg_id))
+            .collect();
+        self.pending_state_info.push(tl::types::MsgsStateInfo {
+            req_msg_id: message.msg_id,
+            info,
+        });
         Ok(())
     }
 
@@ -775,8 +883,22 @@ impl Encrypted {
     /// This message does not require an acknowledgment.
     ///
     /// [Voluntary Communication of Status of Messages]: https://core.telegram.org/mtproto/service_messages_about_messages#voluntary-communication-of-status-of-messages
-    fn handle_msg_all(&mut self, _message: manual_tl::Message) -> Result<(), DeserializeError> {
-        // TODO implement
+    fn handle_msg_all(&mut self, message: manual_tl::Message) -> Result<(), DeserializeError> {
+        let tl::enums::MsgsAllInfo::Info(all_info) =
+            tl::enums::MsgsAllInfo::from_bytes(&message.body)?;
+
+        for (msg_id, status) in all_info.msg_ids.into_iter().zip(all_info.info) {
+            match status & 7 {
+                // Certainly not received. When the `msg_id` is too high (3), it may still arrive.
+                2 => self
+                    .deserialization
+                    .push(Deserialization::Resend(MsgId(msg_id))),
+                4 => self
+                    .deserialization
+                    .push(Deserialization::Ack(MsgId(msg_id))),
+                _ => {}
+            }
+        }
         Ok(())
     }
 
@@ -812,6 +934,9 @@ impl Encrypted {
         let msg_detailed = tl::enums::MsgDetailedInfo::from_bytes(&message.body)?;
         match msg_detailed {
             tl::enums::MsgDetailedInfo::Info(x) => {
+                // The server received our message again, so it must have received it before.
+                self.deserialization
+                    .push(Deserialization::Ack(MsgId(x.msg_id)));
                 self.pending_ack.push(x.answer_msg_id);
             }
             tl::enums::MsgDetailedInfo::MsgNewDetailedInfo(x) => {
@@ -849,9 +974,19 @@ impl Encrypted {
     ///
     /// [Explicit Request to Re-Send Answers]: https://core.telegram.org/mtproto/service_messages_about_messages#explicit-request-to-re-send-answers
     /// [Explicit Request to Re-Send Messages]: https://core.telegram.org/mtproto/service_messages_about_messages#explicit-request-to-re-send-messages
-    fn handle_msg_resend(&self, _message: manual_tl::Message) -> Result<(), DeserializeError> {
-        // TODO implement
-        // `msg_resend_ans_req` seems to never occur (it was even missing from `mtproto.tl`)
+    fn handle_msg_resend(&mut self, message: manual_tl::Message) -> Result<(), DeserializeError> {
+        match tl::enums::MsgResendReq::from_bytes(&message.body)? {
+            tl::enums::MsgResendReq::Req(resend_req) => self.deserialization.extend(
+                resend_req
+                    .msg_ids
+                    .into_iter()
+                    .map(|msg_id| Deserialization::Resend(MsgId(msg_id))),
+            ),
+            tl::enums::MsgResendReq::MsgResendAnsReq(_) => {
+                // We never answer queries from the server, so there is nothing to re-send.
+                // `msg_resend_ans_req` seems to never occur (it was even missing from `mtproto.tl`)
+            }
+        }
         Ok(())
     }
 
@@ -1085,8 +1220,18 @@ impl Encrypted {
     /// in a simple container with the same result.
     ///
     /// [Message Copies]: https://core.telegram.org/mtproto/service_messages#message-copies
-    fn handle_msg_copy(&self, _message: manual_tl::Message) -> Result<(), DeserializeError> {
-        panic!("msg_copy should not be used")
+    fn handle_msg_copy(&mut self, message: manual_tl::Message) -> Result<(), DeserializeError> {
+        let manual_tl::MessageCopy { orig_message } =
+            manual_tl::MessageCopy::from_bytes(&message.body)?;
+
+        if self.received_msg_ids.contains(&orig_message.msg_id) {
+            if orig_message.requires_ack() {
+                self.pending_ack.push(orig_message.msg_id);
+            }
+            Ok(())
+        } else {
+            self.process_message(orig_message)
+        }
     }
 
     /// **[Packed Object]**
@@ -1161,7 +1306,7 @@ impl Encrypted {
     ///
     /// [HTTP Wait/Long Poll]: https://core.telegram.org/mtproto/service_messages#http-wait-long-poll
     fn handle_http_wait(&mut self, _message: manual_tl::Message) -> Result<(), DeserializeError> {
-        // TODO implement
+        // Only clients send this (when using the HTTP transport), so there is nothing to handle.
         Ok(())
     }
 
@@ -1298,7 +1443,10 @@ impl Mtp for Encrypted {
     ) -> Result<Vec<Deserialization>, DeserializeError> {
         crate::utils::check_message_buffer(payload)?;
 
-        let plaintext = decrypt_data_v2(payload, &self.auth_key)?;
+        // Padded transports may append up to 15 random bytes after the encrypted data.
+        let padding = payload.len().saturating_sub(24) % 16;
+        let payload_len = payload.len() - padding;
+        let plaintext = decrypt_data_v2(&mut payload[..payload_len], &self.auth_key)?;
 
         let mut buffer = Cursor::from_slice(plaintext);
 
@@ -1505,4 +1653,76 @@ mod tests {
             assert!(buffer.as_ref().windows(4).any(|w| w == GZIP_PACKED_HEADER));
         }
     }
+
+    fn server_message(msg_id: i64, body: Vec<u8>) -> manual_tl::Message {
+        manual_tl::Message {
+            msg_id,
+            seq_no: 1,
+            body,
+        }
+    }
+
+    #[test]
+    fn ensure_state_req_is_answered() {
+        let mut buffer = DequeBuffer::with_capacity(0, 0);
+        let mut mtproto = Encrypted::build().finish(auth_key());
+
+        let update = tl::enums::Updates::TooLong.to_bytes();
+        mtproto
+            .process_message(server_message(400, update))
+            .unwrap();
+        let state_req = tl::enums::MsgsStateReq::Req(tl::types::MsgsStateReq {
+            msg_ids: vec![200, 400, 600, 1200],
+        });
+        mtproto
+            .process_message(server_message(800, state_req.to_bytes()))
+            .unwrap();
+        mtproto.finalize_plain(&mut buffer);
+
+        let state_info = tl::enums::MsgsStateInfo::Info(tl::types::MsgsStateInfo {
+            req_msg_id: 800,
+            info: vec![1, 4, 2, 3],
+        });
+        ensure_buffer_is_message(&buffer[MESSAGE_PREFIX_LEN..], &state_info.to_bytes(), 0);
+    }
+
+    #[test]
+    fn ensure_acks_and_resends_are_propagated() {
+        let mut mtproto = Encrypted::build().finish(auth_key());
+
+        let ack = tl::enums::MsgsAck::Ack(tl::types::MsgsAck { msg_ids: vec![4] });
+        mtproto
+            .process_message(server_message(400, ack.to_bytes()))
+            .unwrap();
+        let resend_req = tl::enums::MsgResendReq::Req(tl::types::MsgResendReq { msg_ids: vec![8] });
+        mtproto
+            .process_message(server_message(800, resend_req.to_bytes()))
+            .unwrap();
+
+        assert!(matches!(
+            mtproto.deserialization[..],
+            [Deserialization::Ack(ack), Deserialization::Resend(resend)]
+                if ack == MsgId(4) && resend == MsgId(8)
+        ));
+    }
+
+    #[test]
+    fn ensure_msg_copy_is_processed_once() {
+        let mut mtproto = Encrypted::build().finish(auth_key());
+
+        let mut copy = Vec::new();
+        manual_tl::MessageCopy::CONSTRUCTOR_ID.serialize(&mut copy);
+        server_message(400, tl::enums::Updates::TooLong.to_bytes()).serialize(&mut copy);
+
+        mtproto
+            .process_message(server_message(800, copy.clone()))
+            .unwrap();
+        mtproto.process_message(server_message(1200, copy)).unwrap();
+
+        assert!(matches!(
+            mtproto.deserialization[..],
+            [Deserialization::Update(_)]
+        ));
+        assert_eq!(mtproto.pending_ack, [800, 400, 1200, 400]);
+    }
 }
@@ -73,6 +73,10 @@ pub enum Deserialization {
     RpcError(RpcResultError),
     /// `bad_msg_notification` for a previously-sent message.
     BadMessage(BadMessage),
+    /// The server acknowledged receiving a previously-sent message.
+    Ack(MsgId),
+    /// The server asked to re-send a previously-sent message it did not receive.
+    Resend(MsgId),
     /// Generic deserialization failure of a received message.
     Failure(DeserializationFailure),
 }
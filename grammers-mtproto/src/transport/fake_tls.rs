@@ -0,0 +1,381 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use grammers_crypto::{DequeBuffer, hmac_sha256};
+
+use super::{Error, Transport, UnpackedOffset};
+
+/// Maximum amount of data a single TLS record may contain.
+const MAX_RECORD_LEN: usize = 1 << 14;
+
+/// Length of the header preceding every TLS record.
+const RECORD_HEADER_LEN: usize = 5;
+
+/// Size the `ClientHello` is padded to, matching what common browsers send.
+const CLIENT_HELLO_LEN: usize = 517;
+
+/// Offset of the random value within both the `ClientHello` and the `ServerHello`.
+const RANDOM_OFFSET: usize = 11;
+
+const CHANGE_CIPHER_SPEC: [u8; 6] = [0x14, 0x03, 0x03, 0x00, 0x01, 0x01];
+
+/// TLS emulation layer used by MTProxies with secrets with the `ee` prefix.
+///
+/// The connection starts with a fake TLS 1.3 handshake whose `ClientHello` contains the
+/// proxy domain as SNI and is signed with the proxy secret. After that, the data of the
+/// inner transport (normally [`Obfuscated`](super::Obfuscated)) is sent wrapped in TLS
+/// application data records.
+///
+/// The server response to the handshake is verified before any data is unwrapped.
+pub struct FakeTls<T: Transport> {
+    inner: T,
+    secret: [u8; 16],
+    domain: Vec<u8>,
+    /// Random value sent in the `ClientHello`, needed to verify the `ServerHello`.
+    client_random: Option<[u8; 32]>,
+    handshake_done: bool,
+    /// Bytes at the start of the buffer that no longer belong to the inner transport.
+    gap: usize,
+    /// End of the contiguous data of the inner transport within the buffer.
+    data_end: usize,
+    /// Bytes still missing from the record being unwrapped.
+    record_left: usize,
+}
+
+impl<T: Transport> FakeTls<T> {
+    /// Creates a new TLS emulation layer around `inner`, using the given proxy `secret`
+    /// (the 16 bytes after the `ee` prefix) and `domain` (the bytes after the key).
+    pub fn new(inner: T, secret: [u8; 16], domain: &str) -> Self {
+        Self {
+            inner,
+            secret,
+            domain: domain.as_bytes().to_vec(),
+            client_random: None,
+            handshake_done: false,
+            gap: 0,
+            data_end: 0,
+            record_left: 0,
+        }
+    }
+
+    /// Builds a signed `ClientHello` record.
+    fn client_hello(&self) -> Vec<u8> {
+        let mut random = [0; 32 + 32 + 32 + 16];
+        getrandom::fill(&mut random).expect("failed to generate a secure client hello");
+        let (session_id, rest) = random.split_at(32);
+        let (key_share, grease) = rest.split_at(32);
+        let grease = grease[..8]
+            .iter()
+            .map(|b| (b & 0xf0) | 0x0a)
+            .collect::<Vec<_>>();
+
+        let sni = &self.domain;
+        let mut hello = Vec::with_capacity(CLIENT_HELLO_LEN);
+        hello.extend([0x16, 0x03, 0x01, 0x02, 0x00]); // record header, length fixed below
+        hello.extend([0x01, 0x00, 0x01, 0xfc]); // client hello, length fixed below
+        hello.extend([0x03, 0x03]);
+        hello.extend([0; 32]); // random, filled in after signing
+        hello.push(0x20);
+        hello.extend(session_id);
+        hello.extend([0x00, 0x20, grease[0], grease[0]]);
+        hello.extend([
+            0x13, 0x01, 0x13, 0x02, 0x13, 0x03, 0xc0, 0x2b, 0xc0, 0x2f, 0xc0, 0x2c, 0xc0, 0x30,
+            0xcc, 0xa9, 0xcc, 0xa8, 0xc0, 0x13, 0xc0, 0x14, 0x00, 0x9c, 0x00, 0x9d, 0x00, 0x2f,
+            0x00, 0x35,
+        ]);
+        hello.extend([0x01, 0x00]); // compression methods
+        let extensions_start = hello.len();
+        hello.extend([0x00, 0x00]); // extensions length, fixed below
+        hello.extend([grease[2], grease[2], 0x00, 0x00]);
+        // server_name
+        hello.extend([0x00, 0x00]);
+        hello.extend(((sni.len() + 5) as u16).to_be_bytes());
+        hello.extend(((sni.len() + 3) as u16).to_be_bytes());
+        hello.push(0x00);
+        hello.extend((sni.len() as u16).to_be_bytes());
+        hello.extend(sni);
+        hello.extend([
+            0x00, 0x17, 0x00, 0x00, // extended_master_secret
+            0xff, 0x01, 0x00, 0x01, 0x00, // renegotiation_info
+            0x00, 0x0a, 0x00, 0x0a, 0x00, 0x08, grease[4], grease[4], 0x00, 0x1d, 0x00, 0x17, 0x00,
+            0x18, // supported_groups
+            0x00, 0x0b, 0x00, 0x02, 0x01, 0x00, // ec_point_formats
+            0x00, 0x23, 0x00, 0x00, // session_ticket
+            0x00, 0x10, 0x00, 0x0e, 0x00, 0x0c, 0x02, b'h', b'2', 0x08, b'h', b't', b't', b'p',
+            b'/', b'1', b'.', b'1', // application_layer_protocol_negotiation
+            0x00, 0x05, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00, // status_request
+            0x00, 0x0d, 0x00, 0x12, 0x00, 0x10, 0x04, 0x03, 0x08, 0x04, 0x04, 0x01, 0x05, 0x03,
+            0x08, 0x05, 0x05, 0x01, 0x08, 0x06, 0x06, 0x01, // signature_algorithms
+            0x00, 0x12, 0x00, 0x00, // signed_certificate_timestamp
+            0x00, 0x33, 0x00, 0x2b, 0x00, 0x29, grease[4], grease[4], 0x00, 0x01, 0x00, 0x00, 0x1d,
+            0x00, 0x20, // key_share
+        ]);
+        hello.extend(key_share);
+        hello.extend([
+            0x00, 0x2d, 0x00, 0x02, 0x01, 0x01, // psk_key_exchange_modes
+            0x00, 0x2b, 0x00, 0x0b, 0x0a, grease[6], grease[6], 0x03, 0x04, 0x03, 0x03, 0x03, 0x02,
+            0x03, 0x01, // supported_versions
+            0x00, 0x1b, 0x00, 0x03, 0x02, 0x00, 0x02, // compress_certificate
+            grease[3], grease[3], 0x00, 0x01, 0x00,
+        ]);
+
+        // padding
+        let padding_len = CLIENT_HELLO_LEN.saturating_sub(hello.len() + 4);
+        hello.extend([0x00, 0x15]);
+        hello.extend((padding_len as u16).to_be_bytes());
+        hello.resize(hello.len() + padding_len, 0);
+
+        let extensions_len = (hello.len() - extensions_start - 2) as u16;
+        hello[extensions_start..extensions_start + 2]
+            .copy_from_slice(&extensions_len.to_be_bytes());
+        let handshake_len = (hello.len() - RECORD_HEADER_LEN - 4) as u32;
+        hello[6..9].copy_from_slice(&handshake_len.to_be_bytes()[1..]);
+        let record_len = (hello.len() - RECORD_HEADER_LEN) as u16;
+        hello[3..5].copy_from_slice(&record_len.to_be_bytes());
+
+        let mut digest = hmac_sha256(&self.secret, &hello);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time is before epoch")
+            .as_secs() as u32;
+        digest[28..32]
+            .iter_mut()
+            .zip(now.to_le_bytes())
+            .for_each(|(d, t)| *d ^= t);
+
+        hello[RANDOM_OFFSET..RANDOM_OFFSET + 32].copy_from_slice(&digest);
+        hello
+    }
+
+    /// Checks the server response to our `ClientHello`, returning its length if complete.
+    fn verify_server_hello(&self, buffer: &[u8]) -> Result<usize, Error> {
+        let record_len = |offset: usize, kind: u8| -> Result<usize, Error> {
+            let header = buffer
+                .get(offset..offset + RECORD_HEADER_LEN)
+                .ok_or(Error::MissingBytes)?;
+            if header[..3] != [kind, 0x03, 0x03] {
+                return Err(Error::BadTlsRecord);
+            }
+            Ok(RECORD_HEADER_LEN + u16::from_be_bytes([header[3], header[4]]) as usize)
+        };
+
+        let mut len = record_len(0, 0x16)?;
+        match buffer.get(len..len + CHANGE_CIPHER_SPEC.len()) {
+            Some(ccs) if ccs == CHANGE_CIPHER_SPEC => len += CHANGE_CIPHER_SPEC.len(),
+            Some(_) => return Err(Error::BadTlsRecord),
+            None => return Err(Error::MissingBytes),
+        }
+        len += record_len(len, 0x17)?;
+        if buffer.len() < len || len < RANDOM_OFFSET + 32 {
+            return Err(Error::MissingBytes);
+        }
+
+        let client_random = self.client_random.ok_or(Error::BadTlsHandshake)?;
+        let mut response = Vec::with_capacity(32 + len);
+        response.extend(client_random);
+        response.extend(&buffer[..len]);
+        response[32 + RANDOM_OFFSET..32 + RANDOM_OFFSET + 32].fill(0);
+
+        if hmac_sha256(&self.secret, &response)[..] != buffer[RANDOM_OFFSET..RANDOM_OFFSET + 32] {
+            return Err(Error::BadTlsHandshake);
+        }
+
+        Ok(len)
+    }
+}
+
+impl<T: Transport> Transport for FakeTls<T> {
+    fn pack(&mut self, buffer: &mut DequeBuffer<u8>) {
+        self.inner.pack(buffer);
+
+        let data = buffer.as_ref().to_vec();
+        buffer.clear();
+        for chunk in data.chunks(MAX_RECORD_LEN) {
+            buffer.extend([0x17, 0x03, 0x03]);
+            buffer.extend((chunk.len() as u16).to_be_bytes());
+            buffer.extend(chunk);
+        }
+
+        if self.client_random.is_none() {
+            let hello = self.client_hello();
+            let mut client_random = [0; 32];
+            client_random.copy_from_slice(&hello[RANDOM_OFFSET..RANDOM_OFFSET + 32]);
+            self.client_random = Some(client_random);
+
+            buffer.extend_front(&CHANGE_CIPHER_SPEC);
+            buffer.extend_front(&hello);
+        }
+    }
+
+    fn unpack(&mut self, buffer: &mut [u8]) -> Result<UnpackedOffset, Error> {
+        if !self.handshake_done {
+            let len = self.verify_server_hello(buffer)?;
+            self.handshake_done = true;
+            self.gap = len;
+            self.data_end = len;
+        }
+
+        // Strip the headers of the records received so far, moving the data that precedes them
+        // forward so that the inner transport sees a contiguous stream.
+        loop {
+            let available = buffer.len() - self.data_end;
+            if self.record_left != 0 {
+                let n = self.record_left.min(available);
+                self.data_end += n;
+                self.record_left -= n;
+                if self.record_left != 0 {
+                    break;
+                }
+                continue;
+            }
+
+            if available < RECORD_HEADER_LEN {
+                break;
+            }
+
+            let header = &buffer[self.data_end..self.data_end + RECORD_HEADER_LEN];
+            if header[..3] != [0x17, 0x03, 0x03] {
+                return Err(Error::BadTlsRecord);
+            }
+            self.record_left = u16::from_be_bytes([header[3], header[4]]) as usize;
+
+            buffer.copy_within(self.gap..self.data_end, self.gap + RECORD_HEADER_LEN);
+            self.gap += RECORD_HEADER_LEN;
+            self.data_end += RECORD_HEADER_LEN;
+        }
+
+        let gap = self.gap;
+        let offset = self.inner.unpack(&mut buffer[gap..self.data_end])?;
+
+        let next_offset = gap + offset.next_offset;
+        self.data_end -= next_offset;
+        self.gap = 0;
+
+        Ok(UnpackedOffset {
+            data_range: gap + offset.data_range.start..gap + offset.data_range.end,
+            next_offset,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::Intermediate;
+
+    const SECRET: [u8; 16] = [7; 16];
+
+    /// Builds the response a proxy would send to the given `ClientHello`.
+    fn server_hello(client_hello: &[u8]) -> Vec<u8> {
+        let mut response = vec![0x16, 0x03, 0x03, 0x00, 0x7a];
+        response.extend([0x02; 0x7a]);
+        response.extend(CHANGE_CIPHER_SPEC);
+        response.extend([0x17, 0x03, 0x03, 0x00, 0x10]);
+        response.extend([0x42; 0x10]);
+        response[RANDOM_OFFSET..RANDOM_OFFSET + 32].fill(0);
+
+        let mut signed = client_hello[RANDOM_OFFSET..RANDOM_OFFSET + 32].to_vec();
+        signed.extend(&response);
+        let digest = hmac_sha256(&SECRET, &signed);
+        response[RANDOM_OFFSET..RANDOM_OFFSET + 32].copy_from_slice(&digest);
+        response
+    }
+
+    /// Wraps intermediate packets containing `payload` in application data records,
+    /// splitting them at `split`.
+    fn records(payload: &[u8], split: usize) -> Vec<u8> {
+        let mut data = (payload.len() as i32).to_le_bytes().to_vec();
+        data.extend(payload);
+        let mut result = Vec::new();
+        for chunk in data.chunks(split) {
+            result.extend([0x17, 0x03, 0x03]);
+            result.extend((chunk.len() as u16).to_be_bytes());
+            result.extend(chunk);
+        }
+        result
+    }
+
+    fn setup() -> (FakeTls<Intermediate>, Vec<u8>) {
+        let mut transport = FakeTls::new(Intermediate::new(), SECRET, "example.com");
+        let mut buffer = DequeBuffer::with_capacity(8, 0);
+        buffer.extend([1, 2, 3, 4, 5, 6, 7, 8]);
+        transport.pack(&mut buffer);
+        let hello = buffer[..CLIENT_HELLO_LEN].to_vec();
+        (transport, hello)
+    }
+
+    #[test]
+    fn pack_client_hello() {
+        let (_, hello) = setup();
+        assert_eq!(&hello[..5], &[0x16, 0x03, 0x01, 0x02, 0x00]);
+        assert!(hello.windows(11).any(|w| w == b"example.com"));
+
+        let mut unsigned = hello.clone();
+        unsigned[RANDOM_OFFSET..RANDOM_OFFSET + 32].fill(0);
+        let digest = hmac_sha256(&SECRET, &unsigned);
+        assert_eq!(
+            &hello[RANDOM_OFFSET..RANDOM_OFFSET + 28],
+            &digest[..28],
+            "client hello must be signed with the secret"
+        );
+    }
+
+    #[test]
+    fn pack_wraps_records() {
+        let mut transport = FakeTls::new(Intermediate::new(), SECRET, "example.com");
+        let mut buffer = DequeBuffer::with_capacity(8, 0);
+        buffer.extend([1, 2, 3, 4, 5, 6, 7, 8]);
+        transport.pack(&mut buffer);
+
+        let rest = &buffer[CLIENT_HELLO_LEN..];
+        assert_eq!(&rest[..6], &CHANGE_CIPHER_SPEC);
+        assert_eq!(&rest[6..11], &[0x17, 0x03, 0x03, 0x00, 16]);
+
+        let mut buffer = DequeBuffer::with_capacity(8, 0);
+        buffer.extend([1, 2, 3, 4, 5, 6, 7, 8]);
+        transport.pack(&mut buffer);
+        assert_eq!(&buffer[..5], &[0x17, 0x03, 0x03, 0x00, 12]);
+    }
+
+    #[test]
+    fn unpack_bad_handshake() {
+        let (mut transport, hello) = setup();
+        let mut response = server_hello(&hello);
+        response[RANDOM_OFFSET] ^= 1;
+        assert_eq!(transport.unpack(&mut response), Err(Error::BadTlsHandshake));
+    }
+
+    #[test]
+    fn unpack_split_records() {
+        let (mut transport, hello) = setup();
+        let payload = (0..64).collect::<Vec<u8>>();
+
+        let mut buffer = server_hello(&hello);
+        buffer.extend(records(&payload, 7));
+        buffer.extend(records(&payload, 1000));
+
+        // Feed the data in small increments, like the network would.
+        let mut tail = 0;
+        let mut received = Vec::new();
+        for end in (0..=buffer.len()).step_by(13).chain([buffer.len()]) {
+            loop {
+                match transport.unpack(&mut buffer[tail..end]) {
+                    Ok(offset) => {
+                        received.push(buffer[tail..][offset.data_range].to_vec());
+                        tail += offset.next_offset;
+                    }
+                    Err(Error::MissingBytes) => break,
+                    Err(e) => panic!("unexpected error: {e}"),
+                }
+            }
+        }
+
+        assert_eq!(received, vec![payload.clone(), payload]);
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+use super::{Error, Transport, UnpackedOffset};
+use grammers_crypto::DequeBuffer;
+
+/// A MTProto transport protocol that sends every payload in its own HTTP request.
+///
+/// This is an implementation of the [HTTP transport].
+///
+/// * Overhead: large.
+/// * Minimum envelope length: depends on the host.
+/// * Maximum envelope length: depends on the host.
+///
+/// It serializes the input payload as the body of a `POST` request to `/api`,
+/// and expects the responses to carry the payload as their body:
+///
+/// ```text
+/// POST /api HTTP/1.1\r\n
+/// Host: {host}\r\n
+/// Connection: keep-alive\r\n
+/// Content-Length: {len}\r\n
+/// \r\n
+/// {payload}
+/// ```
+///
+/// The server only sends data in response to a request, so encrypted connections
+/// using this transport should keep a request open at all times with [`http_wait`].
+///
+/// [HTTP transport]: https://core.telegram.org/mtproto/transports#http
+/// [`http_wait`]: https://core.telegram.org/mtproto/service_messages#http-wait-long-poll
+pub struct Http {
+    host: String,
+}
+
+impl Http {
+    /// Creates a new transport, sending `host` (such as `"149.154.167.51:80"`) as the `Host` header.
+    pub fn new(host: impl Into<String>) -> Self {
+        Self { host: host.into() }
+    }
+}
+
+impl Transport for Http {
+    fn pack(&mut self, buffer: &mut DequeBuffer<u8>) {
+        let len = buffer.len();
+        assert_eq!(len % 4, 0);
+
+        let header = format!(
+            "POST /api HTTP/1.1\r\nHost: {}\r\nConnection: keep-alive\r\nContent-Length: {}\r\n\r\n",
+            self.host, len
+        );
+        buffer.extend_front(header.as_bytes());
+    }
+
+    fn unpack(&mut self, buffer: &mut [u8]) -> Result<UnpackedOffset, Error> {
+        let Some(header_len) = buffer
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map(|pos| pos + 4)
+        else {
+            return Err(Error::MissingBytes);
+        };
+
+        let header = String::from_utf8_lossy(&buffer[..header_len]);
+        let mut lines = header.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|status_line| status_line.split(' ').nth(1))
+            .and_then(|status| status.parse::<u32>().ok())
+            .ok_or(Error::BadHttpHeader)?;
+        if status != 200 {
+            return Err(Error::BadStatus { status });
+        }
+
+        let len = lines
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.trim().parse::<i32>().ok())
+            .ok_or(Error::BadHttpHeader)?;
+        if buffer.len() < header_len + len.max(0) as usize {
+            return Err(Error::MissingBytes);
+        }
+
+        if len <= 4 {
+            if len == 4 {
+                let data =
+                    i32::from_le_bytes(buffer[header_len..header_len + 4].try_into().unwrap());
+                if let Some(status) = data.checked_neg().filter(|status| *status > 0) {
+                    return Err(Error::BadStatus {
+                        status: status as u32,
+                    });
+                }
+            }
+            return Err(Error::BadLen { got: len });
+        }
+
+        let len = len as usize;
+        Ok(UnpackedOffset {
+            data_range: header_len..header_len + len,
+            next_offset: header_len + len,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a new HTTP transport, and `n` bytes of input data for it.
+    fn setup_pack(n: usize) -> (Http, DequeBuffer<u8>) {
+        let mut buffer = DequeBuffer::with_capacity(n, 0);
+        buffer.extend((0..n).map(|x| (x & 0xff) as u8));
+        (Http::new("127.0.0.1:80"), buffer)
+    }
+
+    /// Returns an HTTP response carrying `body`.
+    fn response(status: &str, body: &[u8]) -> Vec<u8> {
+        let mut response = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: application/octet-stream\r\ncontent-length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    #[test]
+    #[should_panic]
+    fn pack_non_padded() {
+        let (mut transport, mut buffer) = setup_pack(7);
+        transport.pack(&mut buffer);
+    }
+
+    #[test]
+    fn pack_normal() {
+        let (mut transport, mut buffer) = setup_pack(128);
+        let orig = buffer.clone();
+        transport.pack(&mut buffer);
+        let header = b"POST /api HTTP/1.1\r\nHost: 127.0.0.1:80\r\nConnection: keep-alive\r\nContent-Length: 128\r\n\r\n";
+        assert_eq!(&buffer[..header.len()], header);
+        assert_eq!(&buffer[header.len()..], &orig[..]);
+    }
+
+    #[test]
+    fn unpack_small() {
+        let mut transport = Http::new("127.0.0.1:80");
+        let mut buffer = response("200 OK", &[1; 8]);
+        for len in [10, buffer.len() - 1] {
+            assert_eq!(
+                transport.unpack(&mut buffer[..len]),
+                Err(Error::MissingBytes)
+            );
+        }
+    }
+
+    #[test]
+    fn unpack_normal() {
+        let mut transport = Http::new("127.0.0.1:80");
+        let body = (0..128).collect::<Vec<u8>>();
+        let mut buffer = response("200 OK", &body);
+        let offset = transport.unpack(&mut buffer).unwrap();
+        assert_eq!(&buffer[offset.data_range], &body[..]);
+        assert_eq!(offset.next_offset, buffer.len());
+    }
+
+    #[test]
+    fn unpack_two_at_once() {
+        let mut transport = Http::new("127.0.0.1:80");
+        let body = (0..128).collect::<Vec<u8>>();
+        let mut buffer = response("200 OK", &body);
+        buffer.extend(response("200 OK", &body));
+
+        let offset = transport.unpack(&mut buffer).unwrap();
+        assert_eq!(&buffer[offset.data_range], &body[..]);
+        let rest = &mut buffer[offset.next_offset..];
+        let offset = transport.unpack(rest).unwrap();
+        assert_eq!(&rest[offset.data_range], &body[..]);
+    }
+
+    #[test]
+    fn unpack_bad_status() {
+        let mut transport = Http::new("127.0.0.1:80");
+        let mut buffer = response("404 Not Found", &[]);
+        assert_eq!(
+            transport.unpack(&mut buffer),
+            Err(Error::BadStatus { status: 404 })
+        );
+
+        let mut buffer = b"HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\n".to_vec();
+        assert_eq!(
+            transport.unpack(&mut buffer),
+            Err(Error::BadStatus { status: 404 })
+        );
+
+        let mut buffer = response("200 OK", &(-429i32).to_le_bytes());
+        assert_eq!(
+            transport.unpack(&mut buffer),
+            Err(Error::BadStatus { status: 429 })
+        );
+    }
+
+    #[test]
+    fn unpack_short_body_without_status() {
+        let mut transport = Http::new("127.0.0.1:80");
+        for data in [i32::MIN, 0, 7] {
+            let mut buffer = response("200 OK", &data.to_le_bytes());
+            assert_eq!(transport.unpack(&mut buffer), Err(Error::BadLen { got: 4 }));
+        }
+    }
+
+    #[test]
+    fn unpack_bad_header() {
+        let mut transport = Http::new("127.0.0.1:80");
+        for header in [
+            &b"garbage\r\n\r\n"[..],
+            b"HTTP/1.1 OK\r\nContent-Length: 8\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: many\r\n\r\n",
+        ] {
+            assert_eq!(
+                transport.unpack(&mut header.to_vec()),
+                Err(Error::BadHttpHeader)
+            );
+        }
+    }
+}
@@ -58,7 +58,7 @@ impl Transport for Intermediate {
         }
 
         let len = i32::from_le_bytes(buffer[0..4].try_into().unwrap());
-        if (buffer.len() as i32) < len {
+        if (buffer.len() as i32) < 4 + len {
             return Err(Error::MissingBytes);
         }
 
@@ -130,6 +130,24 @@ mod tests {
         assert_eq!(transport.unpack(&mut buffer[..],), Err(Error::MissingBytes));
     }
 
+    #[test]
+    fn unpack_partial() {
+        let (mut transport, mut buffer) = setup_pack(128);
+        transport.pack(&mut buffer);
+        // Skipping the init bytes and the last one leaves more bytes than the declared length,
+        // but the packet is still incomplete once its own length prefix is accounted for.
+        let (n, len) = (4, buffer.len());
+        assert_eq!(
+            transport.unpack(&mut buffer[n..len - 1]),
+            Err(Error::MissingBytes)
+        );
+
+        let mut buffer = DequeBuffer::with_capacity(6, 0);
+        buffer.extend(&(4_i32).to_le_bytes());
+        buffer.extend([0, 0]);
+        assert_eq!(transport.unpack(&mut buffer[..]), Err(Error::MissingBytes));
+    }
+
     #[test]
     fn unpack_normal() {
         let (mut transport, mut buffer) = setup_pack(128);
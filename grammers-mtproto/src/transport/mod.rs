@@ -13,15 +13,21 @@
 //!
 //! [MTProto transports]: https://core.telegram.org/mtproto#mtproto-transport
 mod abridged;
+mod fake_tls;
 mod full;
+mod http;
 mod intermediate;
 mod obfuscated;
+mod padded_intermediate;
 
 pub use abridged::Abridged;
+pub use fake_tls::FakeTls;
 pub use full::Full;
 use grammers_crypto::DequeBuffer;
+pub use http::Http;
 pub use intermediate::Intermediate;
 pub use obfuscated::Obfuscated;
+pub use padded_intermediate::PaddedIntermediate;
 use std::{fmt, ops::Range};
 
 /// The error type reported by the different transports when something is wrong.
@@ -56,6 +62,15 @@ pub enum Error {
     /// [transport-level error]: https://core.telegram.org/mtproto/mtproto-transports#transport-errors
     /// [HTTP status code]: https://developer.mozilla.org/en-US/docs/Web/HTTP/Status
     BadStatus { status: u32 },
+
+    /// A record received while emulating TLS was malformed or of an unexpected type.
+    BadTlsRecord,
+
+    /// The server response to the emulated TLS handshake was not signed with our secret.
+    BadTlsHandshake,
+
+    /// The header of a response received over HTTP was malformed.
+    BadHttpHeader,
 }
 
 /// Result from calling [`Transport::unpack`].
@@ -84,6 +99,9 @@ impl fmt::Display for Error {
             Error::BadStatus { status } => {
                 write!(f, "bad status (negative length -{status})")
             }
+            Error::BadTlsRecord => write!(f, "bad tls record"),
+            Error::BadTlsHandshake => write!(f, "bad tls handshake"),
+            Error::BadHttpHeader => write!(f, "bad http header"),
         }
     }
 }
@@ -103,6 +121,16 @@ pub trait Transport {
     fn unpack(&mut self, buffer: &mut [u8]) -> Result<UnpackedOffset, Error>;
 }
 
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn pack(&mut self, buffer: &mut DequeBuffer<u8>) {
+        (**self).pack(buffer)
+    }
+
+    fn unpack(&mut self, buffer: &mut [u8]) -> Result<UnpackedOffset, Error> {
+        (**self).unpack(buffer)
+    }
+}
+
 /// The trait used by the obfuscated transport to get the transport tags.
 pub trait Tagged {
     /// Gets the transport tag for use in the obfuscated transport and
@@ -41,7 +41,10 @@ const FORBIDDEN_FIRST_INTS: [[u8; 4]; 7] = [
 ];
 
 impl<T: Transport + Tagged> Obfuscated<T> {
-    fn generate_keys(inner: &mut T) -> ([u8; 64], ObfuscatedCipher) {
+    fn generate_keys(
+        inner: &mut T,
+        proxy: Option<(&[u8; 16], i16)>,
+    ) -> ([u8; 64], ObfuscatedCipher) {
         let mut init = [0; 64];
 
         while init[4..8] == [0; 4] // Full
@@ -53,7 +56,13 @@ impl<T: Transport + Tagged> Obfuscated<T> {
 
         init[56..60].copy_from_slice(&inner.init_tag());
 
-        let mut cipher = ObfuscatedCipher::new(&init);
+        let mut cipher = match proxy {
+            Some((secret, dc_id)) => {
+                init[60..62].copy_from_slice(&dc_id.to_le_bytes());
+                ObfuscatedCipher::with_secret(&init, secret)
+            }
+            None => ObfuscatedCipher::new(&init),
+        };
 
         let mut encrypted_init = init.to_vec();
         cipher.encrypt(&mut encrypted_init);
@@ -63,7 +72,22 @@ impl<T: Transport + Tagged> Obfuscated<T> {
     }
 
     pub fn new(mut inner: T) -> Self {
-        let (init, cipher) = Self::generate_keys(&mut inner);
+        let (init, cipher) = Self::generate_keys(&mut inner, None);
+
+        Self {
+            inner,
+            head: Some(init),
+            decrypt_tail: 0,
+            cipher,
+        }
+    }
+
+    /// Creates a new obfuscated transport to connect through an MTProxy.
+    ///
+    /// The `secret` is the 16-byte key of the proxy (without any prefix or domain),
+    /// and `dc_id` is the datacenter the proxy should forward the connection to.
+    pub fn with_secret(mut inner: T, secret: &[u8; 16], dc_id: i16) -> Self {
+        let (init, cipher) = Self::generate_keys(&mut inner, Some((secret, dc_id)));
 
         Self {
             inner,
@@ -0,0 +1,176 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+use super::{Error, Tagged, Transport, UnpackedOffset};
+use grammers_crypto::DequeBuffer;
+
+/// A variant of the intermediate transport that appends random padding to every packet.
+///
+/// This is an implementation of the [padded intermediate transport].
+///
+/// * Overhead: small.
+/// * Minimum envelope length: 4 bytes.
+/// * Maximum envelope length: 19 bytes.
+///
+/// It serializes the input payload as follows:
+///
+/// ```text
+/// +----+----...----+----...----+
+/// | len|  payload  |  padding  |
+/// +----+----...----+----...----+
+///  ^^^^ 4 bytes     ^^^^^^^^^^^ 0 to 15 bytes
+/// ```
+///
+/// The padding is included in the length, so unpacked data may contain trailing garbage,
+/// which the MTP layer is expected to ignore. This transport is required by MTProxies
+/// using secrets with the `dd` prefix.
+///
+/// [padded intermediate transport]: https://core.telegram.org/mtproto/mtproto-transports#padded-intermediate
+pub struct PaddedIntermediate {
+    init: bool,
+}
+
+#[allow(clippy::new_without_default)]
+impl PaddedIntermediate {
+    const TAG: [u8; 4] = 0xdd_dd_dd_dd_u32.to_le_bytes();
+
+    pub fn new() -> Self {
+        Self { init: false }
+    }
+}
+
+impl Transport for PaddedIntermediate {
+    fn pack(&mut self, buffer: &mut DequeBuffer<u8>) {
+        assert_eq!(buffer.len() % 4, 0);
+
+        let mut padding = [0; 16];
+        getrandom::fill(&mut padding).expect("failed to generate a secure padding");
+        let padding_len = (padding[0] % 16) as usize;
+        buffer.extend(&padding[..padding_len]);
+
+        let len = buffer.len();
+        buffer.extend_front(&(len as i32).to_le_bytes());
+
+        if !self.init {
+            buffer.extend_front(&Self::TAG);
+            self.init = true;
+        }
+    }
+
+    fn unpack(&mut self, buffer: &mut [u8]) -> Result<UnpackedOffset, Error> {
+        if buffer.len() < 4 {
+            return Err(Error::MissingBytes);
+        }
+
+        let len = i32::from_le_bytes(buffer[0..4].try_into().unwrap());
+        if len < 0 {
+            return Err(Error::BadLen { got: len });
+        }
+
+        let len = len as usize;
+        if buffer.len() < 4 + len {
+            return Err(Error::MissingBytes);
+        }
+
+        if len <= 4 {
+            if len == 4 {
+                let data = i32::from_le_bytes(buffer[4..8].try_into().unwrap());
+                return Err(Error::BadStatus {
+                    status: (-data) as u32,
+                });
+            }
+            return Err(Error::BadLen { got: len as i32 });
+        }
+
+        Ok(UnpackedOffset {
+            data_range: 4..4 + len,
+            next_offset: 4 + len,
+        })
+    }
+}
+
+impl Tagged for PaddedIntermediate {
+    fn init_tag(&mut self) -> [u8; 4] {
+        self.init = true;
+        Self::TAG
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a padded intermediate transport, and `n` bytes of input data for it.
+    fn setup_pack(n: usize) -> (PaddedIntermediate, DequeBuffer<u8>) {
+        let mut buffer = DequeBuffer::with_capacity(n, 0);
+        buffer.extend((0..n).map(|x| (x & 0xff) as u8));
+        (PaddedIntermediate::new(), buffer)
+    }
+
+    #[test]
+    #[should_panic]
+    fn pack_non_padded() {
+        let (mut transport, mut buffer) = setup_pack(7);
+        transport.pack(&mut buffer);
+    }
+
+    #[test]
+    fn pack_normal() {
+        let (mut transport, mut buffer) = setup_pack(128);
+        let orig = buffer.clone();
+        transport.pack(&mut buffer);
+        let len = i32::from_le_bytes(buffer[4..8].try_into().unwrap()) as usize;
+        assert_eq!(&buffer[..4], &[0xdd, 0xdd, 0xdd, 0xdd]);
+        assert!((128..128 + 16).contains(&len));
+        assert_eq!(buffer.len(), 8 + len);
+        assert_eq!(&buffer[8..8 + 128], &orig[..]);
+    }
+
+    #[test]
+    fn unpack_small() {
+        let mut transport = PaddedIntermediate::new();
+        let mut buffer = DequeBuffer::with_capacity(1, 0);
+        buffer.extend([1]);
+        assert_eq!(transport.unpack(&mut buffer[..]), Err(Error::MissingBytes));
+    }
+
+    #[test]
+    fn unpack_normal() {
+        let (mut transport, mut buffer) = setup_pack(128);
+        let orig = buffer.clone();
+        transport.pack(&mut buffer);
+        let n = 4; // init bytes
+        let offset = transport.unpack(&mut buffer[n..]).unwrap();
+        assert_eq!(offset.next_offset, buffer.len() - n);
+        assert_eq!(&buffer[n..][offset.data_range][..128], &orig[..]);
+    }
+
+    #[test]
+    fn unpack_incomplete() {
+        let (mut transport, mut buffer) = setup_pack(128);
+        transport.pack(&mut buffer);
+        let n = 4; // init bytes
+        let end = buffer.len() - 1;
+        assert_eq!(
+            transport.unpack(&mut buffer[n..end]),
+            Err(Error::MissingBytes)
+        );
+    }
+
+    #[test]
+    fn unpack_bad_status() {
+        let mut transport = PaddedIntermediate::new();
+        let mut buffer = DequeBuffer::with_capacity(8, 0);
+        buffer.extend(&(4_i32).to_le_bytes());
+        buffer.extend(&(-404_i32).to_le_bytes());
+
+        assert_eq!(
+            transport.unpack(&mut buffer[..]),
+            Err(Error::BadStatus { status: 404 })
+        );
+    }
+}
@@ -6,7 +6,10 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 use grammers_mtproto::{authentication, mtp, transport};
+use grammers_session::SessionError;
 use grammers_tl_types as tl;
+use std::error::Error;
+use std::sync::Arc;
 use std::{fmt, io};
 
 /// This error occurs when reading from the network fails.
@@ -22,14 +25,17 @@ pub enum ReadError {
 
 impl std::error::Error for ReadError {}
 
+/// Standard I/O errors can't be cloned, so a new one with the same kind and message is made.
+fn clone_io_error(e: &io::Error) -> io::Error {
+    e.raw_os_error()
+        .map(io::Error::from_raw_os_error)
+        .unwrap_or_else(|| io::Error::new(e.kind(), e.to_string()))
+}
+
 impl Clone for ReadError {
     fn clone(&self) -> Self {
         match self {
-            Self::Io(e) => Self::Io(
-                e.raw_os_error()
-                    .map(io::Error::from_raw_os_error)
-                    .unwrap_or_else(|| io::Error::new(e.kind(), e.to_string())),
-            ),
+            Self::Io(e) => Self::Io(clone_io_error(e)),
             Self::Transport(e) => Self::Transport(e.clone()),
             Self::Deserialize(e) => Self::Deserialize(e.clone()),
         }
@@ -219,16 +225,43 @@ pub enum InvocationError {
     /// This may mean that the [`crate::SenderPoolRunner`] is no longer running.
     Dropped,
 
+    /// The response did not arrive before the deadline of the request.
+    ///
+    /// The request is cancelled, but it may have already been processed by the server.
+    Timeout,
+
     /// The request was invoked in a datacenter that does not exist or is not known by the session.
     InvalidDc,
 
     /// The request caused the sender to connect to a new datacenter to be performed,
     /// but the Authorization Key generation process failed.
     Authentication(authentication::Error),
+
+    /// The session failed to persist the changes made while processing the request.
+    ///
+    /// The request itself may have been successful. The changes are kept in memory,
+    /// so that the session can try to persist them again later.
+    Session(Arc<dyn Error + Send + Sync>),
 }
 
 impl std::error::Error for InvocationError {}
 
+impl Clone for InvocationError {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Rpc(e) => Self::Rpc(e.clone()),
+            Self::Io(e) => Self::Io(clone_io_error(e)),
+            Self::Deserialize(e) => Self::Deserialize(e.clone()),
+            Self::Transport(e) => Self::Transport(e.clone()),
+            Self::Dropped => Self::Dropped,
+            Self::Timeout => Self::Timeout,
+            Self::InvalidDc => Self::InvalidDc,
+            Self::Authentication(e) => Self::Authentication(e.clone()),
+            Self::Session(e) => Self::Session(Arc::clone(e)),
+        }
+    }
+}
+
 impl fmt::Display for InvocationError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -237,8 +270,10 @@ impl fmt::Display for InvocationError {
             Self::Deserialize(err) => write!(f, "request error: {err}"),
             Self::Transport(err) => write!(f, "request error: {err}"),
             Self::Dropped => write!(f, "request error: dropped (cancelled)"),
+            Self::Timeout => write!(f, "request error: timed out"),
             Self::InvalidDc => write!(f, "request error: invalid dc"),
             Self::Authentication(err) => write!(f, "request error: {err}"),
+            Self::Session(err) => write!(f, "request error: session storage failed: {err}"),
         }
     }
 }
@@ -283,6 +318,12 @@ impl From<authentication::Error> for InvocationError {
     }
 }
 
+impl From<SessionError> for InvocationError {
+    fn from(error: SessionError) -> Self {
+        Self::Session(error.into())
+    }
+}
+
 impl InvocationError {
     /// Matches on the name of the RPC error (case-sensitive).
     ///
@@ -331,4 +372,16 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn check_invocation_error_clone() {
+        let error = InvocationError::Io(io::Error::new(io::ErrorKind::TimedOut, "too slow"));
+        match error.clone() {
+            InvocationError::Io(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+                assert_eq!(e.to_string(), "too slow");
+            }
+            e => panic!("unexpected error: {e:?}"),
+        }
+    }
 }
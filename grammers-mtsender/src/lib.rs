@@ -29,8 +29,15 @@ mod net;
 mod sender;
 mod sender_pool;
 
-pub use configuration::ConnectionParams;
+pub use configuration::{ConnectionParams, IpPreference, ReconnectionPolicy, TransportMode};
 pub use errors::{InvocationError, ReadError, RpcError};
 pub use net::ServerAddr;
-pub use sender::{Sender, connect, connect_with_auth, generate_auth_key};
-pub use sender_pool::{SenderPool, SenderPoolHandle, SenderPoolRunner};
+#[cfg(feature = "proxy")]
+pub use net::{MtProxy, MtProxySecret};
+pub use sender::{
+    Sender, SenderStats, connect, connect_with_auth, generate_auth_key, generate_temp_auth_key,
+};
+pub use sender_pool::{
+    ConnectionEvent, ConnectionStats, DisconnectReason, SenderPool, SenderPoolHandle,
+    SenderPoolRunner,
+};
@@ -6,14 +6,22 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+#[cfg(feature = "proxy")]
+mod mtproxy;
 mod tcp;
+#[cfg(feature = "websocket")]
+mod websocket;
 
+#[cfg(feature = "proxy")]
+pub use mtproxy::{MtProxy, MtProxySecret};
 pub use tcp::NetStream;
 
 /// Represents a socket address which may be proxied.
 #[derive(Debug, Clone)]
 pub enum ServerAddr {
     /// Socket address whose connection should be proxied.
+    ///
+    /// The proxy may either be a SOCKS5 or HTTP(S) URL, or an MTProxy link.
     #[cfg(feature = "proxy")]
     Proxied {
         address: std::net::SocketAddr,
@@ -21,4 +29,15 @@ pub enum ServerAddr {
     },
     /// Proxy address for direct connection.
     Tcp { address: std::net::SocketAddr },
+    /// Pair of addresses for direct connection, preferring IPv6 but falling back to IPv4
+    /// if the former fails or does not connect fast enough.
+    DualStack {
+        ipv6: std::net::SocketAddrV6,
+        ipv4: std::net::SocketAddrV4,
+    },
+    /// URL of a WebSocket endpoint, using either the `ws` or `wss` scheme.
+    ///
+    /// Requires the `websocket` feature to be enabled.
+    #[cfg(feature = "websocket")]
+    WebSocket { url: String },
 }
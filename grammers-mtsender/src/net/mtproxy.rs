@@ -0,0 +1,269 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::io::{self, ErrorKind};
+
+use base64::alphabet;
+use base64::engine::{DecodePaddingMode, Engine, GeneralPurpose, GeneralPurposeConfig};
+use grammers_crypto::hex;
+use grammers_mtproto::transport::{self, Transport};
+
+/// Secrets may be shared with or without padding, using either base64 alphabet.
+const BASE64_CONFIG: GeneralPurposeConfig =
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent);
+const BASE64_ENGINES: [GeneralPurpose; 2] = [
+    GeneralPurpose::new(&alphabet::URL_SAFE, BASE64_CONFIG),
+    GeneralPurpose::new(&alphabet::STANDARD, BASE64_CONFIG),
+];
+
+/// Secret of an [MTProxy](https://core.telegram.org/mtproto/mtproto-transports#transport-obfuscation),
+/// which also determines the transport to use when connecting through it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MtProxySecret {
+    /// A bare 16-byte secret. The obfuscated intermediate transport is used.
+    Plain([u8; 16]),
+    /// A secret prefixed with `dd`. The obfuscated padded intermediate transport is used.
+    Padded([u8; 16]),
+    /// A secret prefixed with `ee` and followed by a domain. The obfuscated padded
+    /// intermediate transport is used, wrapped in a fake TLS connection to said domain.
+    FakeTls { secret: [u8; 16], domain: String },
+}
+
+/// Connection details of an MTProxy, as found in `tg://proxy` links.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MtProxy {
+    /// Host name or IP address of the proxy.
+    pub server: String,
+    /// Port the proxy is listening on.
+    pub port: u16,
+    /// Secret used to authenticate with the proxy.
+    pub secret: MtProxySecret,
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.into())
+}
+
+impl MtProxySecret {
+    /// Parse a secret given either in hexadecimal or base64 form.
+    pub fn parse(secret: &str) -> Result<Self, io::Error> {
+        let bytes = hex::opt_from_hex(secret)
+            .or_else(|| {
+                BASE64_ENGINES
+                    .iter()
+                    .find_map(|engine| engine.decode(secret).ok())
+            })
+            .ok_or_else(|| invalid(format!("proxy secret is not hex nor base64: {secret}")))?;
+
+        let key = |bytes: &[u8]| -> Result<[u8; 16], io::Error> {
+            bytes
+                .get(..16)
+                .and_then(|key| key.try_into().ok())
+                .ok_or_else(|| invalid("proxy secret is too short"))
+        };
+
+        match bytes.first() {
+            Some(0xdd) if bytes.len() == 17 => Ok(Self::Padded(key(&bytes[1..])?)),
+            Some(0xee) if bytes.len() > 17 => Ok(Self::FakeTls {
+                secret: key(&bytes[1..])?,
+                domain: String::from_utf8(bytes[17..].to_vec())
+                    .map_err(|_| invalid("proxy secret domain is not valid utf-8"))?,
+            }),
+            _ if bytes.len() == 16 => Ok(Self::Plain(key(&bytes)?)),
+            _ => Err(invalid(format!("proxy secret is not supported: {secret}"))),
+        }
+    }
+
+    /// Create the transport that should be used to reach the datacenter `dc_id` via the proxy.
+    pub(crate) fn transport(&self, dc_id: i16) -> Box<dyn Transport + Send> {
+        match self {
+            Self::Plain(secret) => Box::new(transport::Obfuscated::with_secret(
+                transport::Intermediate::new(),
+                secret,
+                dc_id,
+            )),
+            Self::Padded(secret) => Box::new(transport::Obfuscated::with_secret(
+                transport::PaddedIntermediate::new(),
+                secret,
+                dc_id,
+            )),
+            Self::FakeTls { secret, domain } => Box::new(transport::FakeTls::new(
+                transport::Obfuscated::with_secret(
+                    transport::PaddedIntermediate::new(),
+                    secret,
+                    dc_id,
+                ),
+                *secret,
+                domain,
+            )),
+        }
+    }
+}
+
+impl MtProxy {
+    /// Parse a proxy link, such as `tg://proxy?server=...&port=...&secret=...`.
+    ///
+    /// The equivalent `https://t.me/proxy?...` form is also accepted.
+    pub fn parse(link: &str) -> Result<Self, io::Error> {
+        let url =
+            url::Url::parse(link).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+        if !Self::is_link(&url) {
+            return Err(invalid(format!("not an mtproxy link: {link}")));
+        }
+
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+                .ok_or_else(|| invalid(format!("mtproxy link is missing {name}: {link}")))
+        };
+
+        Ok(Self {
+            server: param("server")?,
+            port: param("port")?
+                .parse()
+                .map_err(|_| invalid(format!("mtproxy port is invalid: {link}")))?,
+            secret: MtProxySecret::parse(&param("secret")?)?,
+        })
+    }
+
+    /// Parse the proxy URL if it is an MTProxy link.
+    ///
+    /// Other URLs, such as those of SOCKS proxies, are not an error, but malformed MTProxy links
+    /// are, so that they're not mistaken for a different kind of proxy.
+    pub(crate) fn from_proxy_url(proxy_url: &str) -> Result<Option<Self>, io::Error> {
+        match Self::parse(proxy_url) {
+            Ok(mtproxy) => Ok(Some(mtproxy)),
+            Err(_) if !url::Url::parse(proxy_url).is_ok_and(|url| Self::is_link(&url)) => Ok(None),
+            Err(err) => Err(io::Error::new(ErrorKind::InvalidInput, err)),
+        }
+    }
+
+    /// Whether the scheme and host of the URL are those of an MTProxy link.
+    fn is_link(url: &url::Url) -> bool {
+        match url.scheme() {
+            "tg" => url.host_str() == Some("proxy"),
+            "http" | "https" => {
+                matches!(url.host_str(), Some("t.me" | "telegram.me")) && url.path() == "/proxy"
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 16] = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee,
+        0xff,
+    ];
+
+    #[test]
+    fn parse_plain_secret() {
+        assert_eq!(
+            MtProxySecret::parse("00112233445566778899aabbccddeeff").unwrap(),
+            MtProxySecret::Plain(KEY)
+        );
+    }
+
+    #[test]
+    fn parse_padded_secret() {
+        assert_eq!(
+            MtProxySecret::parse("dd00112233445566778899AABBCCDDEEFF").unwrap(),
+            MtProxySecret::Padded(KEY)
+        );
+    }
+
+    #[test]
+    fn parse_fake_tls_secret() {
+        let expected = MtProxySecret::FakeTls {
+            secret: KEY,
+            domain: "example.com".into(),
+        };
+        assert_eq!(
+            MtProxySecret::parse("ee00112233445566778899aabbccddeeff6578616d706c652e636f6d")
+                .unwrap(),
+            expected
+        );
+        assert_eq!(
+            MtProxySecret::parse("7gARIjNEVWZ3iJmqu8zd7v9leGFtcGxlLmNvbQ").unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn parse_bad_secret() {
+        assert!(MtProxySecret::parse("").is_err());
+        assert!(MtProxySecret::parse("0011").is_err());
+        assert!(MtProxySecret::parse("dd00112233445566778899aabbccddeeff00").is_err());
+        assert!(MtProxySecret::parse("not a secret").is_err());
+    }
+
+    #[test]
+    fn parse_link() {
+        let expected = MtProxy {
+            server: "proxy.example.com".into(),
+            port: 443,
+            secret: MtProxySecret::Padded(KEY),
+        };
+        assert_eq!(
+            MtProxy::parse(
+                "tg://proxy?server=proxy.example.com&port=443&secret=dd00112233445566778899aabbccddeeff"
+            )
+            .unwrap(),
+            expected
+        );
+        assert_eq!(
+            MtProxy::parse(
+                "https://t.me/proxy?server=proxy.example.com&port=443&secret=dd00112233445566778899aabbccddeeff"
+            )
+            .unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn parse_bad_link() {
+        assert!(MtProxy::parse("socks5://127.0.0.1:1080").is_err());
+        assert!(MtProxy::parse("tg://proxy?server=example.com&secret=00").is_err());
+        assert!(
+            MtProxy::parse(
+                "tg://socks?server=example.com&port=443&secret=00112233445566778899aabbccddeeff"
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn malformed_link_is_not_another_proxy() {
+        assert!(
+            MtProxy::from_proxy_url("socks5://127.0.0.1:1080")
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            MtProxy::from_proxy_url(
+                "tg://proxy?server=example.com&port=443&secret=00112233445566778899aabbccddeeff"
+            )
+            .unwrap()
+            .is_some()
+        );
+        for link in [
+            "tg://proxy?server=example.com&port=443&secret=zz112233445566778899aabbccddeeff",
+            "tg://proxy?server=example.com&port=443&secret=0011",
+            "https://t.me/proxy?server=example.com&port=443&secret=ff00112233445566778899aabbccddeeff",
+        ] {
+            assert_eq!(
+                MtProxy::from_proxy_url(link).unwrap_err().kind(),
+                ErrorKind::InvalidInput
+            );
+        }
+    }
+}
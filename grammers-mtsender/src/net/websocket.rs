@@ -0,0 +1,429 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Minimal [WebSocket](https://www.rfc-editor.org/rfc/rfc6455) client,
+//! enough to exchange binary messages with Telegram's endpoints.
+use std::io::{self, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Appended to the key sent during the opening handshake to compute the expected accept value.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Upper bound for the size of the response headers, in case the server misbehaves.
+const MAX_RESPONSE_LEN: usize = 8 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Byte stream that can carry a WebSocket connection, with or without TLS.
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> Stream for S {}
+
+/// A WebSocket connection whose binary messages are exposed as a continuous byte stream.
+pub struct WebSocket {
+    reader: tokio::io::ReadHalf<Box<dyn Stream>>,
+    writer: tokio::io::WriteHalf<Box<dyn Stream>>,
+    incoming: FrameReader,
+    outgoing: FrameWriter,
+}
+
+/// Borrowed reading half of a [`WebSocket`].
+pub struct ReadHalf<'a> {
+    reader: &'a mut tokio::io::ReadHalf<Box<dyn Stream>>,
+    incoming: &'a mut FrameReader,
+}
+
+/// Borrowed writing half of a [`WebSocket`].
+pub struct WriteHalf<'a> {
+    writer: &'a mut tokio::io::WriteHalf<Box<dyn Stream>>,
+    outgoing: &'a mut FrameWriter,
+}
+
+/// State of the frame being received.
+#[derive(Default)]
+struct FrameReader {
+    header: [u8; 14],
+    header_len: usize,
+    /// How many bytes of the payload of the current frame have not been read yet.
+    remaining: u64,
+    /// Whether the payload of the current frame is data, or should be skipped.
+    data: bool,
+}
+
+/// State of the frame being sent.
+#[derive(Default)]
+struct FrameWriter {
+    frame: Vec<u8>,
+    written: usize,
+    /// How many bytes of the caller's buffer the frame carries.
+    payload_len: usize,
+}
+
+impl WebSocket {
+    /// Perform the opening handshake over `stream` to upgrade it to a WebSocket connection.
+    pub(crate) async fn connect(
+        mut stream: Box<dyn Stream>,
+        host: &str,
+        path: &str,
+    ) -> Result<Self, io::Error> {
+        let mut nonce = [0; 16];
+        getrandom::fill(&mut nonce).expect("failed to generate websocket key");
+        let key = BASE64.encode(nonce);
+
+        let request = format!(
+            "GET {path} HTTP/1.1\r\nHost: {host}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: binary\r\n\r\n"
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read one byte at a time so that nothing past the headers is consumed,
+        // as that data already belongs to the first frames.
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= MAX_RESPONSE_LEN {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "websocket handshake response headers are too long",
+                ));
+            }
+            response.push(stream.read_u8().await?);
+        }
+
+        let response = String::from_utf8_lossy(&response);
+        let mut lines = response.lines();
+        let status_line = lines.next().unwrap_or_default();
+        if status_line.split(' ').nth(1) != Some("101") {
+            return Err(io::Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("server refused to upgrade to websocket: {status_line}"),
+            ));
+        }
+
+        let expected_accept = accept_key(&key);
+        let accepted = lines
+            .filter_map(|line| line.split_once(':'))
+            .any(|(name, value)| {
+                name.trim().eq_ignore_ascii_case("sec-websocket-accept")
+                    && value.trim() == expected_accept
+            });
+        if !accepted {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "websocket handshake response has a bad accept key",
+            ));
+        }
+
+        let (reader, writer) = tokio::io::split(stream);
+        Ok(Self {
+            reader,
+            writer,
+            incoming: FrameReader::default(),
+            outgoing: FrameWriter::default(),
+        })
+    }
+
+    pub(crate) fn split(&mut self) -> (ReadHalf<'_>, WriteHalf<'_>) {
+        (
+            ReadHalf {
+                reader: &mut self.reader,
+                incoming: &mut self.incoming,
+            },
+            WriteHalf {
+                writer: &mut self.writer,
+                outgoing: &mut self.outgoing,
+            },
+        )
+    }
+}
+
+/// The value the server must reply with to accept the handshake that used `key`.
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(HANDSHAKE_GUID.as_bytes());
+    BASE64.encode(hasher.finalize())
+}
+
+impl FrameReader {
+    /// How many bytes the header of the current frame takes, as far as it is known.
+    fn header_size(&self) -> usize {
+        if self.header_len < 2 {
+            return 2;
+        }
+        let extended_len = match self.header[1] & 0x7f {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        let mask = if self.header[1] & 0x80 != 0 { 4 } else { 0 };
+        2 + extended_len + mask
+    }
+
+    /// Prepare to read the payload of the frame whose header was fully read.
+    fn start_payload(&mut self) -> Result<(), io::Error> {
+        let (opcode, masked) = (self.header[0] & 0x0f, self.header[1] & 0x80 != 0);
+        if masked {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "websocket server sent a masked frame",
+            ));
+        }
+
+        self.remaining = match self.header[1] & 0x7f {
+            126 => u16::from_be_bytes(self.header[2..4].try_into().unwrap()) as u64,
+            127 => u64::from_be_bytes(self.header[2..10].try_into().unwrap()),
+            len => len as u64,
+        };
+        self.data = match opcode {
+            OPCODE_CONTINUATION | OPCODE_BINARY => true,
+            // MTProto has its own pings, so there is no need to answer these.
+            OPCODE_PING | OPCODE_PONG => false,
+            opcode => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("websocket server sent a frame with unexpected opcode {opcode}"),
+                ));
+            }
+        };
+        self.header_len = 0;
+        Ok(())
+    }
+
+    fn poll_read<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.remaining != 0 {
+                let mut scratch = [0; 256];
+                let limit = self.remaining.min(buf.remaining() as u64) as usize;
+                let mut payload = if self.data {
+                    ReadBuf::new(buf.initialize_unfilled_to(limit))
+                } else {
+                    let limit = self.remaining.min(scratch.len() as u64) as usize;
+                    ReadBuf::new(&mut scratch[..limit])
+                };
+                ready!(Pin::new(&mut *reader).poll_read(cx, &mut payload))?;
+                let n = payload.filled().len();
+                if n == 0 {
+                    return Poll::Ready(Ok(()));
+                }
+                self.remaining -= n as u64;
+                if self.data {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                continue;
+            }
+
+            let header_size = self.header_size();
+            let mut header = ReadBuf::new(&mut self.header[self.header_len..header_size]);
+            ready!(Pin::new(&mut *reader).poll_read(cx, &mut header))?;
+            let n = header.filled().len();
+            if n == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            self.header_len += n;
+
+            if self.header_len == self.header_size() {
+                if self.header[0] & 0x0f == OPCODE_CLOSE {
+                    // Reporting the end of the stream is enough for the connection to be dropped.
+                    return Poll::Ready(Ok(()));
+                }
+                self.start_payload()?;
+            }
+        }
+    }
+}
+
+impl FrameWriter {
+    /// Wrap `payload` in a single masked binary frame, as required of clients.
+    fn start_frame(&mut self, payload: &[u8]) {
+        let mut mask = [0; 4];
+        getrandom::fill(&mut mask).expect("failed to generate websocket mask");
+
+        self.frame.clear();
+        self.frame.push(0x80 | OPCODE_BINARY);
+        match payload.len() {
+            len @ 0..=125 => self.frame.push(0x80 | len as u8),
+            len @ 126..=0xffff => {
+                self.frame.push(0x80 | 126);
+                self.frame.extend((len as u16).to_be_bytes());
+            }
+            len => {
+                self.frame.push(0x80 | 127);
+                self.frame.extend((len as u64).to_be_bytes());
+            }
+        }
+        self.frame.extend(mask);
+        self.frame.extend(
+            payload
+                .iter()
+                .zip(mask.iter().cycle())
+                .map(|(byte, mask)| byte ^ mask),
+        );
+        self.written = 0;
+        self.payload_len = payload.len();
+    }
+
+    /// Write `buf` in its own frame.
+    ///
+    /// If the write cannot complete at once, the caller must try again with the same `buf`,
+    /// which will not be framed again.
+    fn poll_write<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if self.frame.is_empty() {
+            self.start_frame(buf);
+        }
+
+        while self.written != self.frame.len() {
+            let n = ready!(Pin::new(&mut *writer).poll_write(cx, &self.frame[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+
+        self.frame.clear();
+        Poll::Ready(Ok(self.payload_len))
+    }
+}
+
+impl AsyncRead for ReadHalf<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.incoming.poll_read(this.reader, cx, buf)
+    }
+}
+
+impl AsyncWrite for WriteHalf<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.outgoing.poll_write(this.writer, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().writer).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::{NetStream, ServerAddr};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Accept a single WebSocket connection and send back the payload of every frame received.
+    async fn echo_server(listener: TcpListener) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+        let request = String::from_utf8(request).unwrap();
+        let key = request
+            .lines()
+            .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+            .unwrap();
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key)
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+
+        // Also send a ping, which the client should skip over.
+        stream
+            .write_all(&[0x80 | OPCODE_PING, 2, 1, 2])
+            .await
+            .unwrap();
+
+        while let Some(payload) = read_client_frame(&mut stream).await {
+            let mut frame = vec![0x80 | OPCODE_BINARY, 127];
+            frame.extend((payload.len() as u64).to_be_bytes());
+            frame.extend(payload);
+            stream.write_all(&frame).await.unwrap();
+        }
+    }
+
+    async fn read_client_frame(stream: &mut TcpStream) -> Option<Vec<u8>> {
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).await.ok()?;
+        assert_eq!(header[0], 0x80 | OPCODE_BINARY);
+        assert_eq!(header[1] & 0x80, 0x80, "client frames must be masked");
+        let len = match header[1] & 0x7f {
+            126 => stream.read_u16().await.unwrap() as usize,
+            127 => stream.read_u64().await.unwrap() as usize,
+            len => len as usize,
+        };
+        let mut mask = [0; 4];
+        stream.read_exact(&mut mask).await.unwrap();
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await.unwrap();
+        payload
+            .iter_mut()
+            .zip(mask.iter().cycle())
+            .for_each(|(byte, mask)| *byte ^= mask);
+        Some(payload)
+    }
+
+    #[tokio::test]
+    async fn websocket_echo() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/apiws", listener.local_addr().unwrap());
+        let server = tokio::spawn(echo_server(listener));
+
+        let mut stream = NetStream::connect(&ServerAddr::WebSocket { url })
+            .await
+            .unwrap();
+        for len in [4, 200, 70_000] {
+            let payload = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+            let (mut reader, mut writer) = stream.split();
+            writer.write_all(&payload).await.unwrap();
+
+            let mut echoed = vec![0; len];
+            reader.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(echoed, payload);
+        }
+
+        drop(stream);
+        server.await.unwrap();
+    }
+}
@@ -15,7 +15,7 @@ use grammers_mtproto::mtp::{
 use grammers_mtproto::transport::{self, Transport};
 use grammers_mtproto::{MsgId, authentication};
 use grammers_session::updates::UpdatesLike;
-use grammers_tl_types::{self as tl, Deserializable, RemoteCall};
+use grammers_tl_types::{self as tl, Deserializable, Identifiable, RemoteCall};
 use log::{debug, error, info, trace, warn};
 use std::io;
 use std::sync::atomic::{AtomicI64, Ordering};
@@ -55,6 +55,12 @@ const PING_DELAY: Duration = Duration::from_secs(60);
 /// are getting through consistently enough.
 const NO_PING_DISCONNECT: i32 = 75;
 
+/// For how long should the server hold on to an HTTP request with nothing to send back?
+///
+/// This is the default used by the server when no `http_wait` is sent, and it is always
+/// shorter than the [`PING_DELAY`], so that the requests made for pings are answered in time.
+const HTTP_WAIT_MAX: Duration = Duration::from_secs(25);
+
 /// Generate a "random" ping ID.
 pub(crate) fn generate_random_id() -> i64 {
     static LAST_ID: AtomicI64 = AtomicI64::new(0);
@@ -80,7 +86,18 @@ pub struct Sender<T: Transport, M: Mtp> {
     mtp: M,
     addr: ServerAddr,
     requests: Vec<Request>,
+    next_request_id: u64,
+    /// Requests whose answer the server should not send because the caller is no longer waiting.
+    dropped_answers: Vec<MsgId>,
     next_ping: Instant,
+    /// Whether an HTTP request should be kept open at all times with `http_wait`.
+    http_wait: bool,
+    /// How many of the packets written have not been answered by a packet from the server yet.
+    unanswered_packets: usize,
+    /// Round-trip time of the last ping answered by the server.
+    rtt: Option<Duration>,
+    bytes_read: u64,
+    bytes_written: u64,
 
     // Transport-level buffers and positions
     read_buffer: Vec<u8>,
@@ -89,10 +106,51 @@ pub struct Sender<T: Transport, M: Mtp> {
     write_head: usize,
 }
 
+/// Channel through which the result of a request is delivered.
+pub(crate) type ResultSender = oneshot::Sender<Result<Vec<u8>, InvocationError>>;
+
 struct Request {
     body: Vec<u8>,
     state: RequestState,
     result: oneshot::Sender<Result<Vec<u8>, InvocationError>>,
+    /// Whether the request should be dropped once nobody is waiting for its result.
+    cancellable: bool,
+    id: RequestId,
+    /// Request that must be executed by the server before this one, if any.
+    after: Option<RequestId>,
+    /// When the request was written to the network, used to measure the round-trip time.
+    sent_at: Option<Instant>,
+    /// Whether the server acknowledged receiving the request since it was last sent.
+    acknowledged: bool,
+}
+
+/// Statistics about the connection of a [`Sender`], such as to export them as metrics.
+#[non_exhaustive]
+#[derive(Clone, Debug, Default)]
+pub struct SenderStats {
+    /// Round-trip time measured from the last ping answered by the server, if any.
+    pub rtt: Option<Duration>,
+    /// Total amount of bytes read from the network.
+    pub bytes_read: u64,
+    /// Total amount of bytes written to the network.
+    pub bytes_written: u64,
+    /// Requests waiting to be sent.
+    pub queued_requests: usize,
+    /// Requests sent but not answered yet.
+    pub in_flight_requests: usize,
+    /// Server salts known, including the one currently in use. More are fetched before running out.
+    pub salts_remaining: usize,
+    /// Difference in seconds between the server's clock and the local one.
+    pub time_offset: i32,
+}
+
+/// Locally-unique identifier for an enqueued request, used to express ordering between them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct RequestId(u64);
+
+impl RequestId {
+    /// Identifier for requests that others can't be ordered after.
+    const NONE: Self = Self(0);
 }
 
 #[derive(Clone, Debug)]
@@ -129,7 +187,14 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
             mtp,
             addr,
             requests: vec![],
+            next_request_id: 1,
+            dropped_answers: vec![],
             next_ping: Instant::now() + PING_DELAY,
+            http_wait: false,
+            unanswered_packets: 0,
+            rtt: None,
+            bytes_read: 0,
+            bytes_written: 0,
 
             read_buffer: vec![0; MAXIMUM_DATA],
             read_tail: 0,
@@ -156,6 +221,19 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
         body: Vec<u8>,
         tx: oneshot::Sender<Result<Vec<u8>, InvocationError>>,
     ) {
+        self.enqueue_body_after(body, tx, None);
+    }
+
+    /// Like [`Self::enqueue_body`], but the server will only execute the request after
+    /// the one identified by `after`, if any, has been executed.
+    ///
+    /// Returns the identifier of the enqueued request, so that others can follow it.
+    pub(crate) fn enqueue_body_after(
+        &mut self,
+        body: Vec<u8>,
+        tx: oneshot::Sender<Result<Vec<u8>, InvocationError>>,
+        after: Option<RequestId>,
+    ) -> RequestId {
         assert!(body.len() >= 4);
         let req_id = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
         debug!(
@@ -163,11 +241,19 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
             tl::name_for_id(req_id)
         );
 
+        let id = RequestId(self.next_request_id);
+        self.next_request_id += 1;
         self.requests.push(Request {
             body,
             state: RequestState::NotSerialized,
             result: tx,
+            cancellable: true,
+            id,
+            after,
+            sent_at: None,
+            acknowledged: false,
         });
+        id
     }
 
     async fn step_until_receive(
@@ -193,6 +279,18 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
     /// If an error is returned, the connection should be treated
     /// as dead and the sender instance recreated.
     pub async fn step(&mut self) -> Result<Vec<UpdatesLike>, ReadError> {
+        match self.try_step().await {
+            Ok(ok) => Ok(ok),
+            Err(err) => {
+                self.on_error(&err);
+                Err(err)
+            }
+        }
+    }
+
+    /// Like [`Self::step`], but requests are kept on error rather than being marked as failed,
+    /// so that they can be [`Self::take_requests`] and sent through a different connection.
+    pub(crate) async fn try_step(&mut self) -> Result<Vec<UpdatesLike>, ReadError> {
         self.try_fill_write();
         let write_len = self.write_buffer.len() - self.write_head;
         trace!(
@@ -203,7 +301,7 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
         let (mut reader, mut writer) = self.stream.split();
         let sleep = sleep_until(self.next_ping);
 
-        let res = tokio::select! {
+        tokio::select! {
             n = reader.read(&mut self.read_buffer[self.read_tail..]) => {
                 n.map_err(ReadError::Io).and_then(|n| self.on_net_read(n))
             }
@@ -217,13 +315,73 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
                 self.on_ping_timeout();
                 Ok(Vec::new())
             }
-        };
+        }
+    }
 
-        match res {
-            Ok(ok) => Ok(ok),
-            Err(err) => {
-                self.on_error(&err);
-                Err(err)
+    /// Remove all requests still waiting for a response, along with the channel to deliver it.
+    ///
+    /// Requests whose caller is no longer interested in the response (such as pings) are dropped.
+    pub(crate) fn take_requests(&mut self) -> Vec<(Vec<u8>, ResultSender)> {
+        self.requests
+            .drain(..)
+            .filter(|request| !request.result.is_closed())
+            .map(|request| (request.body, request.result))
+            .collect()
+    }
+
+    /// Open a new connection to the same address, resuming the current session through it.
+    ///
+    /// The `transport` must be a new instance, because its state belongs to the lost connection.
+    ///
+    /// Requests that the server acknowledged are kept waiting for their response, which the
+    /// server will deliver through the new connection. Any others are sent again.
+    pub async fn reconnect(&mut self, transport: T) -> Result<(), io::Error> {
+        self.stream = NetStream::connect(&self.addr).await?;
+        self.transport = transport;
+        self.read_tail = 0;
+        self.write_buffer.clear();
+        self.write_head = 0;
+        self.unanswered_packets = 0;
+
+        let mut resent = 0;
+        for request in self.requests.iter_mut() {
+            match request.state {
+                RequestState::NotSerialized => {}
+                RequestState::Sent(_) if request.acknowledged => {}
+                RequestState::Serialized(_) | RequestState::Sent(_) => {
+                    request.state = RequestState::NotSerialized;
+                    resent += 1;
+                }
+            }
+        }
+        info!("resumed session through a new connection; re-sending {resent} request(s)");
+        Ok(())
+    }
+
+    /// Stop tracking the requests whose caller is no longer waiting for a response.
+    ///
+    /// Requests that were not sent yet are simply forgotten. For those that were, the server
+    /// is asked to not send the answer via `rpc_drop_answer`.
+    ///
+    /// Must not be called while requests are [`RequestState::Serialized`] but not yet sent.
+    fn drop_cancelled_requests(&mut self) {
+        let mut i = 0;
+        while i < self.requests.len() {
+            let request = &self.requests[i];
+            if !request.cancellable || !request.result.is_closed() {
+                i += 1;
+                continue;
+            }
+
+            match self.requests.swap_remove(i).state {
+                RequestState::NotSerialized => debug!("dropping cancelled request before sending"),
+                RequestState::Serialized(pair) => {
+                    unreachable!("cancelled request {pair:?} is being sent")
+                }
+                RequestState::Sent(pair) => {
+                    debug!("dropping answer to cancelled request {:?}", pair.msg_id);
+                    self.dropped_answers.push(pair.msg_id);
+                }
             }
         }
     }
@@ -234,14 +392,56 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
             return;
         }
 
+        self.drop_cancelled_requests();
+        while let Some(msg_id) = self.dropped_answers.last() {
+            let body = tl::functions::RpcDropAnswer {
+                req_msg_id: msg_id.value(),
+            }
+            .to_bytes();
+
+            // The answer to `rpc_drop_answer` itself is not delivered by the MTP,
+            // so it is not tracked as a request.
+            if self.mtp.push(&mut self.write_buffer, &body).is_some() {
+                self.dropped_answers.pop();
+            } else {
+                break;
+            }
+        }
+
         // TODO add a test to make sure we only ever send the same request once
-        for request in self
-            .requests
-            .iter_mut()
-            .filter(|r| matches!(r.state, RequestState::NotSerialized))
-        {
+        for i in 0..self.requests.len() {
+            if !matches!(self.requests[i].state, RequestState::NotSerialized) {
+                continue;
+            }
+
+            // Requests following another one wait until it's serialized to refer to its
+            // message identifier. If it was already answered, there's nothing to wait for.
+            let after_msg_id = match self.requests[i].after.and_then(|after| {
+                self.requests
+                    .iter()
+                    .find(|request| request.id == after)
+                    .map(|request| &request.state)
+            }) {
+                None => None,
+                Some(RequestState::NotSerialized) => continue,
+                Some(RequestState::Serialized(pair) | RequestState::Sent(pair)) => {
+                    Some(pair.msg_id)
+                }
+            };
+
+            let request = &mut self.requests[i];
             // TODO make mtp itself use BytesMut to avoid copies
-            if let Some(msg_id) = self.mtp.push(&mut self.write_buffer, &request.body) {
+            let pushed = match after_msg_id {
+                Some(msg_id) => {
+                    let mut body = Vec::with_capacity(12 + request.body.len());
+                    tl::functions::InvokeAfterMsg::<()>::CONSTRUCTOR_ID.serialize(&mut body);
+                    msg_id.value().serialize(&mut body);
+                    body.extend_from_slice(&request.body);
+                    self.mtp.push(&mut self.write_buffer, &body)
+                }
+                None => self.mtp.push(&mut self.write_buffer, &request.body),
+            };
+            if let Some(msg_id) = pushed {
                 assert!(request.body.len() >= 4);
                 let req_id = u32::from_le_bytes([
                     request.body[0],
@@ -264,7 +464,22 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
             }
         }
 
-        if let Some(container_msg_id) = self.mtp.finalize(&mut self.write_buffer) {
+        let mut container_msg_id = self.mtp.finalize(&mut self.write_buffer);
+        if container_msg_id.is_none() && self.http_wait && self.unanswered_packets == 0 {
+            // The server can only send data back in response to a request, so one is kept open.
+            let body = tl::enums::HttpWait::Wait(tl::types::HttpWait {
+                max_delay: 0,
+                wait_after: 0,
+                max_wait: HTTP_WAIT_MAX.as_millis() as i32,
+            })
+            .to_bytes();
+            if self.mtp.push(&mut self.write_buffer, &body).is_some() {
+                trace!("serialized http_wait to poll for new messages");
+                container_msg_id = self.mtp.finalize(&mut self.write_buffer);
+            }
+        }
+
+        if let Some(container_msg_id) = container_msg_id {
             for request in self.requests.iter_mut() {
                 match request.state {
                     RequestState::Serialized(ref mut pair) => {
@@ -289,6 +504,7 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
         }
 
         self.read_tail += n;
+        self.bytes_read += n as u64;
         trace!("read {} bytes from the network", n);
         trace!("trying to unpack buffer of {} bytes...", self.read_tail);
 
@@ -309,6 +525,7 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
 
                     self.process_mtp_buffer(result, &mut updates);
                     next_offset += offset.next_offset;
+                    self.unanswered_packets = self.unanswered_packets.saturating_sub(1);
                 }
                 Err(transport::Error::MissingBytes) => break,
                 Err(err) => return Err(err.into()),
@@ -324,6 +541,7 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
     /// Handle `n` more written bytes being ready to process by the transport.
     fn on_net_write(&mut self, n: usize) {
         self.write_head += n;
+        self.bytes_written += n as u64;
         trace!(
             "written {} bytes to the network ({}/{})",
             n,
@@ -337,12 +555,15 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
 
         self.write_buffer.clear();
         self.write_head = 0;
+        self.unanswered_packets += 1;
+        let now = Instant::now();
         for req in self.requests.iter_mut() {
             match &req.state {
                 RequestState::NotSerialized | RequestState::Sent(_) => {}
                 RequestState::Serialized(pair) => {
                     debug!("sent request with {:?}", pair);
                     req.state = RequestState::Sent(pair.clone());
+                    req.sent_at = Some(now);
                 }
             }
         }
@@ -353,14 +574,20 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
         let ping_id = generate_random_id();
         debug!("enqueueing keepalive ping {}", ping_id);
         let (tx, _rx) = oneshot::channel();
-        self.enqueue_body(
-            tl::functions::PingDelayDisconnect {
+        self.requests.push(Request {
+            body: tl::functions::PingDelayDisconnect {
                 ping_id,
                 disconnect_delay: NO_PING_DISCONNECT,
             }
             .to_bytes(),
-            tx,
-        );
+            state: RequestState::NotSerialized,
+            result: tx,
+            cancellable: false,
+            id: RequestId::NONE,
+            after: None,
+            sent_at: None,
+            acknowledged: false,
+        });
         self.next_ping = Instant::now() + PING_DELAY;
     }
 
@@ -392,6 +619,8 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
                 Deserialization::RpcResult(result) => self.process_result(result),
                 Deserialization::RpcError(error) => self.process_error(error),
                 Deserialization::BadMessage(bad_msg) => self.process_bad_message(bad_msg),
+                Deserialization::Ack(msg_id) => self.process_ack(msg_id),
+                Deserialization::Resend(msg_id) => self.process_resend(msg_id),
                 Deserialization::Failure(failure) => self.process_deserialize_error(failure),
             }
         }
@@ -460,6 +689,11 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
                 tl::name_for_id(res_id),
                 result.msg_id
             );
+            if res_id == tl::types::Pong::CONSTRUCTOR_ID
+                && let Some(sent_at) = req.sent_at
+            {
+                self.rtt = Some(sent_at.elapsed());
+            }
             drop(req.result.send(Ok(x)));
         } else {
             info!(
@@ -535,6 +769,32 @@ impl<T: Transport, M: Mtp> Sender<T, M> {
         }
     }
 
+    fn process_ack(&mut self, msg_id: MsgId) {
+        for request in self.requests.iter_mut() {
+            match &request.state {
+                RequestState::Sent(pair) if pair.msg_id == msg_id => {
+                    request.acknowledged = true;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn process_resend(&mut self, msg_id: MsgId) {
+        for request in self.requests.iter_mut() {
+            match &request.state {
+                RequestState::Sent(pair)
+                    if pair.msg_id == msg_id || pair.container_msg_id == msg_id =>
+                {
+                    info!("server asked to re-send request {:?}", pair.msg_id);
+                    request.state = RequestState::NotSerialized;
+                    request.acknowledged = false;
+                }
+                _ => {}
+            }
+        }
+    }
+
     fn process_deserialize_error(&mut self, failure: DeserializationFailure) {
         if let Some(req) = self.pop_request(failure.msg_id) {
             debug!("got deserialization failure {:?}", failure.error);
@@ -576,6 +836,114 @@ impl<T: Transport> Sender<T, mtp::Encrypted> {
     pub fn auth_key(&self) -> [u8; 256] {
         self.mtp.auth_key()
     }
+
+    /// Bind the temporary Authorization Key this sender is using to the permanent
+    /// `perm_auth_key`, until the Unix timestamp `expires_at`.
+    ///
+    /// This must be the first request sent by a sender created with [`generate_temp_auth_key`].
+    pub async fn bind_temp_auth_key(
+        &mut self,
+        perm_auth_key: &[u8; 256],
+        expires_at: i32,
+    ) -> Result<(), InvocationError> {
+        let temp_auth_key = self.mtp.auth_key();
+        let temp_session_id = self.mtp.session_id();
+
+        let mut body = Vec::new();
+        let msg_id = self.mtp.push_with_msg_id(&mut self.write_buffer, |msg_id| {
+            body = authentication::bind_temp_auth_key(
+                perm_auth_key,
+                &temp_auth_key,
+                temp_session_id,
+                msg_id,
+                expires_at,
+            )
+            .to_bytes();
+            body.clone()
+        });
+        let container_msg_id = self.mtp.finalize(&mut self.write_buffer).unwrap_or(msg_id);
+        self.transport.pack(&mut self.write_buffer);
+        debug!("serialized request auth.bindTempAuthKey with {msg_id:?}");
+
+        let (tx, rx) = oneshot::channel();
+        self.requests.push(Request {
+            body,
+            state: RequestState::Serialized(MsgIdPair {
+                msg_id,
+                container_msg_id,
+            }),
+            result: tx,
+            cancellable: false,
+            id: RequestId::NONE,
+            after: None,
+            sent_at: None,
+            acknowledged: false,
+        });
+
+        let result = self.step_until_receive(rx).await?;
+        match bool::from_bytes(&result)? {
+            true => Ok(()),
+            false => Err(InvocationError::Authentication(
+                authentication::Error::TempAuthKeyNotBound,
+            )),
+        }
+    }
+
+    /// Keep an HTTP request open at all times by sending `http_wait` when there is nothing else
+    /// to send, so that the server can deliver responses and updates as soon as they are ready.
+    ///
+    /// This is required by [`transport::Http`], where the server may only send data in response
+    /// to a request. It should not be used with other transports.
+    pub fn enable_http_wait(&mut self) {
+        self.http_wait = true;
+    }
+
+    /// Statistics about this connection, as of the last step.
+    pub fn stats(&self) -> SenderStats {
+        let queued_requests = self
+            .requests
+            .iter()
+            .filter(|request| matches!(request.state, RequestState::NotSerialized))
+            .count();
+
+        SenderStats {
+            rtt: self.rtt,
+            bytes_read: self.bytes_read,
+            bytes_written: self.bytes_written,
+            queued_requests,
+            in_flight_requests: self.requests.len() - queued_requests,
+            salts_remaining: self.mtp.salt_count(),
+            time_offset: self.mtp.time_offset(),
+        }
+    }
+
+    /// Whether there are requests that have been enqueued but not yet answered.
+    pub(crate) fn has_pending_requests(&self) -> bool {
+        !self.requests.is_empty()
+    }
+
+    /// Whether any of the requests that have not been answered yet still has a caller waiting for it.
+    pub(crate) fn has_awaited_requests(&self) -> bool {
+        self.requests
+            .iter()
+            .any(|request| !request.result.is_closed())
+    }
+
+    /// How many requests have been enqueued but not yet answered.
+    pub(crate) fn pending_requests(&self) -> usize {
+        self.requests.len()
+    }
+}
+
+#[cfg(test)]
+impl<T: Transport, M: Mtp> Sender<T, M> {
+    /// Act as if the server acknowledged every request sent so far.
+    pub(crate) fn acknowledge_sent_requests(&mut self) {
+        self.requests
+            .iter_mut()
+            .filter(|request| matches!(request.state, RequestState::Sent(_)))
+            .for_each(|request| request.acknowledged = true);
+    }
 }
 
 /// Helper function to [`Sender::connect`] a plain transport and [`generate_auth_key`] on it.
@@ -590,14 +958,38 @@ pub async fn connect<T: Transport>(
 /// Uses the input plain sender to carry the Authorization Key generation process,
 /// and returns an encrypted sender reusing the same connection, transport and buffers.
 pub async fn generate_auth_key<T: Transport>(
-    mut sender: Sender<T, mtp::Plain>,
+    sender: Sender<T, mtp::Plain>,
 ) -> Result<Sender<T, mtp::Encrypted>, InvocationError> {
     info!("generating new authorization key...");
+    do_generate_auth_key(sender, None).await
+}
+
+/// Like [`generate_auth_key`], but the generated key is temporary, valid in the datacenter
+/// `dc_id` for `expires_in` seconds.
+///
+/// The temporary key must be bound to a permanent one with [`Sender::bind_temp_auth_key`]
+/// before it can be used to invoke any other request.
+pub async fn generate_temp_auth_key<T: Transport>(
+    sender: Sender<T, mtp::Plain>,
+    dc_id: i32,
+    expires_in: i32,
+) -> Result<Sender<T, mtp::Encrypted>, InvocationError> {
+    info!("generating new temporary authorization key...");
+    do_generate_auth_key(sender, Some((dc_id, expires_in))).await
+}
+
+async fn do_generate_auth_key<T: Transport>(
+    mut sender: Sender<T, mtp::Plain>,
+    temp: Option<(i32, i32)>,
+) -> Result<Sender<T, mtp::Encrypted>, InvocationError> {
     let (request, data) = authentication::step1()?;
     debug!("gen auth key: sending step 1");
     let response = sender.invoke(&request).await?;
     debug!("gen auth key: starting step 2");
-    let (request, data) = authentication::step2(data, response)?;
+    let (request, data) = match temp {
+        None => authentication::step2(data, response)?,
+        Some((dc_id, expires_in)) => authentication::step2_temp(data, response, dc_id, expires_in)?,
+    };
     debug!("gen auth key: sending step 2");
     let response = sender.invoke(&request).await?;
     debug!("gen auth key: starting step 3");
@@ -620,7 +1012,14 @@ pub async fn generate_auth_key<T: Transport>(
             .first_salt(first_salt)
             .finish(auth_key),
         requests: sender.requests,
+        next_request_id: sender.next_request_id,
+        dropped_answers: sender.dropped_answers,
         next_ping: Instant::now() + PING_DELAY,
+        http_wait: sender.http_wait,
+        unanswered_packets: sender.unanswered_packets,
+        rtt: sender.rtt,
+        bytes_read: sender.bytes_read,
+        bytes_written: sender.bytes_written,
         read_buffer: sender.read_buffer,
         read_tail: sender.read_tail,
         write_buffer: sender.write_buffer,
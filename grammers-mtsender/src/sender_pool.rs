@@ -6,54 +6,196 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use crate::configuration::ConnectionParams;
+use crate::configuration::{ConnectionParams, IpPreference, TransportMode};
 use crate::errors::ReadError;
-use crate::{InvocationError, Sender, ServerAddr, connect, connect_with_auth};
+#[cfg(feature = "proxy")]
+use crate::net::MtProxy;
+use crate::{
+    InvocationError, Sender, SenderStats, ServerAddr, connect, connect_with_auth,
+    generate_temp_auth_key,
+};
 use grammers_mtproto::{mtp, transport};
 use grammers_session::Session;
 use grammers_session::types::DcOption;
 use grammers_session::updates::UpdatesLike;
 use grammers_tl_types::{self as tl, enums};
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use log::{info, warn};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
 use std::ops::ControlFlow;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use std::{fmt, panic};
-use tokio::task::AbortHandle;
+use tokio::task::{self, AbortHandle};
+use tokio::time::{Instant, sleep_until, timeout, timeout_at};
 use tokio::{
-    sync::{mpsc, oneshot},
+    sync::{broadcast, mpsc, oneshot},
     task::JoinSet,
 };
 
-pub(crate) type Transport = transport::Full;
+pub(crate) type Transport = Box<dyn transport::Transport + Send>;
+
+/// For how long temporary Authorization Keys are valid when Perfect Forward Secrecy is enabled.
+const TEMP_AUTH_KEY_EXPIRES_IN: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long before expiring should connections using a temporary key stop being used.
+const TEMP_AUTH_KEY_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// How many [`ConnectionEvent`]s are kept for subscribers that fall behind.
+const EVENTS_CAPACITY: usize = 64;
+
+/// Port where datacenters accept connections using [`TransportMode::Http`].
+const HTTP_PORT: u16 = 80;
+
+/// How many times connections are checked for inactivity during [`ConnectionParams::idle_timeout`].
+const IDLE_CHECKS_PER_TIMEOUT: u32 = 4;
+
+/// Lower bound for the interval between checks for inactive connections.
+const MIN_IDLE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a retired connection may take to answer the requests it had already sent.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
 
 type InvokeResponse = Vec<u8>;
 
 enum Request {
     Invoke {
         dc_id: i32,
-        body: Vec<u8>,
-        tx: oneshot::Sender<Result<InvokeResponse, InvocationError>>,
+        lane: Option<usize>,
+        /// Sent through the same connection, so that they can be ordered with respect to each other.
+        rpcs: Vec<Rpc>,
     },
     Disconnect {
         dc_id: i32,
     },
+    Stats {
+        tx: oneshot::Sender<Vec<ConnectionStats>>,
+    },
     Quit,
 }
 
 struct Rpc {
     body: Vec<u8>,
     tx: oneshot::Sender<Result<InvokeResponse, InvocationError>>,
+    /// Whether the server should only execute it after the previous one on the same connection.
+    after_previous: bool,
+}
+
+/// A connection that was lost, along with the requests that were still waiting for a response.
+struct Disconnection {
+    dc_id: i32,
+    lane: usize,
+    error: ReadError,
+    /// Requests that had not reached the sender yet.
+    rpcs: Vec<Rpc>,
+    /// The sender of the lost connection, still holding the requests it had enqueued.
+    sender: Box<Sender<Transport, mtp::Encrypted>>,
+}
+
+/// A lost connection that is pending to be re-established.
+struct Reconnection {
+    dc_id: i32,
+    lane: usize,
+    /// Error that caused the connection to be lost, used to fail the requests if giving up.
+    error: ReadError,
+    rpcs: Vec<Rpc>,
+    /// The sender whose session will be resumed, if only the underlying connection was lost.
+    ///
+    /// Resuming the session means the requests that the server already acknowledged are not
+    /// sent again. Otherwise, a new session is made and all of the requests are in `rpcs`.
+    sender: Option<Box<Sender<Transport, mtp::Encrypted>>>,
+    /// How many attempts to reconnect have failed.
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+impl Reconnection {
+    /// Give up, failing all of the requests with the error that caused the connection to be lost.
+    fn fail(mut self) {
+        if let Some(sender) = self.sender.as_mut() {
+            self.rpcs.splice(0..0, take_rpcs(sender));
+        }
+        fail_rpcs(self.rpcs, &self.error.into());
+    }
 }
 
 struct ConnectionInfo {
     dc_id: i32,
+    /// Which of the connections to the same datacenter this is.
+    lane: usize,
     rpc_tx: mpsc::UnboundedSender<Rpc>,
+    /// How many requests sent through this connection have not been answered yet.
+    load: Arc<AtomicUsize>,
+    /// Statistics of the sender, updated as it runs.
+    stats: Arc<Mutex<SenderStats>>,
     abort_handle: AbortHandle,
+    /// When the connection should be replaced, if it uses a temporary Authorization Key.
+    expires_at: Option<Instant>,
+    /// Since when the connection has had no requests waiting for a response, as last observed.
+    idle_since: Option<Instant>,
+}
+
+/// Change in the state of one of the connections managed by the [`SenderPoolRunner`].
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub enum ConnectionEvent {
+    /// A new connection to the datacenter is being made.
+    Connecting { dc_id: i32 },
+    /// A new permanent Authorization Key was generated for the datacenter.
+    AuthKeyGenerated { dc_id: i32 },
+    /// The connection to the datacenter was made and initialized, and is ready to use.
+    Connected { dc_id: i32 },
+    /// The connection to the datacenter was closed.
+    Disconnected {
+        dc_id: i32,
+        reason: DisconnectReason,
+    },
+    /// A lost connection to the datacenter will be re-established after `delay`.
+    ///
+    /// `attempt` starts at zero, and increases every time the attempt fails.
+    Reconnecting {
+        dc_id: i32,
+        attempt: u32,
+        delay: Duration,
+    },
+    /// The session's home datacenter changed, such as after a migration when signing in.
+    Migrated { from: i32, to: i32 },
+}
+
+/// Statistics about one of the connections managed by the [`SenderPoolRunner`].
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub struct ConnectionStats {
+    /// Datacenter the connection is made to.
+    pub dc_id: i32,
+    /// Which of the connections to the same datacenter this is.
+    pub lane: usize,
+    /// Statistics of the [`Sender`] driving the connection.
+    pub sender: SenderStats,
+}
+
+/// Why a connection was closed.
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub enum DisconnectReason {
+    /// It was explicitly requested via [`SenderPoolHandle::disconnect_from_dc`].
+    Requested,
+    /// Its temporary Authorization Key was about to expire, so it was replaced with a new one.
+    KeyExpired,
+    /// It had no requests waiting for a response for [`ConnectionParams::idle_timeout`],
+    /// or was closed to make room for another under [`ConnectionParams::max_connections`].
+    Idle,
+    /// The connection failed.
+    Error(ReadError),
 }
 
 /// Cheaply cloneable handle to interact with its [`SenderPoolRunner`].
 #[derive(Clone)]
-pub struct SenderPoolHandle(mpsc::UnboundedSender<Request>);
+pub struct SenderPoolHandle {
+    requests: mpsc::UnboundedSender<Request>,
+    events: broadcast::Sender<ConnectionEvent>,
+}
 
 /// Named type holding the actual runner and initial handles. The entry point.
 pub struct SenderPool {
@@ -88,23 +230,139 @@ pub struct SenderPoolRunner {
     pub connection_params: ConnectionParams,
     request_rx: mpsc::UnboundedReceiver<Request>,
     updates_tx: mpsc::UnboundedSender<UpdatesLike>,
+    events_tx: broadcast::Sender<ConnectionEvent>,
+    /// Last-known home datacenter, used to detect migrations.
+    home_dc_id: i32,
     connections: Vec<ConnectionInfo>,
-    connection_pool: JoinSet<Result<(), ReadError>>,
+    connection_pool: JoinSet<Result<(), Disconnection>>,
+    reconnections: Vec<Reconnection>,
+    /// When connections should be checked next for [`ConnectionParams::idle_timeout`].
+    next_idle_check: Instant,
 }
 
 impl SenderPoolHandle {
     /// Communicate with the running [`SenderPoolRunner`] instance
     /// to invoke the serialized request body in the specified datacenter.
+    ///
+    /// If [`ConnectionParams::connections_per_dc`] allows for more than one connection,
+    /// the request is sent through the one with the least load.
+    ///
+    /// If the returned future is dropped before completion, the request is cancelled.
+    /// It will not be sent if it was still waiting to be, and otherwise the server will
+    /// be asked to not send its response.
     pub async fn invoke_in_dc(
         &self,
         dc_id: i32,
         body: Vec<u8>,
+    ) -> Result<InvokeResponse, InvocationError> {
+        self.invoke_in_dc_with(dc_id, None, None, body).await
+    }
+
+    /// Like [`Self::invoke_in_dc`], but the request is cancelled if its response has not
+    /// arrived by the given `deadline`, in which case [`InvocationError::Timeout`] is returned.
+    pub async fn invoke_in_dc_with_deadline(
+        &self,
+        dc_id: i32,
+        body: Vec<u8>,
+        deadline: Instant,
+    ) -> Result<InvokeResponse, InvocationError> {
+        self.invoke_in_dc_with(dc_id, None, Some(deadline), body)
+            .await
+    }
+
+    /// Like [`Self::invoke_in_dc`], but always through the same connection to the datacenter.
+    ///
+    /// Lanes are numbered from zero, and wrap around [`ConnectionParams::connections_per_dc`].
+    /// This is useful to spread out long-running transfers with one lane per worker.
+    pub async fn invoke_in_dc_lane(
+        &self,
+        dc_id: i32,
+        lane: usize,
+        body: Vec<u8>,
+    ) -> Result<InvokeResponse, InvocationError> {
+        self.invoke_in_dc_with(dc_id, Some(lane), None, body).await
+    }
+
+    /// Most general form of [`Self::invoke_in_dc`], which allows specifying both the lane
+    /// of [`Self::invoke_in_dc_lane`] and the deadline of [`Self::invoke_in_dc_with_deadline`].
+    pub async fn invoke_in_dc_with(
+        &self,
+        dc_id: i32,
+        lane: Option<usize>,
+        deadline: Option<Instant>,
+        body: Vec<u8>,
     ) -> Result<InvokeResponse, InvocationError> {
         let (tx, rx) = oneshot::channel();
-        self.0
-            .send(Request::Invoke { dc_id, body, tx })
+        self.requests
+            .send(Request::Invoke {
+                dc_id,
+                lane,
+                rpcs: vec![Rpc {
+                    body,
+                    tx,
+                    after_previous: false,
+                }],
+            })
             .map_err(|_| InvocationError::Dropped)?;
-        rx.await.map_err(|_| InvocationError::Dropped)?
+
+        // Dropping the receiver is what signals the cancellation to the sender.
+        let response = match deadline {
+            Some(deadline) => timeout_at(deadline, rx)
+                .await
+                .map_err(|_| InvocationError::Timeout)?,
+            None => rx.await,
+        };
+        response.map_err(|_| InvocationError::Dropped)?
+    }
+
+    /// Communicate with the running [`SenderPoolRunner`] instance to invoke the serialized
+    /// request bodies in the specified datacenter, such that the server executes them in order.
+    ///
+    /// All requests are sent at once through the same connection, with every request after the
+    /// first wrapped in [`tl::functions::InvokeAfterMsg`]. A request failing does not prevent
+    /// the next ones from executing. If the connection is lost and the requests have to be sent
+    /// again, they are no longer guaranteed to execute in order.
+    ///
+    /// The result of each request is returned in the same order as the input.
+    pub async fn invoke_ordered_in_dc(
+        &self,
+        dc_id: i32,
+        bodies: Vec<Vec<u8>>,
+    ) -> Vec<Result<InvokeResponse, InvocationError>> {
+        let (rpcs, receivers) = bodies
+            .into_iter()
+            .enumerate()
+            .map(|(i, body)| {
+                let (tx, rx) = oneshot::channel();
+                let rpc = Rpc {
+                    body,
+                    tx,
+                    after_previous: i != 0,
+                };
+                (rpc, rx)
+            })
+            .unzip::<_, _, Vec<_>, Vec<_>>();
+
+        if self
+            .requests
+            .send(Request::Invoke {
+                dc_id,
+                lane: None,
+                rpcs,
+            })
+            .is_err()
+        {
+            return receivers
+                .iter()
+                .map(|_| Err(InvocationError::Dropped))
+                .collect();
+        }
+
+        let mut results = Vec::with_capacity(receivers.len());
+        for rx in receivers {
+            results.push(rx.await.unwrap_or(Err(InvocationError::Dropped)));
+        }
+        results
     }
 
     /// Communicate with the running [`SenderPoolRunner`] instance
@@ -115,13 +373,32 @@ impl SenderPoolHandle {
     /// This is useful after datacenter migrations during sign in,
     /// when the old connection is known to not be needed anymore.
     pub fn disconnect_from_dc(&self, dc_id: i32) -> bool {
-        self.0.send(Request::Disconnect { dc_id }).is_ok()
+        self.requests.send(Request::Disconnect { dc_id }).is_ok()
     }
 
     /// Communicate with the running [`SenderPoolRunner`] instance
     /// to drop all active connections and gracefully stop running.
     pub fn quit(&self) -> bool {
-        self.0.send(Request::Quit).is_ok()
+        self.requests.send(Request::Quit).is_ok()
+    }
+
+    /// Communicate with the running [`SenderPoolRunner`] instance to retrieve
+    /// statistics about all of its active connections.
+    pub async fn connection_stats(&self) -> Result<Vec<ConnectionStats>, InvocationError> {
+        let (tx, rx) = oneshot::channel();
+        self.requests
+            .send(Request::Stats { tx })
+            .map_err(|_| InvocationError::Dropped)?;
+        rx.await.map_err(|_| InvocationError::Dropped)
+    }
+
+    /// Subscribe to the [`ConnectionEvent`]s produced by the running [`SenderPoolRunner`]
+    /// from this point onwards.
+    ///
+    /// Only a limited amount of events is kept for each subscriber. If they are not
+    /// received fast enough, the oldest ones are lost and the receiver reports a lag.
+    pub fn events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
     }
 }
 
@@ -146,6 +423,8 @@ impl SenderPool {
     ) -> Self {
         let (request_tx, request_rx) = mpsc::unbounded_channel();
         let (updates_tx, updates_rx) = mpsc::unbounded_channel();
+        let (events_tx, _) = broadcast::channel(EVENTS_CAPACITY);
+        let home_dc_id = session.home_dc_id();
 
         Self {
             runner: SenderPoolRunner {
@@ -154,10 +433,17 @@ impl SenderPool {
                 connection_params,
                 request_rx,
                 updates_tx,
+                events_tx: events_tx.clone(),
+                home_dc_id,
                 connections: Vec::new(),
                 connection_pool: JoinSet::new(),
+                reconnections: Vec::new(),
+                next_idle_check: Instant::now(),
+            },
+            handle: SenderPoolHandle {
+                requests: request_tx,
+                events: events_tx,
             },
-            handle: SenderPoolHandle(request_tx),
             updates: updates_rx,
         }
     }
@@ -167,19 +453,42 @@ impl SenderPoolRunner {
     /// Run the sender pool until [`SenderPoolHandle::quit`] is called or the returned future is dropped.
     ///
     /// Connections will be initiated on-demand whenever the first request to a datacenter is made.
+    /// Lost connections are re-established according to the [`ConnectionParams::reconnection_policy`],
+    /// and inactive ones are closed according to the [`ConnectionParams::idle_timeout`].
     pub async fn run(mut self) {
         loop {
+            let next_reconnection = self
+                .reconnections
+                .iter()
+                .map(|reconnection| reconnection.next_attempt)
+                .min();
+            let idle_timeout = self.connection_params.idle_timeout;
+
             tokio::select! {
                 biased;
-                completion = self.connection_pool.join_next(), if !self.connection_pool.is_empty() => {
-                    if let Err(err) = completion.unwrap() {
-                        if let Ok(reason) = err.try_into_panic() {
-                            panic::resume_unwind(reason);
+                completion = self.connection_pool.join_next_with_id(), if !self.connection_pool.is_empty() => {
+                    match completion.unwrap() {
+                        Ok((_, Ok(()))) => {}
+                        Ok((id, Err(disconnection))) => self.on_disconnection(id, disconnection),
+                        Err(err) => {
+                            if let Ok(reason) = err.try_into_panic() {
+                                panic::resume_unwind(reason);
+                            }
                         }
                     }
                     self.connections
                         .retain(|connection| !connection.abort_handle.is_finished());
                 }
+                _ = sleep_until(next_reconnection.unwrap_or_else(Instant::now)), if next_reconnection.is_some() => {
+                    self.reconnect().await;
+                }
+                _ = sleep_until(self.next_idle_check), if idle_timeout.is_some() => {
+                    let idle_timeout = idle_timeout.unwrap();
+                    let now = Instant::now();
+                    self.close_idle_connections(idle_timeout, now);
+                    self.next_idle_check =
+                        now + (idle_timeout / IDLE_CHECKS_PER_TIMEOUT).max(MIN_IDLE_CHECK_INTERVAL);
+                }
                 request = self.request_rx.recv() => {
                     let flow = if let Some(request) = request {
                         self.process_request(request).await
@@ -199,52 +508,89 @@ impl SenderPoolRunner {
     }
 
     async fn process_request(&mut self, request: Request) -> ControlFlow<()> {
+        let home_dc_id = self.session.home_dc_id();
+        if home_dc_id != self.home_dc_id {
+            self.emit(ConnectionEvent::Migrated {
+                from: self.home_dc_id,
+                to: home_dc_id,
+            });
+            self.home_dc_id = home_dc_id;
+        }
+
         match request {
-            Request::Invoke { dc_id, body, tx } => {
-                let Some(mut dc_option) = self.session.dc_option(dc_id) else {
-                    let _ = tx.send(Err(InvocationError::InvalidDc));
+            Request::Invoke { dc_id, lane, rpcs } => {
+                let Some(mut dc_option) = self
+                    .session
+                    .dc_option(dc_id)
+                    .filter(|dc_option| dc_option.test == self.connection_params.test_mode)
+                else {
+                    fail_rpcs(rpcs, &InvocationError::InvalidDc);
                     return ControlFlow::Continue(());
                 };
 
+                // Connections whose temporary key is about to expire are dropped so that a new one
+                // takes their place. Their sender keeps running until pending requests complete.
+                let now = Instant::now();
+                let events_tx = &self.events_tx;
+                self.connections.retain(|connection| {
+                    let expired = connection.dc_id == dc_id
+                        && connection
+                            .expires_at
+                            .is_some_and(|expires_at| expires_at <= now);
+                    if expired {
+                        let _ = events_tx.send(ConnectionEvent::Disconnected {
+                            dc_id,
+                            reason: DisconnectReason::KeyExpired,
+                        });
+                    }
+                    !expired
+                });
+
+                let lane = self.pick_lane(dc_id, lane);
+
+                // The request will be sent once the connection is re-established.
+                if let Some(reconnection) = self
+                    .reconnections
+                    .iter_mut()
+                    .find(|reconnection| reconnection.dc_id == dc_id && reconnection.lane == lane)
+                {
+                    reconnection.rpcs.extend(rpcs);
+                    return ControlFlow::Continue(());
+                }
+
                 let connection = match self
                     .connections
                     .iter()
-                    .find(|connection| connection.dc_id == dc_id)
+                    .position(|connection| connection.dc_id == dc_id && connection.lane == lane)
                 {
-                    Some(connection) => connection,
+                    Some(i) => &mut self.connections[i],
                     None => {
-                        let sender = match self.connect_sender(&dc_option).await {
+                        self.make_room();
+                        let sender = match self.connect_sender(&mut dc_option).await {
                             Ok(t) => t,
                             Err(e) => {
-                                let _ = tx.send(Err(e));
+                                fail_rpcs(rpcs, &e);
                                 return ControlFlow::Continue(());
                             }
                         };
 
-                        dc_option.auth_key = Some(sender.auth_key());
-                        self.session.set_dc_option(&dc_option);
-
-                        let (rpc_tx, rpc_rx) = mpsc::unbounded_channel();
-                        let abort_handle = self.connection_pool.spawn(run_sender(
-                            sender,
-                            rpc_rx,
-                            self.updates_tx.clone(),
-                        ));
-                        self.connections.push(ConnectionInfo {
-                            dc_id,
-                            rpc_tx,
-                            abort_handle,
-                        });
-                        self.connections.last().unwrap()
+                        self.spawn_sender(dc_id, lane, sender)
                     }
                 };
-                let _ = connection.rpc_tx.send(Rpc { body, tx });
+                rpcs.into_iter().for_each(|rpc| connection.send(rpc));
                 ControlFlow::Continue(())
             }
             Request::Disconnect { dc_id } => {
+                self.reconnections
+                    .retain(|reconnection| reconnection.dc_id != dc_id);
+                let events_tx = &self.events_tx;
                 self.connections.retain(|connection| {
                     if connection.dc_id == dc_id {
                         connection.abort_handle.abort();
+                        let _ = events_tx.send(ConnectionEvent::Disconnected {
+                            dc_id,
+                            reason: DisconnectReason::Requested,
+                        });
                         false
                     } else {
                         true
@@ -252,34 +598,350 @@ impl SenderPoolRunner {
                 });
                 ControlFlow::Continue(())
             }
+            Request::Stats { tx } => {
+                let stats = self
+                    .connections
+                    .iter()
+                    .map(|connection| ConnectionStats {
+                        dc_id: connection.dc_id,
+                        lane: connection.lane,
+                        sender: connection.stats.lock().unwrap().clone(),
+                    })
+                    .collect();
+                let _ = tx.send(stats);
+                ControlFlow::Continue(())
+            }
             Request::Quit => ControlFlow::Break(()),
         }
     }
 
-    async fn connect_sender(
-        &mut self,
-        dc_option: &DcOption,
-    ) -> Result<Sender<transport::Full, mtp::Encrypted>, InvocationError> {
-        let transport = transport::Full::new;
+    /// Notify all subscribers of the event, if any.
+    fn emit(&self, event: ConnectionEvent) {
+        let _ = self.events_tx.send(event);
+    }
 
-        #[cfg(feature = "proxy")]
-        let addr = || {
-            if let Some(proxy) = self.connection_params.proxy_url.clone() {
-                ServerAddr::Proxied {
-                    address: dc_option.ipv4.into(),
-                    proxy,
-                }
+    /// Choose which of the connections to the datacenter should be used for a request.
+    ///
+    /// Explicit lanes wrap around the amount of connections allowed per datacenter. Otherwise,
+    /// idle connections are preferred, followed by a new connection if neither limit has been
+    /// reached yet, and finally the connection with the least requests waiting for a response.
+    fn pick_lane(&self, dc_id: i32, lane: Option<usize>) -> usize {
+        let max_lanes = self.connection_params.connections_per_dc.max(1);
+        if let Some(lane) = lane {
+            return lane % max_lanes;
+        }
+
+        let connections = || {
+            self.connections
+                .iter()
+                .filter(move |connection| connection.dc_id == dc_id)
+        };
+        if let Some(connection) = connections().find(|connection| connection.load() == 0) {
+            return connection.lane;
+        }
+
+        let lane_in_use = |lane: usize| {
+            connections().any(|connection| connection.lane == lane)
+                || self
+                    .reconnections
+                    .iter()
+                    .any(|reconnection| reconnection.dc_id == dc_id && reconnection.lane == lane)
+        };
+        (0..max_lanes)
+            .find(|&lane| !lane_in_use(lane))
+            .filter(|_| !self.at_connection_limit() || connections().next().is_none())
+            .or_else(|| {
+                connections()
+                    .min_by_key(|connection| connection.load())
+                    .map(|connection| connection.lane)
+            })
+            .unwrap_or(0)
+    }
+
+    /// Whether [`ConnectionParams::max_connections`] has been reached, counting those that
+    /// are pending to be re-established.
+    fn at_connection_limit(&self) -> bool {
+        self.connection_params
+            .max_connections
+            .is_some_and(|max| self.connections.len() + self.reconnections.len() >= max)
+    }
+
+    /// Whether the connection is needed to receive updates, and must be kept open.
+    fn receives_updates(&self, connection: &ConnectionInfo) -> bool {
+        connection.dc_id == self.session.home_dc_id() && connection.lane == 0
+    }
+
+    /// Close the connections that have had no requests waiting for a response for `idle_timeout`.
+    fn close_idle_connections(&mut self, idle_timeout: Duration, now: Instant) {
+        let mut i = 0;
+        while i < self.connections.len() {
+            let connection = &mut self.connections[i];
+            if connection.load() != 0 {
+                connection.idle_since = None;
+            } else if connection.idle_since.is_none() {
+                connection.idle_since = Some(now);
+            }
+
+            let connection = &self.connections[i];
+            if !self.receives_updates(connection)
+                && connection
+                    .idle_since
+                    .is_some_and(|idle_since| idle_since + idle_timeout <= now)
+            {
+                self.close_idle_connection(i);
             } else {
-                ServerAddr::Tcp {
-                    address: dc_option.ipv4.into(),
-                }
+                i += 1;
             }
+        }
+    }
+
+    /// Close the connection that has been idle for the longest if the connection limit has
+    /// been reached, so that a new one can be made.
+    fn make_room(&mut self) {
+        if !self.at_connection_limit() {
+            return;
+        }
+
+        let oldest_idle = self
+            .connections
+            .iter()
+            .enumerate()
+            .filter(|(_, connection)| connection.load() == 0 && !self.receives_updates(connection))
+            .min_by_key(|(_, connection)| connection.idle_since.unwrap_or_else(Instant::now))
+            .map(|(i, _)| i);
+
+        match oldest_idle {
+            Some(i) => self.close_idle_connection(i),
+            None => warn!("every connection is busy; exceeding the connection limit"),
+        }
+    }
+
+    /// Stop using the connection, which will close once it has no requests waiting for a response.
+    fn close_idle_connection(&mut self, i: usize) {
+        let connection = self.connections.swap_remove(i);
+        info!(
+            "closing idle connection to dc {} (lane {})",
+            connection.dc_id, connection.lane
+        );
+        self.emit(ConnectionEvent::Disconnected {
+            dc_id: connection.dc_id,
+            reason: DisconnectReason::Idle,
+        });
+    }
+
+    /// Start driving the sender of a new connection to the datacenter.
+    fn spawn_sender(
+        &mut self,
+        dc_id: i32,
+        lane: usize,
+        sender: Sender<Transport, mtp::Encrypted>,
+    ) -> &mut ConnectionInfo {
+        let expires_at = self
+            .connection_params
+            .perfect_forward_secrecy
+            .then(|| Instant::now() + TEMP_AUTH_KEY_EXPIRES_IN - TEMP_AUTH_KEY_MARGIN);
+
+        let (rpc_tx, rpc_rx) = mpsc::unbounded_channel();
+        let load = Arc::new(AtomicUsize::new(0));
+        let stats = Arc::new(Mutex::new(sender.stats()));
+        let abort_handle = self.connection_pool.spawn(run_sender(
+            dc_id,
+            lane,
+            sender,
+            rpc_rx,
+            Arc::clone(&load),
+            Arc::clone(&stats),
+            self.updates_tx.clone(),
+        ));
+        self.connections.push(ConnectionInfo {
+            dc_id,
+            lane,
+            rpc_tx,
+            load,
+            stats,
+            abort_handle,
+            expires_at,
+            idle_since: None,
+        });
+        self.emit(ConnectionEvent::Connected { dc_id });
+        self.connections.last_mut().unwrap()
+    }
+
+    /// Decide what to do with a lost connection and the requests it had not answered.
+    ///
+    /// Connections that were retired on purpose, such as idle or expired ones, are not
+    /// re-established, because they may have been replaced already.
+    fn on_disconnection(&mut self, id: task::Id, disconnection: Disconnection) {
+        let Disconnection {
+            dc_id,
+            lane,
+            error,
+            mut rpcs,
+            mut sender,
+        } = disconnection;
+        if !self
+            .connections
+            .iter()
+            .any(|connection| connection.abort_handle.id() == id)
+        {
+            warn!("retired connection to dc {dc_id} (lane {lane}) lost: {error}");
+            rpcs.splice(0..0, take_rpcs(&mut sender));
+            fail_rpcs(rpcs, &error.into());
+            return;
+        }
+        self.emit(ConnectionEvent::Disconnected {
+            dc_id,
+            reason: DisconnectReason::Error(error.clone()),
+        });
+        let policy = &self.connection_params.reconnection_policy;
+
+        // Only the home datacenter sends updates, so others can wait until they're needed.
+        // Additional connections to the home datacenter are also only needed for requests.
+        if !policy.enabled()
+            || (rpcs.is_empty()
+                && !sender.has_awaited_requests()
+                && (lane != 0 || dc_id != self.session.home_dc_id()))
+        {
+            warn!("connection to dc {dc_id} lost: {error}");
+            rpcs.splice(0..0, take_rpcs(&mut sender));
+            fail_rpcs(rpcs, &error.into());
+            return;
+        }
+
+        // Only losing the underlying connection leaves the session in a state worth resuming.
+        let sender = if matches!(error, ReadError::Io(_)) {
+            Some(sender)
+        } else {
+            rpcs.splice(0..0, take_rpcs(&mut sender));
+            None
         };
-        #[cfg(not(feature = "proxy"))]
-        let addr = || ServerAddr::Tcp {
-            address: dc_option.ipv4.into(),
+
+        if let Some(reconnection) = self
+            .reconnections
+            .iter_mut()
+            .find(|reconnection| reconnection.dc_id == dc_id && reconnection.lane == lane)
+        {
+            if let Some(mut sender) = sender {
+                reconnection.rpcs.extend(take_rpcs(&mut sender));
+            }
+            reconnection.rpcs.extend(rpcs);
+            return;
+        }
+
+        let delay = policy.delay(0);
+        warn!("connection to dc {dc_id} lost: {error}; reconnecting in {delay:?}");
+        self.emit(ConnectionEvent::Reconnecting {
+            dc_id,
+            attempt: 0,
+            delay,
+        });
+        self.reconnections.push(Reconnection {
+            dc_id,
+            lane,
+            error,
+            rpcs,
+            sender,
+            attempts: 0,
+            next_attempt: Instant::now() + delay,
+        });
+    }
+
+    /// Attempt to re-establish the first lost connection whose delay has elapsed.
+    async fn reconnect(&mut self) {
+        let now = Instant::now();
+        let Some(i) = self
+            .reconnections
+            .iter()
+            .position(|reconnection| reconnection.next_attempt <= now)
+        else {
+            return;
+        };
+        let mut reconnection = self.reconnections.swap_remove(i);
+        let dc_id = reconnection.dc_id;
+
+        let Some(mut dc_option) = self.session.dc_option(dc_id) else {
+            reconnection.fail();
+            return;
+        };
+
+        let result = match reconnection.sender.take() {
+            Some(mut sender) => match self.resume_sender(&mut sender, &dc_option).await {
+                Ok(()) => Ok(*sender),
+                Err(err) => {
+                    reconnection.sender = Some(sender);
+                    Err(err)
+                }
+            },
+            None => self.connect_sender(&mut dc_option).await,
         };
 
+        match result {
+            Ok(sender) => {
+                info!(
+                    "reconnected to dc {dc_id}; sending {} queued request(s)",
+                    reconnection.rpcs.len()
+                );
+                let connection = self.spawn_sender(dc_id, reconnection.lane, sender);
+                for rpc in reconnection.rpcs {
+                    connection.send(rpc);
+                }
+                if dc_id == self.session.home_dc_id() {
+                    let _ = self.updates_tx.send(UpdatesLike::Reconnection);
+                }
+            }
+            Err(err) => {
+                let policy = &self.connection_params.reconnection_policy;
+                reconnection.attempts += 1;
+                if policy.exhausted(reconnection.attempts) {
+                    warn!(
+                        "giving up on reconnecting to dc {dc_id} after {} attempt(s): {err}",
+                        reconnection.attempts
+                    );
+                    reconnection.fail();
+                } else {
+                    let delay = policy.delay(reconnection.attempts);
+                    warn!("failed to reconnect to dc {dc_id}: {err}; retrying in {delay:?}");
+                    self.emit(ConnectionEvent::Reconnecting {
+                        dc_id,
+                        attempt: reconnection.attempts,
+                        delay,
+                    });
+                    reconnection.next_attempt = Instant::now() + delay;
+                    self.reconnections.push(reconnection);
+                }
+            }
+        }
+    }
+
+    /// Resume the session of a lost connection through a new one to the same address.
+    async fn resume_sender(
+        &mut self,
+        sender: &mut Sender<Transport, mtp::Encrypted>,
+        dc_option: &DcOption,
+    ) -> Result<(), InvocationError> {
+        self.emit(ConnectionEvent::Connecting {
+            dc_id: dc_option.id,
+        });
+        let (transport, _, _) = self.endpoint(dc_option)?;
+        sender.reconnect(transport()).await?;
+        Ok(())
+    }
+
+    /// Connect to the datacenter and initialize the connection.
+    ///
+    /// The permanent Authorization Key in `dc_option` is updated and persisted if a new one
+    /// had to be generated, along with the datacenter options returned by the server.
+    async fn connect_sender(
+        &mut self,
+        dc_option: &mut DcOption,
+    ) -> Result<Sender<Transport, mtp::Encrypted>, InvocationError> {
+        self.emit(ConnectionEvent::Connecting {
+            dc_id: dc_option.id,
+        });
+        let previous_auth_key = dc_option.auth_key;
+
+        let (transport, addr, http) = self.endpoint(dc_option)?;
+
         let init_connection = tl::functions::InvokeWithLayer {
             layer: tl::LAYER,
             query: tl::functions::InitConnection {
@@ -296,7 +958,10 @@ impl SenderPoolRunner {
             },
         };
 
-        let mut sender = if let Some(auth_key) = dc_option.auth_key {
+        let pfs = self.connection_params.perfect_forward_secrecy;
+        let mut sender = if pfs {
+            connect_temp(&transport, &addr, dc_option).await?
+        } else if let Some(auth_key) = dc_option.auth_key {
             connect_with_auth(transport(), addr(), auth_key).await?
         } else {
             connect(transport(), addr()).await?
@@ -305,104 +970,787 @@ impl SenderPoolRunner {
         let enums::Config::Config(remote_config) = match sender.invoke(&init_connection).await {
             Ok(config) => config,
             Err(InvocationError::Transport(transport::Error::BadStatus { status: 404 })) => {
-                sender = connect(transport(), addr()).await?;
+                sender = if pfs {
+                    connect_temp(&transport, &addr, dc_option).await?
+                } else {
+                    connect(transport(), addr()).await?
+                };
                 sender.invoke(&init_connection).await?
             }
-            Err(e) => return Err(dbg!(e).into()),
+            Err(e) => {
+                warn!("failed to initialize the connection: {e}");
+                return Err(e);
+            }
         };
+        if http {
+            sender.enable_http_wait();
+        }
 
+        if !pfs {
+            dc_option.auth_key = Some(sender.auth_key());
+        }
+
+        if dc_option.auth_key != previous_auth_key {
+            self.emit(ConnectionEvent::AuthKeyGenerated {
+                dc_id: dc_option.id,
+            });
+        }
+        self.session.set_dc_option(dc_option);
         self.update_config(remote_config);
+        self.session.flush().await?;
 
         Ok(sender)
     }
 
+    /// Determine how new connections to the datacenter are made.
+    ///
+    /// Returns how to instantiate the transport, which address to connect to, and whether
+    /// the connection uses HTTP, which needs to be told to wait for messages from the server.
+    fn endpoint(
+        &self,
+        dc_option: &DcOption,
+    ) -> Result<
+        (
+            impl Fn() -> Transport + use<>,
+            impl Fn() -> ServerAddr + use<>,
+            bool,
+        ),
+        InvocationError,
+    > {
+        #[cfg(feature = "proxy")]
+        let dc_id = environment_dc_id(dc_option);
+        let (mut ipv4, mut ipv6) = (dc_option.ipv4, dc_option.ipv6);
+        let ip_preference = self.connection_params.ip_preference;
+        let mode = self.connection_params.transport;
+
+        #[cfg(feature = "proxy")]
+        let mtproxy = match self.connection_params.proxy_url.as_deref() {
+            Some(url) => MtProxy::from_proxy_url(url)?,
+            None => None,
+        };
+
+        #[cfg(feature = "websocket")]
+        let websocket_url = match self.connection_params.transport {
+            TransportMode::WebSocket => {
+                Some(websocket_url(dc_option).ok_or(InvocationError::InvalidDc)?)
+            }
+            _ => None,
+        };
+        #[cfg(all(feature = "proxy", feature = "websocket"))]
+        let mtproxy = mtproxy.filter(|_| websocket_url.is_none());
+
+        let http = self.connection_params.transport == TransportMode::Http;
+        #[cfg(feature = "proxy")]
+        let http = http && mtproxy.is_none();
+        if http {
+            ipv4.set_port(HTTP_PORT);
+            ipv6.set_port(HTTP_PORT);
+        }
+        let host = match ip_preference {
+            IpPreference::Ipv6Only => ipv6.to_string(),
+            IpPreference::Ipv4Only | IpPreference::PreferIpv6 => ipv4.to_string(),
+        };
+
+        #[cfg(feature = "proxy")]
+        let transport = move || match &mtproxy {
+            Some(mtproxy) => mtproxy.secret.transport(dc_id as i16),
+            None => mode.instantiate(&host),
+        };
+        #[cfg(not(feature = "proxy"))]
+        let transport = move || mode.instantiate(&host);
+
+        let direct_addr = move || match ip_preference {
+            IpPreference::Ipv4Only => ServerAddr::Tcp {
+                address: ipv4.into(),
+            },
+            IpPreference::Ipv6Only => ServerAddr::Tcp {
+                address: ipv6.into(),
+            },
+            IpPreference::PreferIpv6 if ipv6.ip().to_ipv4().is_none() => {
+                ServerAddr::DualStack { ipv6, ipv4 }
+            }
+            // The session does not know a real IPv6 address, so it would only embed the IPv4 one.
+            IpPreference::PreferIpv6 => ServerAddr::Tcp {
+                address: ipv4.into(),
+            },
+        };
+
+        #[cfg(feature = "proxy")]
+        let proxy_url = self.connection_params.proxy_url.clone();
+        #[cfg(feature = "proxy")]
+        let addr = move || {
+            if let Some(proxy) = proxy_url.clone() {
+                ServerAddr::Proxied {
+                    address: match ip_preference {
+                        IpPreference::Ipv6Only => ipv6.into(),
+                        IpPreference::Ipv4Only | IpPreference::PreferIpv6 => ipv4.into(),
+                    },
+                    proxy,
+                }
+            } else {
+                direct_addr()
+            }
+        };
+        #[cfg(not(feature = "proxy"))]
+        let addr = direct_addr;
+        #[cfg(feature = "websocket")]
+        let addr = move || match &websocket_url {
+            Some(url) => ServerAddr::WebSocket { url: url.clone() },
+            None => addr(),
+        };
+
+        Ok((transport, addr, http))
+    }
+
+    /// Persist the datacenter options from the configuration returned by the server.
+    ///
+    /// There may be several options for the same datacenter. Regular options are preferred over
+    /// media-only ones, so those are applied last. See [`dc_option_preference`] for the rest.
     fn update_config(&mut self, config: tl::types::Config) {
-        config
+        #[cfg(feature = "proxy")]
+        let through_proxy = self.connection_params.proxy_url.is_some();
+        #[cfg(not(feature = "proxy"))]
+        let through_proxy = false;
+
+        let mut options = config
             .dc_options
-            .iter()
+            .into_iter()
             .map(|tl::enums::DcOption::Option(option)| option)
-            .filter(|option| !option.media_only && !option.tcpo_only && option.r#static)
-            .for_each(|option| {
-                let mut dc_option = self
-                    .session
-                    .dc_option(option.id)
-                    .unwrap_or_else(|| DcOption {
-                        id: option.id,
-                        ipv4: SocketAddrV4::new(Ipv4Addr::from_bits(0), 0),
-                        ipv6: SocketAddrV6::new(Ipv6Addr::from_bits(0), 0, 0, 0),
-                        auth_key: None,
-                    });
-                if option.ipv6 {
-                    dc_option.ipv6 = SocketAddrV6::new(
-                        option
-                            .ip_address
-                            .parse()
-                            .expect("Telegram to return a valid IPv6 address"),
-                        option.port as _,
-                        0,
-                        0,
-                    );
-                } else {
-                    dc_option.ipv4 = SocketAddrV4::new(
-                        option
-                            .ip_address
-                            .parse()
-                            .expect("Telegram to return a valid IPv4 address"),
-                        option.port as _,
-                    );
-                    if dc_option.ipv6.ip().to_bits() == 0 {
-                        dc_option.ipv6 = SocketAddrV6::new(
-                            dc_option.ipv4.ip().to_ipv6_mapped(),
-                            dc_option.ipv4.port(),
-                            0,
-                            0,
-                        )
-                    }
+            .filter(|option| !option.tcpo_only)
+            .collect::<Vec<_>>();
+        options.sort_by_key(|option| dc_option_preference(option, through_proxy));
+
+        let mut dc_options = Vec::<DcOption>::new();
+        for option in options {
+            let ip_address = if option.ipv6 {
+                option.ip_address.parse().map(IpAddr::V6)
+            } else {
+                option.ip_address.parse().map(IpAddr::V4)
+            };
+            let Ok(ip_address) = ip_address else {
+                warn!(
+                    "ignoring option for dc {} with invalid address: {}",
+                    option.id, option.ip_address
+                );
+                continue;
+            };
+
+            let dc_option = match dc_options.iter().position(|dc| dc.id == option.id) {
+                Some(i) => &mut dc_options[i],
+                None => {
+                    dc_options.push(self.session.dc_option(option.id).unwrap_or_else(|| {
+                        DcOption {
+                            id: option.id,
+                            ipv4: SocketAddrV4::new(Ipv4Addr::from_bits(0), 0),
+                            ipv6: SocketAddrV6::new(Ipv6Addr::from_bits(0), 0, 0, 0),
+                            media_only: option.media_only,
+                            cdn: option.cdn,
+                            test: config.test_mode,
+                            r#static: option.r#static,
+                            auth_key: None,
+                        }
+                    }));
+                    dc_options.last_mut().unwrap()
                 }
-            });
+            };
+
+            // Addresses meant for a different purpose must not be mixed.
+            if (dc_option.media_only, dc_option.cdn) != (option.media_only, option.cdn) {
+                dc_option.ipv4 = SocketAddrV4::new(Ipv4Addr::from_bits(0), 0);
+                dc_option.ipv6 = SocketAddrV6::new(Ipv6Addr::from_bits(0), 0, 0, 0);
+            }
+            dc_option.media_only = option.media_only;
+            dc_option.cdn = option.cdn;
+            dc_option.test = config.test_mode;
+            dc_option.r#static = option.r#static;
+
+            match ip_address {
+                IpAddr::V6(ip) => dc_option.ipv6 = SocketAddrV6::new(ip, option.port as _, 0, 0),
+                IpAddr::V4(ip) => dc_option.ipv4 = SocketAddrV4::new(ip, option.port as _),
+            }
+        }
+
+        for mut dc_option in dc_options {
+            if dc_option.ipv6.ip().to_bits() == 0 {
+                dc_option.ipv6 = SocketAddrV6::new(
+                    dc_option.ipv4.ip().to_ipv6_mapped(),
+                    dc_option.ipv4.port(),
+                    0,
+                    0,
+                )
+            }
+            self.session.set_dc_option(&dc_option);
+        }
+    }
+}
+
+/// Sorting key for the datacenter options, so that the preferred ones come last.
+///
+/// Static addresses are the ones suitable when connecting through a proxy, so they're
+/// only preferred in that case, and the non-static ones are used otherwise.
+fn dc_option_preference(option: &tl::types::DcOption, through_proxy: bool) -> (bool, bool) {
+    (!option.media_only, option.r#static == through_proxy)
+}
+
+impl ConnectionInfo {
+    /// How many requests sent through this connection have not been answered yet.
+    fn load(&self) -> usize {
+        self.load.load(Ordering::Relaxed)
+    }
+
+    /// Send the request through this connection, accounting for its load.
+    fn send(&mut self, rpc: Rpc) {
+        if self.rpc_tx.send(rpc).is_ok() {
+            self.load.fetch_add(1, Ordering::Relaxed);
+            self.idle_since = None;
+        }
+    }
+}
+
+/// Identifier of the datacenter as expected by the server in some places,
+/// which is offset in the test environment.
+fn environment_dc_id(dc_option: &DcOption) -> i32 {
+    if dc_option.test {
+        10000 + dc_option.id
+    } else {
+        dc_option.id
+    }
+}
+
+/// URL of the WebSocket endpoint used by Telegram Web to reach the datacenter, if it has one.
+#[cfg(feature = "websocket")]
+fn websocket_url(dc_option: &DcOption) -> Option<String> {
+    const SUBDOMAINS: [&str; 5] = ["pluto", "venus", "aurora", "vesta", "flora"];
+
+    let subdomain = SUBDOMAINS.get(usize::try_from(dc_option.id).ok()?.checked_sub(1)?)?;
+    let media = if dc_option.media_only { "-1" } else { "" };
+    let path = if dc_option.test {
+        "apiws_test"
+    } else {
+        "apiws"
+    };
+    Some(format!("wss://{subdomain}{media}.web.telegram.org/{path}"))
+}
+
+/// Connect using a new temporary Authorization Key bound to the permanent key of `dc_option`.
+///
+/// If there is no permanent key yet, or the server no longer recognises it, a new one is generated.
+async fn connect_temp(
+    transport: impl Fn() -> Transport,
+    addr: impl Fn() -> ServerAddr,
+    dc_option: &mut DcOption,
+) -> Result<Sender<Transport, mtp::Encrypted>, InvocationError> {
+    let mut fresh_perm_auth_key = dc_option.auth_key.is_none();
+    let mut perm_auth_key = match dc_option.auth_key {
+        Some(auth_key) => auth_key,
+        None => connect(transport(), addr()).await?.auth_key(),
+    };
+
+    loop {
+        let expires_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("system time is before epoch")
+            + TEMP_AUTH_KEY_EXPIRES_IN;
+
+        let mut sender = generate_temp_auth_key(
+            Sender::connect(transport(), mtp::Plain::new(), addr()).await?,
+            environment_dc_id(dc_option),
+            TEMP_AUTH_KEY_EXPIRES_IN.as_secs() as i32,
+        )
+        .await?;
+
+        match sender
+            .bind_temp_auth_key(&perm_auth_key, expires_at.as_secs() as i32)
+            .await
+        {
+            Ok(()) => {
+                dc_option.auth_key = Some(perm_auth_key);
+                break Ok(sender);
+            }
+            Err(err) if !fresh_perm_auth_key && is_unknown_perm_auth_key(&err) => {
+                warn!("failed to bind temporary auth key ({err}); generating new permanent key");
+                perm_auth_key = connect(transport(), addr()).await?.auth_key();
+                fresh_perm_auth_key = true;
+            }
+            Err(e) => break Err(e),
+        }
     }
 }
 
+/// Whether binding a temporary key failed because the server does not know the permanent key.
+///
+/// Any other error must not cause the permanent key to be replaced, as that would log out.
+fn is_unknown_perm_auth_key(error: &InvocationError) -> bool {
+    error.is("ENCRYPTED_MESSAGE_INVALID")
+}
+
+/// Remove the requests still waiting for a response from the sender, so that they can be sent elsewhere.
+fn take_rpcs(sender: &mut Sender<Transport, mtp::Encrypted>) -> Vec<Rpc> {
+    sender
+        .take_requests()
+        .into_iter()
+        .map(|(body, tx)| Rpc {
+            body,
+            tx,
+            after_previous: false,
+        })
+        .collect()
+}
+
+/// Fail all the requests with the same error, such as the one that caused their connection to be lost.
+fn fail_rpcs(rpcs: Vec<Rpc>, error: &InvocationError) {
+    rpcs.into_iter().for_each(|rpc| {
+        let _ = rpc.tx.send(Err(error.clone()));
+    });
+}
+
 async fn run_sender(
-    mut sender: Sender<Transport, grammers_mtproto::mtp::Encrypted>,
+    dc_id: i32,
+    lane: usize,
+    mut sender: Sender<Transport, mtp::Encrypted>,
     mut rpc_rx: mpsc::UnboundedReceiver<Rpc>,
+    load: Arc<AtomicUsize>,
+    stats: Arc<Mutex<SenderStats>>,
     updates: mpsc::UnboundedSender<UpdatesLike>,
+) -> Result<(), Disconnection> {
+    let result = drive_sender(&mut sender, &mut rpc_rx, &load, &stats, &updates).await;
+
+    result.map_err(|error| {
+        let mut rpcs = Vec::new();
+        while let Ok(rpc) = rpc_rx.try_recv() {
+            rpcs.push(rpc);
+        }
+        Disconnection {
+            dc_id,
+            lane,
+            error,
+            rpcs,
+            sender: Box::new(sender),
+        }
+    })
+}
+
+async fn drive_sender(
+    sender: &mut Sender<Transport, mtp::Encrypted>,
+    rpc_rx: &mut mpsc::UnboundedReceiver<Rpc>,
+    load: &AtomicUsize,
+    stats: &Mutex<SenderStats>,
+    updates: &mpsc::UnboundedSender<UpdatesLike>,
 ) -> Result<(), ReadError> {
+    // The sender may also add requests of its own, such as pings, so the load can go either way.
+    let mut accounted = 0;
+    let mut last_request = None;
     loop {
+        let pending = sender.pending_requests();
+        if pending > accounted {
+            load.fetch_add(pending - accounted, Ordering::Relaxed);
+        } else {
+            load.fetch_sub(accounted - pending, Ordering::Relaxed);
+        }
+        accounted = pending;
+        *stats.lock().unwrap() = sender.stats();
+
         tokio::select! {
-            step = sender.step() => match step {
+            step = sender.try_step() => match step {
                 Ok(all_new_updates) => all_new_updates.into_iter().for_each(|new_updates| {
                     let _ = updates.send(new_updates);
                 }),
                 Err(err) => break Err(err),
             },
             rpc = rpc_rx.recv() => match rpc {
-                Some(rpc) => sender.enqueue_body(rpc.body, rpc.tx),
+                Some(rpc) => {
+                    let after = last_request.filter(|_| rpc.after_previous);
+                    last_request = Some(sender.enqueue_body_after(rpc.body, rpc.tx, after));
+                    accounted += 1;
+                }
                 None => break Ok(()),
             },
         }
-    }
+    }?;
+
+    // The connection is no longer in use, but requests already sent deserve an answer.
+    let drain = async {
+        while sender.has_pending_requests() {
+            sender
+                .try_step()
+                .await?
+                .into_iter()
+                .for_each(|new_updates| {
+                    let _ = updates.send(new_updates);
+                });
+        }
+        Ok(())
+    };
+    timeout(DRAIN_TIMEOUT, drain).await.unwrap_or_else(|_| {
+        Err(ReadError::Io(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "retired connection did not answer its pending requests in time",
+        )))
+    })
 }
 
 impl fmt::Debug for Request {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Invoke { dc_id, body, tx } => f
+            Self::Invoke { dc_id, lane, rpcs } => f
                 .debug_struct("Invoke")
                 .field("dc_id", dc_id)
-                .field(
-                    "request",
-                    &body[..4]
-                        .try_into()
-                        .map(|constructor_id| tl::name_for_id(u32::from_le_bytes(constructor_id)))
-                        .unwrap_or("?"),
-                )
-                .field("tx", tx)
+                .field("lane", lane)
+                .field("rpcs", rpcs)
                 .finish(),
             Self::Disconnect { dc_id } => {
                 f.debug_struct("Disconnect").field("dc_id", dc_id).finish()
             }
+            Self::Stats { tx } => f.debug_struct("Stats").field("tx", tx).finish(),
             Self::Quit => write!(f, "Quit"),
         }
     }
 }
+
+impl fmt::Debug for Rpc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rpc")
+            .field(
+                "request",
+                &self.body[..4]
+                    .try_into()
+                    .map(|constructor_id| tl::name_for_id(u32::from_le_bytes(constructor_id)))
+                    .unwrap_or("?"),
+            )
+            .field("tx", &self.tx)
+            .field("after_previous", &self.after_previous)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RpcError;
+    use grammers_session::storages::MemorySession;
+    use grammers_tl_types::Serializable;
+
+    fn new_runner(connections_per_dc: usize) -> SenderPoolRunner {
+        SenderPool::with_configuration(
+            Arc::new(MemorySession::default()),
+            0,
+            ConnectionParams {
+                connections_per_dc,
+                ..Default::default()
+            },
+        )
+        .runner
+    }
+
+    fn add_connection(runner: &mut SenderPoolRunner, dc_id: i32, lane: usize, load: usize) {
+        let (rpc_tx, _) = mpsc::unbounded_channel();
+        runner.connections.push(ConnectionInfo {
+            dc_id,
+            lane,
+            rpc_tx,
+            load: Arc::new(AtomicUsize::new(load)),
+            stats: Arc::default(),
+            abort_handle: runner.connection_pool.spawn(async { Ok(()) }),
+            expires_at: None,
+            idle_since: None,
+        });
+    }
+
+    async fn connect_to(listener: &tokio::net::TcpListener) -> Sender<Transport, mtp::Encrypted> {
+        Sender::connect(
+            Box::new(transport::Full::new()) as Transport,
+            mtp::Encrypted::build().finish([0; 256]),
+            ServerAddr::Tcp {
+                address: listener.local_addr().unwrap(),
+            },
+        )
+        .await
+        .unwrap()
+    }
+
+    fn send_ping(
+        connection: &mut ConnectionInfo,
+    ) -> oneshot::Receiver<Result<InvokeResponse, InvocationError>> {
+        let (tx, rx) = oneshot::channel();
+        connection.send(Rpc {
+            body: tl::functions::Ping { ping_id: 0 }.to_bytes(),
+            tx,
+            after_previous: false,
+        });
+        rx
+    }
+
+    async fn next_disconnection(runner: &mut SenderPoolRunner) {
+        let (id, result) = runner
+            .connection_pool
+            .join_next_with_id()
+            .await
+            .unwrap()
+            .unwrap();
+        runner.on_disconnection(id, result.unwrap_err());
+        runner
+            .connections
+            .retain(|connection| !connection.abort_handle.is_finished());
+    }
+
+    #[tokio::test]
+    async fn pick_lane_by_load() {
+        let mut runner = new_runner(3);
+        assert_eq!(runner.pick_lane(2, None), 0);
+
+        add_connection(&mut runner, 2, 0, 0);
+        assert_eq!(runner.pick_lane(2, None), 0);
+
+        runner.connections[0].load.store(5, Ordering::Relaxed);
+        assert_eq!(runner.pick_lane(2, None), 1);
+
+        add_connection(&mut runner, 2, 1, 2);
+        add_connection(&mut runner, 2, 2, 3);
+        assert_eq!(runner.pick_lane(2, None), 1);
+
+        // Connections to other datacenters do not count.
+        add_connection(&mut runner, 4, 0, 0);
+        assert_eq!(runner.pick_lane(2, None), 1);
+    }
+
+    #[tokio::test]
+    async fn invoke_past_deadline() {
+        // The runner is never started, so no response can arrive.
+        let pool = SenderPool::new(Arc::new(MemorySession::default()), 0);
+        let deadline = Instant::now() + Duration::from_millis(10);
+        let result = pool
+            .handle
+            .invoke_in_dc_with_deadline(2, tl::functions::Ping { ping_id: 0 }.to_bytes(), deadline)
+            .await;
+        assert!(matches!(result, Err(InvocationError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn pick_explicit_lane() {
+        let runner = new_runner(3);
+        assert_eq!(runner.pick_lane(2, Some(1)), 1);
+        assert_eq!(runner.pick_lane(2, Some(4)), 1);
+
+        // Zero connections per datacenter is treated as one.
+        let runner = new_runner(0);
+        assert_eq!(runner.pick_lane(2, Some(4)), 0);
+    }
+
+    #[tokio::test]
+    async fn close_idle_connections() {
+        let mut runner = new_runner(2);
+        let home_dc_id = runner.session.home_dc_id();
+        add_connection(&mut runner, home_dc_id, 0, 0);
+        add_connection(&mut runner, home_dc_id, 1, 0);
+        add_connection(&mut runner, 4, 0, 0);
+        add_connection(&mut runner, 5, 0, 1);
+
+        let timeout = Duration::from_secs(60);
+        let now = Instant::now();
+        runner.close_idle_connections(timeout, now);
+        assert_eq!(runner.connections.len(), 4);
+
+        // Busy connections are not idle, and the one receiving updates is always kept.
+        runner.close_idle_connections(timeout, now + timeout);
+        let mut remaining = runner
+            .connections
+            .iter()
+            .map(|connection| (connection.dc_id, connection.lane))
+            .collect::<Vec<_>>();
+        remaining.sort();
+        assert_eq!(remaining, [(home_dc_id, 0), (5, 0)]);
+
+        // Idle time only counts from the moment the connection was last seen idle.
+        runner.connections[1].load.store(0, Ordering::Relaxed);
+        runner.close_idle_connections(timeout, now + timeout * 2);
+        assert_eq!(runner.connections.len(), 2);
+        runner.close_idle_connections(timeout, now + timeout * 3);
+        assert_eq!(runner.connections.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn respect_connection_limit() {
+        let mut runner = new_runner(2);
+        runner.connection_params.max_connections = Some(2);
+        add_connection(&mut runner, 4, 0, 1);
+        add_connection(&mut runner, 5, 0, 0);
+
+        // No new lanes are opened at the limit if the datacenter already has a connection.
+        assert_eq!(runner.pick_lane(4, None), 0);
+        runner.connection_params.max_connections = Some(3);
+        assert_eq!(runner.pick_lane(4, None), 1);
+
+        // The idle connection makes room for a new one.
+        runner.connection_params.max_connections = Some(2);
+        runner.make_room();
+        assert_eq!(runner.connections.len(), 1);
+        assert_eq!(runner.connections[0].dc_id, 4);
+
+        // Busy connections are never closed.
+        runner.connection_params.max_connections = Some(1);
+        runner.make_room();
+        assert_eq!(runner.connections.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn report_connection_stats() {
+        let mut runner = new_runner(2);
+        add_connection(&mut runner, 2, 0, 0);
+        add_connection(&mut runner, 4, 1, 0);
+        runner.connections[1].stats.lock().unwrap().bytes_read = 128;
+
+        let (tx, rx) = oneshot::channel();
+        let _ = runner.process_request(Request::Stats { tx }).await;
+        let stats = rx.await.unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!((stats[0].dc_id, stats[0].lane), (2, 0));
+        assert_eq!(stats[0].sender.bytes_read, 0);
+        assert_eq!((stats[1].dc_id, stats[1].lane), (4, 1));
+        assert_eq!(stats[1].sender.bytes_read, 128);
+    }
+
+    #[test]
+    fn regenerate_perm_auth_key_only_if_unknown() {
+        let rpc_error = |code, error_message: &str| {
+            InvocationError::Rpc(RpcError::from(tl::types::RpcError {
+                error_code: code,
+                error_message: error_message.into(),
+            }))
+        };
+        assert!(is_unknown_perm_auth_key(&rpc_error(
+            400,
+            "ENCRYPTED_MESSAGE_INVALID"
+        )));
+        assert!(!is_unknown_perm_auth_key(&rpc_error(
+            400,
+            "TEMP_AUTH_KEY_ALREADY_BOUND"
+        )));
+        assert!(!is_unknown_perm_auth_key(&rpc_error(420, "FLOOD_WAIT_31")));
+        assert!(!is_unknown_perm_auth_key(&rpc_error(500, "INTERNAL")));
+        assert!(!is_unknown_perm_auth_key(&InvocationError::Dropped));
+    }
+
+    #[test]
+    fn prefer_static_dc_options_only_through_proxy() {
+        let option = |media_only, r#static, ip_address: &str| tl::types::DcOption {
+            ipv6: false,
+            media_only,
+            tcpo_only: false,
+            cdn: false,
+            r#static,
+            this_port_only: false,
+            id: 2,
+            ip_address: ip_address.into(),
+            port: 443,
+            secret: None,
+        };
+        let preferred = |through_proxy| {
+            let mut options = [
+                option(false, true, "static"),
+                option(false, false, "dynamic"),
+                option(true, false, "media"),
+            ];
+            options.sort_by_key(|option| dc_option_preference(option, through_proxy));
+            options.last().unwrap().ip_address.clone()
+        };
+        assert_eq!(preferred(false), "dynamic");
+        assert_eq!(preferred(true), "static");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn account_load_of_pings() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut sender = Sender::connect(
+            Box::new(transport::Full::new()) as Transport,
+            mtp::Encrypted::build().finish([0; 256]),
+            ServerAddr::Tcp { address },
+        )
+        .await
+        .unwrap();
+        let _server = listener.accept().await.unwrap();
+
+        let load = Arc::new(AtomicUsize::new(0));
+        let driver = tokio::spawn({
+            let load = Arc::clone(&load);
+            async move {
+                let (_rpc_tx, mut rpc_rx) = mpsc::unbounded_channel();
+                let (updates, _) = mpsc::unbounded_channel();
+                drive_sender(&mut sender, &mut rpc_rx, &load, &Mutex::default(), &updates).await
+            }
+        });
+
+        // Nothing but the keepalive ping is sent, which the server never answers.
+        tokio::time::sleep(Duration::from_secs(90)).await;
+        assert!(!driver.is_finished());
+        assert_eq!(load.load(Ordering::Relaxed), 1);
+        driver.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn acknowledged_request_is_not_resent() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut runner = new_runner(1);
+        let home_dc_id = runner.session.home_dc_id();
+        let mut sender = connect_to(&listener).await;
+        let server = listener.accept().await.unwrap();
+
+        // The first request reaches the server, which acknowledges it.
+        let (tx, _acknowledged_rx) = oneshot::channel();
+        sender.enqueue_body(tl::functions::Ping { ping_id: 1 }.to_bytes(), tx);
+        let _ = tokio::time::timeout(Duration::from_millis(1), sender.try_step()).await;
+        assert_eq!(sender.stats().in_flight_requests, 1);
+        sender.acknowledge_sent_requests();
+        let _rx = send_ping(runner.spawn_sender(home_dc_id, 0, sender));
+        tokio::task::yield_now().await;
+
+        // Only the connection was lost, so the session is resumed through a new one, and only
+        // the request that the server did not acknowledge is sent again.
+        drop(server);
+        next_disconnection(&mut runner).await;
+        sleep_until(runner.reconnections[0].next_attempt).await;
+        runner.reconnect().await;
+        assert!(runner.reconnections.is_empty());
+        let stats = runner.connections[0].stats.lock().unwrap().clone();
+        assert_eq!((stats.in_flight_requests, stats.queued_requests), (1, 1));
+        let _server = listener.accept().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reaped_connection_is_not_reconnected() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut runner = new_runner(2);
+        let home_dc_id = runner.session.home_dc_id();
+        let sender = connect_to(&listener).await;
+        let server = listener.accept().await.unwrap();
+        let rx = send_ping(runner.spawn_sender(home_dc_id, 1, sender));
+        tokio::task::yield_now().await;
+
+        // The lane was reaped, so the error while waiting for the answer must not bring it back.
+        runner.close_idle_connection(0);
+        drop(server);
+        next_disconnection(&mut runner).await;
+        assert!(runner.connections.is_empty());
+        assert!(runner.reconnections.is_empty());
+        assert!(matches!(rx.await, Ok(Err(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retired_connection_drains_with_deadline() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut runner = new_runner(1);
+        let sender = connect_to(&listener).await;
+        let _server = listener.accept().await.unwrap();
+        let rx = send_ping(runner.spawn_sender(2, 0, sender));
+        tokio::task::yield_now().await;
+
+        // A replacement may take the lane, so the retired connection is never re-established,
+        // even if the server never answers what it had already sent.
+        runner.close_idle_connection(0);
+        let start = Instant::now();
+        next_disconnection(&mut runner).await;
+        assert!(start.elapsed() >= DRAIN_TIMEOUT);
+        assert!(runner.reconnections.is_empty());
+        assert!(matches!(rx.await, Ok(Err(InvocationError::Io(_)))));
+    }
+}
@@ -6,18 +6,27 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::net::{Ipv4Addr, SocketAddrV4, SocketAddrV6};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
 
 use crate::types::DcOption;
 
 pub(crate) const DEFAULT_DC: i32 = 2;
 
+/// Identifier of the datacenter option whose "authorization key" holds the parameters of the
+/// [`EncryptedSession`](crate::storages::EncryptedSession) wrapping a storage instead.
+/// No real datacenter uses it, so it is left out when listing the datacenter options.
+pub(crate) const ENCRYPTION_PARAMS_DC_ID: i32 = i32::MAX;
+
 const fn ipv4(a: u8, b: u8, c: u8, d: u8) -> SocketAddrV4 {
     SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), 443)
 }
 
-const fn ipv6(a: u8, b: u8, c: u8, d: u8) -> SocketAddrV6 {
-    SocketAddrV6::new(ipv4(a, b, c, d).ip().to_ipv6_compatible(), 443, 0, 0)
+const fn ipv6(a: u16, b: u16, c: u16, d: u16) -> SocketAddrV6 {
+    SocketAddrV6::new(Ipv6Addr::new(a, b, c, d, 0, 0, 0, 0x000a), 443, 0, 0)
+}
+
+const fn test_ipv6(a: u16, b: u16, c: u16, d: u16) -> SocketAddrV6 {
+    SocketAddrV6::new(Ipv6Addr::new(a, b, c, d, 0, 0, 0, 0x000e), 443, 0, 0)
 }
 
 /// Hardcoded known `static` options from `functions::help::GetConfig`.
@@ -25,31 +34,85 @@ pub(crate) const KNOWN_DC_OPTIONS: [DcOption; 5] = [
     DcOption {
         id: 1,
         ipv4: ipv4(149, 154, 175, 53),
-        ipv6: ipv6(149, 154, 175, 53),
+        ipv6: ipv6(0x2001, 0x0b28, 0xf23d, 0xf001),
+        media_only: false,
+        cdn: false,
+        test: false,
+        r#static: true,
         auth_key: None,
     },
     DcOption {
         id: 2,
         ipv4: ipv4(149, 154, 167, 41),
-        ipv6: ipv6(149, 154, 167, 41),
+        ipv6: ipv6(0x2001, 0x067c, 0x04e8, 0xf002),
+        media_only: false,
+        cdn: false,
+        test: false,
+        r#static: true,
         auth_key: None,
     },
     DcOption {
         id: 3,
         ipv4: ipv4(149, 154, 175, 100),
-        ipv6: ipv6(149, 154, 175, 100),
+        ipv6: ipv6(0x2001, 0x0b28, 0xf23d, 0xf003),
+        media_only: false,
+        cdn: false,
+        test: false,
+        r#static: true,
         auth_key: None,
     },
     DcOption {
         id: 4,
         ipv4: ipv4(149, 154, 167, 92),
-        ipv6: ipv6(149, 154, 167, 92),
+        ipv6: ipv6(0x2001, 0x067c, 0x04e8, 0xf004),
+        media_only: false,
+        cdn: false,
+        test: false,
+        r#static: true,
         auth_key: None,
     },
     DcOption {
         id: 5,
         ipv4: ipv4(91, 108, 56, 104),
-        ipv6: ipv6(91, 108, 56, 104),
+        ipv6: ipv6(0x2001, 0x0b28, 0xf23f, 0xf005),
+        media_only: false,
+        cdn: false,
+        test: false,
+        r#static: true,
+        auth_key: None,
+    },
+];
+
+/// Hardcoded known options of the datacenters in the test environment.
+pub(crate) const KNOWN_TEST_DC_OPTIONS: [DcOption; 3] = [
+    DcOption {
+        id: 1,
+        ipv4: ipv4(149, 154, 175, 10),
+        ipv6: test_ipv6(0x2001, 0x0b28, 0xf23d, 0xf001),
+        media_only: false,
+        cdn: false,
+        test: true,
+        r#static: true,
+        auth_key: None,
+    },
+    DcOption {
+        id: 2,
+        ipv4: ipv4(149, 154, 167, 40),
+        ipv6: test_ipv6(0x2001, 0x067c, 0x04e8, 0xf002),
+        media_only: false,
+        cdn: false,
+        test: true,
+        r#static: true,
+        auth_key: None,
+    },
+    DcOption {
+        id: 3,
+        ipv4: ipv4(149, 154, 175, 117),
+        ipv6: test_ipv6(0x2001, 0x0b28, 0xf23d, 0xf003),
+        media_only: false,
+        cdn: false,
+        test: true,
+        r#static: true,
         auth_key: None,
     },
 ];
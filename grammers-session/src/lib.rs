@@ -19,11 +19,15 @@
 //!
 //! To use with other libraries, you will want to instantiate one of the
 //! [`storages`], which are what implement the [`Session`] trait.
+//! Storages implementing [`AsyncSession`] can be used through the
+//! [`storages::AsyncSessionAdaptor`].
 //!
 //! To convert between storages, you can use the [`SessionData`] as an
 //! intermediate step, and use its `From` implementations in combination
-//! with [`SessionData::import_to`]. Note that the `From` implementation
-//! will not copy all of the data, only that which is necessary.
+//! with [`SessionData::import_to`].
+//!
+//! [`SessionData`] can also be converted to and from the string sessions
+//! used by Telethon and Pyrogram, or a compact string native to this library.
 
 #![deny(unsafe_code)]
 
@@ -33,9 +37,13 @@ mod peer;
 mod session;
 mod session_data;
 pub mod storages;
+mod string_session;
 pub mod types;
 pub mod updates;
 
-pub(crate) use dc_options::{DEFAULT_DC, KNOWN_DC_OPTIONS};
-pub use session::Session;
+pub(crate) use dc_options::{
+    DEFAULT_DC, ENCRYPTION_PARAMS_DC_ID, KNOWN_DC_OPTIONS, KNOWN_TEST_DC_OPTIONS,
+};
+pub use session::{AsyncSession, Session, SessionError, SessionFuture};
 pub use session_data::SessionData;
+pub use string_session::StringSessionError;
@@ -347,6 +347,8 @@ pub(super) fn adapt(updates: UpdatesLike) -> Result<tl::types::UpdatesCombined,
             date: 0,
         })),
         UpdatesLike::InvitedUsers(invited) => adapt_updates(invited.updates),
+        // Missing updates while disconnected is no different from a gap.
+        UpdatesLike::Reconnection => Err(Gap),
     }
 }
 
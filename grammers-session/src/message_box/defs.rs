@@ -145,6 +145,10 @@ pub enum UpdatesLike {
     AffectedMessages(tl::types::messages::AffectedMessages),
     /// Special-case for requests that lead to users being invited.
     InvitedUsers(tl::types::messages::InvitedUsers),
+    /// Special-case for the connection to the home datacenter being re-established after it was lost.
+    ///
+    /// Updates may have been missed in the meantime, so their difference should be fetched.
+    Reconnection,
 }
 
 // Public interface around the more tightly-packed internal state.
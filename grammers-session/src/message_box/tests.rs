@@ -457,6 +457,24 @@ fn test_process_socket_updates_flow_common_difference_ok() {
     );
 }
 
+#[test]
+fn test_process_socket_updates_flow_reconnection() {
+    reset_time();
+    let mut message_boxes = MessageBoxes::new();
+
+    message_boxes.set_state(state(12, 34, 56, 78));
+    assert_eq!(message_boxes.get_difference(), None);
+
+    assert_eq!(
+        message_boxes.process_updates(UpdatesLike::Reconnection),
+        Err(Gap)
+    );
+    assert_eq!(
+        message_boxes.get_difference(),
+        Some(get_difference(12, 56, 78))
+    );
+}
+
 #[test]
 fn test_process_socket_updates_flow_common_ok() {
     reset_time();
@@ -184,6 +184,11 @@ impl PeerId {
         Self(-(1000000000000 + id))
     }
 
+    /// Creates a peer identity from its [`Self::bot_api_dialog_id`], as persisted by storages.
+    pub(crate) fn from_bot_api_dialog_id(id: i64) -> Self {
+        Self(id)
+    }
+
     /// Peer kind.
     pub fn kind(self) -> PeerKind {
         if 1 <= self.0 && self.0 <= 0xffffffffff {
@@ -6,13 +6,24 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use crate::SessionData;
 use crate::types::{DcOption, PeerId, PeerInfo, UpdateState, UpdatesState};
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+
+/// Arbitrary error produced by a storage when it fails to persist or load the session.
+pub type SessionError = Box<dyn Error + Send + Sync>;
+
+/// Future returned by the fallible, asynchronous methods of the sessions.
+pub type SessionFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, SessionError>> + Send + 'a>>;
 
 /// The main interface to interact with the different [`crate::storages`].
 ///
-/// All methods are synchronous and currently infallible because clients
-/// are not equipped to deal with the arbitrary errors that a dynamic
-/// `Session` could produce. This may change in the future.
+/// All methods but [`Session::flush`] are synchronous and infallible, because they are
+/// used in places where clients are not equipped to deal with the arbitrary errors that
+/// a dynamic `Session` could produce. Storages that cannot work this way, such as those
+/// accessed over the network, should implement [`AsyncSession`] instead.
 ///
 /// A newly-created storage should return the same values that
 /// [crate::SessionData::default] would produce.
@@ -44,6 +55,12 @@ pub trait Session: Send + Sync {
     /// Should also be used after generating permanent authentication keys to a datacenter.
     fn set_dc_option(&self, dc_option: &DcOption);
 
+    /// Query every datacenter option, including the statically-known ones.
+    ///
+    /// Every option with an authorization key must be returned, because wrappers
+    /// such as [`crate::storages::EncryptedSession`] rely on it to migrate them.
+    fn dc_options(&self) -> Vec<DcOption>;
+
     /// Query a peer by its identity.
     ///
     /// Querying for [`PeerId::self_user`] can be used as a way to determine
@@ -56,9 +73,53 @@ pub trait Session: Send + Sync {
     /// except for users where [`PeerInfo::User::is_self`] is `Some(true)`.
     fn cache_peer(&self, peer: &PeerInfo);
 
+    /// Query every cached peer, in no particular order.
+    ///
+    /// Used to copy the session into a different storage without losing the peers needed to
+    /// interact with the API. The state of channels is instead part of [`Session::updates_state`].
+    ///
+    /// Every peer that [`Session::peer`] can return must be included, because wrappers
+    /// such as [`crate::storages::EncryptedSession`] rely on it to migrate them.
+    fn peers(&self) -> Vec<PeerInfo>;
+
     /// Loads the entire updates state.
     fn updates_state(&self) -> UpdatesState;
 
     /// Update the state for one or all updates.
     fn set_update_state(&self, update: UpdateState);
+
+    /// Make sure all changes made so far have been persisted.
+    ///
+    /// Clients call this after important changes, such as logging in, and report the error.
+    ///
+    /// The default implementation does nothing, which is fine for storages that persist
+    /// changes as soon as they are made.
+    fn flush(&self) -> SessionFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Asynchronous and fallible counterpart to [`Session`], for storages that would otherwise
+/// need to block the runtime, such as databases accessed over the network.
+///
+/// It only needs to load and store data. To use it as a [`Session`], wrap it in a
+/// [`crate::storages::AsyncSessionAdaptor`], which keeps the session in memory so that
+/// it can be queried without waiting.
+pub trait AsyncSession: Send + Sync {
+    /// Load the entirety of the stored session.
+    ///
+    /// A newly-created storage should return [`SessionData::default`].
+    fn load(&self) -> SessionFuture<'_, SessionData>;
+
+    /// Store the [`Session::home_dc_id`].
+    fn set_home_dc_id(&self, dc_id: i32) -> SessionFuture<'_, ()>;
+
+    /// Store a datacenter option, replacing any previous one with the same identifier.
+    fn set_dc_option<'a>(&'a self, dc_option: &'a DcOption) -> SessionFuture<'a, ()>;
+
+    /// Store a peer's basic information, replacing any previous one with the same identity.
+    fn cache_peer<'a>(&'a self, peer: &'a PeerInfo) -> SessionFuture<'a, ()>;
+
+    /// Store the state for one or all updates.
+    fn set_update_state(&self, update: UpdateState) -> SessionFuture<'_, ()>;
 }
@@ -9,7 +9,9 @@
 use std::collections::HashMap;
 
 use crate::types::{DcOption, PeerId, PeerInfo, UpdateState, UpdatesState};
-use crate::{DEFAULT_DC, KNOWN_DC_OPTIONS, Session};
+use crate::{
+    DEFAULT_DC, ENCRYPTION_PARAMS_DC_ID, KNOWN_DC_OPTIONS, KNOWN_TEST_DC_OPTIONS, Session,
+};
 
 /// In-memory representation of the entire [`Session`] state.
 ///
@@ -49,6 +51,22 @@ impl Default for SessionData {
 }
 
 impl SessionData {
+    /// Constructs an instance of the session data meant for Telegram's test environment,
+    /// with the list of statically-known test [`Self::dc_options`].
+    ///
+    /// Accounts in the test environment are separate from those in production,
+    /// so the resulting session should never be used to connect to the latter.
+    pub fn test() -> Self {
+        Self {
+            dc_options: KNOWN_TEST_DC_OPTIONS
+                .iter()
+                .cloned()
+                .map(|dc_option| (dc_option.id, dc_option))
+                .collect(),
+            ..Self::default()
+        }
+    }
+
     /// Imports all information from this session data to a type implementing `Session`.
     pub fn import_to<S: Session>(&self, session: &S) {
         session.set_home_dc_id(self.home_dc);
@@ -63,28 +81,25 @@ impl SessionData {
 }
 
 impl<S: Session> From<S> for SessionData {
-    /// Imports the basic information from any type implementing `Session` into `SessionData`.
+    /// Imports all information from any type implementing `Session` into `SessionData`.
     ///
-    /// Notably, only the standard DC options and the cached self-peer will be included.
+    /// Storages that don't override [`Session::dc_options`] and [`Session::peers`]
+    /// will only provide the standard DC options and the cached self-peer.
     fn from(session: S) -> Self {
-        let home_dc = session.home_dc_id();
-        let dc_options = KNOWN_DC_OPTIONS
-            .iter()
-            .map(|dc_option| (dc_option.id, session.dc_option(dc_option.id).unwrap()))
-            .collect();
-        let peer_infos = [session
-            .peer(PeerId::self_user())
-            .map(|peer_info| (peer_info.id(), peer_info))]
-        .into_iter()
-        .collect::<Option<_>>()
-        .unwrap_or_default();
-        let updates_state = session.updates_state();
-
         Self {
-            home_dc,
-            dc_options,
-            peer_infos,
-            updates_state,
+            home_dc: session.home_dc_id(),
+            dc_options: session
+                .dc_options()
+                .into_iter()
+                .filter(|dc_option| dc_option.id != ENCRYPTION_PARAMS_DC_ID)
+                .map(|dc_option| (dc_option.id, dc_option))
+                .collect(),
+            peer_infos: session
+                .peers()
+                .into_iter()
+                .map(|peer| (peer.id(), peer))
+                .collect(),
+            updates_state: session.updates_state(),
         }
     }
 }
@@ -0,0 +1,196 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use super::MemorySession;
+use crate::types::{DcOption, PeerId, PeerInfo, UpdateState, UpdatesState};
+use crate::{AsyncSession, Session, SessionError, SessionFuture};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Change made to the session that has not been stored yet.
+enum Change {
+    HomeDcId(i32),
+    DcOption(Box<DcOption>),
+    Peer(PeerInfo),
+    UpdateState(UpdateState),
+}
+
+/// Adaptor to use an [`AsyncSession`] wherever a [`Session`] is needed.
+///
+/// The entire session is loaded into memory when the adaptor is created, so that it can be
+/// queried without waiting. Changes are applied in memory right away, and stored in the same
+/// order they were made when the session is [`Session::flush`]ed.
+///
+/// Changes that fail to be stored are kept, and will be retried on the next flush.
+/// Any changes not flushed by the time the adaptor is dropped are lost.
+pub struct AsyncSessionAdaptor<S: AsyncSession> {
+    storage: S,
+    cache: MemorySession,
+    pending: Mutex<VecDeque<Change>>,
+    flushing: tokio::sync::Mutex<()>,
+}
+
+impl<S: AsyncSession> AsyncSessionAdaptor<S> {
+    /// Load the session from the storage.
+    pub async fn load(storage: S) -> Result<Self, SessionError> {
+        let session_data = storage.load().await?;
+        Ok(Self {
+            storage,
+            cache: MemorySession::from(session_data),
+            pending: Mutex::default(),
+            flushing: tokio::sync::Mutex::default(),
+        })
+    }
+
+    /// The storage where the session is persisted.
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    fn push(&self, change: Change) {
+        self.pending.lock().unwrap().push_back(change);
+    }
+
+    async fn store(&self, change: &Change) -> Result<(), SessionError> {
+        match change {
+            Change::HomeDcId(dc_id) => self.storage.set_home_dc_id(*dc_id).await,
+            Change::DcOption(dc_option) => self.storage.set_dc_option(dc_option).await,
+            Change::Peer(peer) => self.storage.cache_peer(peer).await,
+            Change::UpdateState(update) => self.storage.set_update_state(update.clone()).await,
+        }
+    }
+}
+
+impl<S: AsyncSession> Session for AsyncSessionAdaptor<S> {
+    fn home_dc_id(&self) -> i32 {
+        self.cache.home_dc_id()
+    }
+
+    fn set_home_dc_id(&self, dc_id: i32) {
+        self.cache.set_home_dc_id(dc_id);
+        self.push(Change::HomeDcId(dc_id));
+    }
+
+    fn dc_option(&self, dc_id: i32) -> Option<DcOption> {
+        self.cache.dc_option(dc_id)
+    }
+
+    fn set_dc_option(&self, dc_option: &DcOption) {
+        self.cache.set_dc_option(dc_option);
+        self.push(Change::DcOption(Box::new(dc_option.clone())));
+    }
+
+    fn dc_options(&self) -> Vec<DcOption> {
+        self.cache.dc_options()
+    }
+
+    fn peer(&self, peer: PeerId) -> Option<PeerInfo> {
+        self.cache.peer(peer)
+    }
+
+    fn cache_peer(&self, peer: &PeerInfo) {
+        self.cache.cache_peer(peer);
+        self.push(Change::Peer(peer.clone()));
+    }
+
+    fn peers(&self) -> Vec<PeerInfo> {
+        self.cache.peers()
+    }
+
+    fn updates_state(&self) -> UpdatesState {
+        self.cache.updates_state()
+    }
+
+    fn set_update_state(&self, update: UpdateState) {
+        self.cache.set_update_state(update.clone());
+        self.push(Change::UpdateState(update));
+    }
+
+    fn flush(&self) -> SessionFuture<'_, ()> {
+        Box::pin(async move {
+            // Concurrent flushes would otherwise be able to store changes out of order.
+            let _flushing = self.flushing.lock().await;
+            loop {
+                let Some(change) = self.pending.lock().unwrap().pop_front() else {
+                    break Ok(());
+                };
+                if let Err(e) = self.store(&change).await {
+                    self.pending.lock().unwrap().push_front(change);
+                    break Err(e);
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SessionData;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Storage recording the home datacenter changes, which fails to store them if `broken`.
+    #[derive(Default)]
+    struct Storage {
+        home_dc_ids: Mutex<Vec<i32>>,
+        broken: AtomicBool,
+    }
+
+    impl AsyncSession for Storage {
+        fn load(&self) -> SessionFuture<'_, SessionData> {
+            Box::pin(async { Ok(SessionData::default()) })
+        }
+
+        fn set_home_dc_id(&self, dc_id: i32) -> SessionFuture<'_, ()> {
+            Box::pin(async move {
+                if self.broken.load(Ordering::Relaxed) {
+                    return Err("storage is broken".into());
+                }
+                self.home_dc_ids.lock().unwrap().push(dc_id);
+                Ok(())
+            })
+        }
+
+        fn set_dc_option<'a>(&'a self, _: &'a DcOption) -> SessionFuture<'a, ()> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn cache_peer<'a>(&'a self, _: &'a PeerInfo) -> SessionFuture<'a, ()> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn set_update_state(&self, _: UpdateState) -> SessionFuture<'_, ()> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn changes_are_stored_in_order_on_flush() {
+        let session = AsyncSessionAdaptor::load(Storage::default()).await.unwrap();
+        session.set_home_dc_id(4);
+        session.set_home_dc_id(5);
+        assert_eq!(session.home_dc_id(), 5);
+        assert!(session.storage().home_dc_ids.lock().unwrap().is_empty());
+
+        session.flush().await.unwrap();
+        assert_eq!(*session.storage().home_dc_ids.lock().unwrap(), [4, 5]);
+    }
+
+    #[tokio::test]
+    async fn failed_changes_are_retried() {
+        let session = AsyncSessionAdaptor::load(Storage::default()).await.unwrap();
+        session.storage().broken.store(true, Ordering::Relaxed);
+        session.set_home_dc_id(4);
+        assert!(session.flush().await.is_err());
+
+        session.storage().broken.store(false, Ordering::Relaxed);
+        session.set_home_dc_id(5);
+        session.flush().await.unwrap();
+        assert_eq!(*session.storage().home_dc_ids.lock().unwrap(), [4, 5]);
+    }
+}
@@ -0,0 +1,621 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::types::{DcOption, PeerAuth, PeerId, PeerInfo, UpdateState, UpdatesState};
+use crate::{ENCRYPTION_PARAMS_DC_ID as PARAMS_DC_ID, Session, SessionFuture};
+use grammers_crypto::{aes, hmac_sha256, pbkdf2_sha256};
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+const VERSION: u8 = 1;
+
+const FLAG_PEER_HASHES: u8 = 1;
+
+const STAGE_AUTH_KEYS: u8 = 1;
+
+const STAGE_PEER_HASHES: u8 = 2;
+
+const PASSPHRASE_ROUNDS: u32 = 100_000;
+
+/// Secret from which the key used to encrypt the session is derived.
+pub enum EncryptionKey {
+    /// Uniformly random key, such as one kept in a secrets manager.
+    Raw([u8; 32]),
+    /// Human-chosen passphrase, stretched with PBKDF2 before use.
+    Passphrase(String),
+}
+
+/// Error returned when an [`EncryptedSession`] cannot be opened.
+#[derive(Debug)]
+pub enum EncryptionError {
+    /// The session was encrypted with a different key.
+    WrongKey,
+    /// The session was encrypted by a newer version of this library.
+    UnsupportedVersion(u8),
+}
+
+impl std::error::Error for EncryptionError {}
+
+impl fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WrongKey => write!(f, "session was encrypted with a different key"),
+            Self::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "session was encrypted with unsupported version {version}"
+                )
+            }
+        }
+    }
+}
+
+/// Wrapper around any [`Session`] to encrypt its secrets at rest.
+///
+/// The authorization keys of the datacenter options, and optionally the hashes of the cached
+/// peers, are encrypted before reaching the wrapped session, and decrypted when read back.
+/// Everything else is stored as-is.
+///
+/// Sessions that were not encrypted yet are migrated when opened: every authorization key,
+/// and every peer hash if requested, is encrypted in place. A migration that is interrupted
+/// resumes where it left off the next time the session is opened.
+///
+/// The parameters needed to open the session again, except for the key, are stored in the
+/// wrapped session itself, under a reserved datacenter option that is only reachable by its
+/// identifier and is never listed among the datacenter options of either session.
+///
+/// ```
+/// use grammers_session::Session;
+/// use grammers_session::storages::{EncryptedSession, EncryptionKey, MemorySession};
+///
+/// let key = EncryptionKey::Raw([7; 32]);
+/// let session = EncryptedSession::new(MemorySession::default(), key).unwrap();
+/// assert_eq!(session.home_dc_id(), session.session().home_dc_id());
+/// ```
+pub struct EncryptedSession<S: Session> {
+    session: S,
+    key: [u8; 32],
+    peer_hashes: bool,
+}
+
+impl<S: Session> EncryptedSession<S> {
+    /// Wrap the session, encrypting the authorization keys stored in it.
+    ///
+    /// Peer hashes remain encrypted if the session was previously opened
+    /// with [`Self::with_peer_hashes`].
+    pub fn new(session: S, key: EncryptionKey) -> Result<Self, EncryptionError> {
+        Self::open(session, key, false)
+    }
+
+    /// Wrap the session, encrypting both the authorization keys and the hashes of cached peers.
+    pub fn with_peer_hashes(session: S, key: EncryptionKey) -> Result<Self, EncryptionError> {
+        Self::open(session, key, true)
+    }
+
+    /// The wrapped session, which only ever sees encrypted secrets.
+    pub fn session(&self) -> &S {
+        &self.session
+    }
+
+    fn open(session: S, key: EncryptionKey, peer_hashes: bool) -> Result<Self, EncryptionError> {
+        let stored = session
+            .dc_option(PARAMS_DC_ID)
+            .and_then(|dc_option| dc_option.auth_key);
+        let mut params = match &stored {
+            Some(params) if params[0] != VERSION => {
+                return Err(EncryptionError::UnsupportedVersion(params[0]));
+            }
+            Some(params) => Params::from_bytes(params),
+            None => {
+                let mut salt = [0; 32];
+                getrandom::fill(&mut salt).expect("failed to generate a secure salt");
+                Params {
+                    rounds: match key {
+                        EncryptionKey::Raw(_) => 0,
+                        EncryptionKey::Passphrase(_) => PASSPHRASE_ROUNDS,
+                    },
+                    salt,
+                    flags: 0,
+                    migration: None,
+                }
+            }
+        };
+        let key = derive_key(&key, &params.salt, params.rounds);
+        if let Some(stored) = &stored
+            && hmac_sha256(&key, b"check")[..] != stored[40..72]
+        {
+            return Err(EncryptionError::WrongKey);
+        }
+
+        let stored_peer_hashes = params.flags & FLAG_PEER_HASHES != 0;
+        let this = Self {
+            session,
+            key,
+            peer_hashes: peer_hashes || stored_peer_hashes,
+        };
+        let resuming = params.migration.map(|migration| migration.stage);
+
+        if stored.is_none() || resuming == Some(STAGE_AUTH_KEYS) {
+            let dc_options = this
+                .session
+                .dc_options()
+                .into_iter()
+                .filter(|dc_option| dc_option.id != PARAMS_DC_ID)
+                .filter_map(|dc_option| Some((dc_option.id as i64, dc_option.auth_key?, dc_option)))
+                .collect();
+            this.migrate(&mut params, STAGE_AUTH_KEYS, dc_options, |dc_option| {
+                this.set_dc_option(dc_option)
+            });
+        }
+        if this.peer_hashes && (!stored_peer_hashes || resuming == Some(STAGE_PEER_HASHES)) {
+            params.flags |= FLAG_PEER_HASHES;
+            let peers = this
+                .session
+                .peers()
+                .into_iter()
+                .map(|peer| {
+                    let hash = peer.auth().hash().to_le_bytes();
+                    (peer.id().bot_api_dialog_id(), hash, peer)
+                })
+                .collect();
+            this.migrate(&mut params, STAGE_PEER_HASHES, peers, |peer| {
+                this.cache_peer(peer)
+            });
+        }
+
+        if stored.as_ref() != Some(&params.to_bytes(&this.key)) {
+            this.store_params(&params);
+        }
+
+        Ok(this)
+    }
+
+    /// Encrypt every secret in place, in order of their identifier, recording the progress
+    /// before each one so that an interrupted migration can be resumed without encrypting
+    /// any secret twice.
+    fn migrate<T, const N: usize>(
+        &self,
+        params: &mut Params,
+        stage: u8,
+        mut secrets: Vec<(i64, [u8; N], T)>,
+        encrypt: impl Fn(&T),
+    ) {
+        secrets.sort_by_key(|(id, _, _)| *id);
+        let resume_from = params
+            .migration
+            .filter(|migration| migration.stage == stage);
+
+        for (id, secret, value) in secrets {
+            let fingerprint = self.fingerprint(&secret);
+            if let Some(migration) = resume_from {
+                // Secrets before the interrupted one were all encrypted already, and the
+                // interrupted one only if it no longer matches its plain fingerprint.
+                if id < migration.id || (id == migration.id && fingerprint != migration.fingerprint)
+                {
+                    continue;
+                }
+            }
+
+            params.migration = Some(Migration {
+                stage,
+                id,
+                fingerprint,
+            });
+            self.store_params(params);
+            encrypt(&value);
+        }
+
+        params.migration = None;
+    }
+
+    fn fingerprint(&self, secret: &[u8]) -> [u8; 32] {
+        hmac_sha256(&self.key, &[b"migration", secret].concat())
+    }
+
+    fn store_params(&self, params: &Params) {
+        self.session.set_dc_option(&DcOption {
+            id: PARAMS_DC_ID,
+            ipv4: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+            ipv6: SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0),
+            media_only: false,
+            cdn: false,
+            test: false,
+            r#static: false,
+            auth_key: Some(params.to_bytes(&self.key)),
+        });
+    }
+
+    /// Derive a value bound to both the key, and the given purpose and identifier.
+    fn derive(&self, purpose: &[u8], id: i64) -> [u8; 32] {
+        hmac_sha256(&self.key, &[purpose, &id.to_le_bytes()].concat())
+    }
+
+    fn auth_key_cipher(&self, dc_id: i32) -> ([u8; 32], [u8; 32]) {
+        (
+            self.derive(b"auth key", 0),
+            self.derive(b"auth key iv", dc_id as i64),
+        )
+    }
+
+    fn decrypt_auth_key(&self, mut dc_option: DcOption) -> DcOption {
+        if let Some(auth_key) = dc_option.auth_key.as_mut() {
+            let (key, iv) = self.auth_key_cipher(dc_option.id);
+            aes::ige_decrypt(auth_key, &key, &iv);
+        }
+        dc_option
+    }
+
+    /// Encrypt or decrypt the peer hash, which are the same operation.
+    fn toggle_peer_hash(&self, peer: &PeerInfo) -> PeerInfo {
+        let mut peer = peer.clone();
+        if !self.peer_hashes {
+            return peer;
+        }
+
+        let pad = self.derive(b"peer hash", peer.id().bot_api_dialog_id());
+        let pad = i64::from_le_bytes(pad[..8].try_into().unwrap());
+        match &mut peer {
+            PeerInfo::User { auth, .. } | PeerInfo::Channel { auth, .. } => {
+                // Unknown hashes are left as-is so storages can keep telling them apart.
+                if let Some(auth) = auth.as_mut().filter(|auth| **auth != PeerAuth::default()) {
+                    *auth = PeerAuth::from_hash(auth.hash() ^ pad);
+                }
+            }
+            PeerInfo::Chat { .. } => {}
+        }
+        peer
+    }
+}
+
+/// Parameters needed to open the session again, except for the key.
+struct Params {
+    rounds: u32,
+    salt: [u8; 32],
+    flags: u8,
+    migration: Option<Migration>,
+}
+
+/// Progress of an unfinished migration.
+#[derive(Clone, Copy)]
+struct Migration {
+    stage: u8,
+    /// Identifier of the datacenter or peer whose secret was being encrypted.
+    id: i64,
+    /// Fingerprint of the secret before it was encrypted.
+    fingerprint: [u8; 32],
+}
+
+impl Params {
+    // Layout: version, flags, stage, padding, rounds, salt, check, migrated ID, fingerprint.
+    fn from_bytes(params: &[u8; 256]) -> Self {
+        Self {
+            rounds: u32::from_le_bytes(params[4..8].try_into().unwrap()),
+            salt: params[8..40].try_into().unwrap(),
+            flags: params[1],
+            migration: (params[2] != 0).then(|| Migration {
+                stage: params[2],
+                id: i64::from_le_bytes(params[72..80].try_into().unwrap()),
+                fingerprint: params[80..112].try_into().unwrap(),
+            }),
+        }
+    }
+
+    fn to_bytes(&self, key: &[u8; 32]) -> [u8; 256] {
+        let mut params = [0; 256];
+        params[0] = VERSION;
+        params[1] = self.flags;
+        params[4..8].copy_from_slice(&self.rounds.to_le_bytes());
+        params[8..40].copy_from_slice(&self.salt);
+        params[40..72].copy_from_slice(&hmac_sha256(key, b"check"));
+        if let Some(migration) = &self.migration {
+            params[2] = migration.stage;
+            params[72..80].copy_from_slice(&migration.id.to_le_bytes());
+            params[80..112].copy_from_slice(&migration.fingerprint);
+        }
+        params
+    }
+}
+
+fn derive_key(key: &EncryptionKey, salt: &[u8], rounds: u32) -> [u8; 32] {
+    match key {
+        EncryptionKey::Raw(key) => hmac_sha256(key, salt),
+        EncryptionKey::Passphrase(passphrase) => pbkdf2_sha256(passphrase.as_bytes(), salt, rounds),
+    }
+}
+
+impl<S: Session> Session for EncryptedSession<S> {
+    fn home_dc_id(&self) -> i32 {
+        self.session.home_dc_id()
+    }
+
+    fn set_home_dc_id(&self, dc_id: i32) {
+        self.session.set_home_dc_id(dc_id)
+    }
+
+    fn dc_option(&self, dc_id: i32) -> Option<DcOption> {
+        if dc_id == PARAMS_DC_ID {
+            return None;
+        }
+
+        self.session
+            .dc_option(dc_id)
+            .map(|dc_option| self.decrypt_auth_key(dc_option))
+    }
+
+    fn set_dc_option(&self, dc_option: &DcOption) {
+        let mut dc_option = dc_option.clone();
+        if let Some(auth_key) = dc_option.auth_key.as_mut() {
+            let (key, iv) = self.auth_key_cipher(dc_option.id);
+            aes::ige_encrypt(auth_key, &key, &iv);
+        }
+        self.session.set_dc_option(&dc_option)
+    }
+
+    fn dc_options(&self) -> Vec<DcOption> {
+        self.session
+            .dc_options()
+            .into_iter()
+            .filter(|dc_option| dc_option.id != PARAMS_DC_ID)
+            .map(|dc_option| self.decrypt_auth_key(dc_option))
+            .collect()
+    }
+
+    fn peer(&self, peer: PeerId) -> Option<PeerInfo> {
+        self.session
+            .peer(peer)
+            .map(|peer| self.toggle_peer_hash(&peer))
+    }
+
+    fn cache_peer(&self, peer: &PeerInfo) {
+        self.session.cache_peer(&self.toggle_peer_hash(peer))
+    }
+
+    fn peers(&self) -> Vec<PeerInfo> {
+        self.session
+            .peers()
+            .iter()
+            .map(|peer| self.toggle_peer_hash(peer))
+            .collect()
+    }
+
+    fn updates_state(&self) -> UpdatesState {
+        self.session.updates_state()
+    }
+
+    fn set_update_state(&self, update: UpdateState) {
+        self.session.set_update_state(update)
+    }
+
+    fn flush(&self) -> SessionFuture<'_, ()> {
+        self.session.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SessionData;
+    use crate::storages::MemorySession;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Session that stops persisting anything after a number of writes, as if the process
+    /// had been killed at that point.
+    struct Interrupted {
+        session: MemorySession,
+        writes_left: AtomicUsize,
+    }
+
+    impl Interrupted {
+        fn write(&self) -> bool {
+            self.writes_left
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .is_ok()
+        }
+    }
+
+    impl Session for Interrupted {
+        fn home_dc_id(&self) -> i32 {
+            self.session.home_dc_id()
+        }
+
+        fn set_home_dc_id(&self, dc_id: i32) {
+            if self.write() {
+                self.session.set_home_dc_id(dc_id)
+            }
+        }
+
+        fn dc_option(&self, dc_id: i32) -> Option<DcOption> {
+            self.session.dc_option(dc_id)
+        }
+
+        fn set_dc_option(&self, dc_option: &DcOption) {
+            if self.write() {
+                self.session.set_dc_option(dc_option)
+            }
+        }
+
+        fn dc_options(&self) -> Vec<DcOption> {
+            self.session.dc_options()
+        }
+
+        fn peer(&self, peer: PeerId) -> Option<PeerInfo> {
+            self.session.peer(peer)
+        }
+
+        fn cache_peer(&self, peer: &PeerInfo) {
+            if self.write() {
+                self.session.cache_peer(peer)
+            }
+        }
+
+        fn peers(&self) -> Vec<PeerInfo> {
+            self.session.peers()
+        }
+
+        fn updates_state(&self) -> UpdatesState {
+            self.session.updates_state()
+        }
+
+        fn set_update_state(&self, update: UpdateState) {
+            if self.write() {
+                self.session.set_update_state(update)
+            }
+        }
+    }
+
+    fn plain_session() -> MemorySession {
+        let session = MemorySession::default();
+        let mut dc_option = session.dc_option(2).unwrap();
+        dc_option.auth_key = Some([1; 256]);
+        session.set_home_dc_id(2);
+        session.set_dc_option(&dc_option);
+        dc_option.id = 203;
+        dc_option.auth_key = Some([3; 256]);
+        session.set_dc_option(&dc_option);
+        session.cache_peer(&PeerInfo::User {
+            id: 123,
+            auth: Some(PeerAuth::from_hash(456)),
+            bot: Some(false),
+            is_self: Some(true),
+        });
+        session
+    }
+
+    #[test]
+    fn existing_auth_keys_are_migrated() {
+        let session = EncryptedSession::new(plain_session(), EncryptionKey::Raw([7; 32])).unwrap();
+        for (dc_id, auth_key) in [(2, [1; 256]), (203, [3; 256])] {
+            assert_eq!(session.dc_option(dc_id).unwrap().auth_key, Some(auth_key));
+            assert_ne!(
+                session.session().dc_option(dc_id).unwrap().auth_key,
+                Some(auth_key)
+            );
+        }
+        assert_eq!(session.dc_option(PARAMS_DC_ID), None);
+        assert!(
+            session
+                .dc_options()
+                .iter()
+                .all(|dc_option| dc_option.id != PARAMS_DC_ID)
+        );
+
+        // Opening it again must not encrypt the keys twice.
+        let session = EncryptedSession::new(session.session, EncryptionKey::Raw([7; 32])).unwrap();
+        assert_eq!(session.dc_option(2).unwrap().auth_key, Some([1; 256]));
+    }
+
+    #[test]
+    fn params_are_not_listed_by_wrapped_session() {
+        let session = EncryptedSession::new(plain_session(), EncryptionKey::Raw([7; 32])).unwrap();
+        assert!(session.session().dc_option(PARAMS_DC_ID).is_some());
+        assert!(
+            session
+                .session()
+                .dc_options()
+                .iter()
+                .all(|dc_option| dc_option.id != PARAMS_DC_ID)
+        );
+
+        // Exporting the wrapped session (such as to a string session) must not include them either.
+        let data = SessionData::from(session.session);
+        assert!(!data.dc_options.contains_key(&PARAMS_DC_ID));
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let session =
+            EncryptedSession::new(plain_session(), EncryptionKey::Passphrase("right".into()))
+                .unwrap();
+        assert!(matches!(
+            EncryptedSession::new(session.session, EncryptionKey::Passphrase("wrong".into())),
+            Err(EncryptionError::WrongKey)
+        ));
+    }
+
+    #[test]
+    fn peer_hashes_are_encrypted() {
+        let session = EncryptedSession::with_peer_hashes(
+            MemorySession::default(),
+            EncryptionKey::Raw([7; 32]),
+        )
+        .unwrap();
+        let peer = PeerInfo::Channel {
+            id: 123,
+            auth: Some(PeerAuth::from_hash(456)),
+            kind: None,
+        };
+        session.cache_peer(&peer);
+        assert_eq!(session.peer(peer.id()), Some(peer.clone()));
+        assert_ne!(session.session().peer(peer.id()), Some(peer));
+
+        let self_user = plain_session().peer(PeerId::self_user());
+        let session = EncryptedSession::new(plain_session(), EncryptionKey::Raw([7; 32])).unwrap();
+        assert_eq!(session.session().peer(PeerId::self_user()), self_user);
+
+        // Peers cached in plain are migrated once requested.
+        let session =
+            EncryptedSession::with_peer_hashes(session.session, EncryptionKey::Raw([7; 32]))
+                .unwrap();
+        assert_eq!(session.peer(PeerId::self_user()), self_user);
+        assert_ne!(session.session().peer(PeerId::self_user()), self_user);
+        let session = EncryptedSession::new(session.session, EncryptionKey::Raw([7; 32])).unwrap();
+        assert_eq!(session.peers(), Vec::from_iter(self_user));
+    }
+
+    #[test]
+    fn interrupted_migration_is_resumed() {
+        let expected = plain_session();
+        let peer = PeerInfo::Channel {
+            id: 789,
+            auth: Some(PeerAuth::from_hash(-1)),
+            kind: None,
+        };
+        expected.cache_peer(&peer);
+
+        for writes in 0.. {
+            let session = plain_session();
+            session.cache_peer(&peer);
+            let interrupted = Interrupted {
+                session,
+                writes_left: AtomicUsize::new(writes),
+            };
+            let key = || EncryptionKey::Raw([7; 32]);
+            let interrupted = EncryptedSession::new(interrupted, key()).unwrap().session;
+            let interrupted = EncryptedSession::with_peer_hashes(interrupted, key())
+                .unwrap()
+                .session;
+            let finished = interrupted.writes_left.load(Ordering::Relaxed) > 0;
+
+            // Opening the session again must finish the migration, and opening it
+            // without peer hashes afterwards must not undo them.
+            let session = EncryptedSession::with_peer_hashes(interrupted.session, key()).unwrap();
+            let session = EncryptedSession::new(session.session, key()).unwrap();
+            for dc_option in expected.dc_options() {
+                assert_eq!(
+                    session.dc_option(dc_option.id),
+                    Some(dc_option),
+                    "{writes} writes"
+                );
+            }
+            assert_eq!(
+                session.peer(peer.id()),
+                Some(peer.clone()),
+                "{writes} writes"
+            );
+            assert_eq!(
+                session.peer(PeerId::self_user()),
+                expected.peer(PeerId::self_user()),
+                "{writes} writes"
+            );
+            assert_ne!(session.session().peer(peer.id()), Some(peer.clone()));
+            if finished {
+                break;
+            }
+        }
+    }
+}
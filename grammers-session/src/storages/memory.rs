@@ -6,8 +6,8 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use crate::types::{ChannelState, DcOption, PeerId, PeerInfo, UpdateState, UpdatesState};
-use crate::{Session, SessionData};
+use crate::types::{ChannelState, DcOption, PeerId, PeerInfo, PeerKind, UpdateState, UpdatesState};
+use crate::{ENCRYPTION_PARAMS_DC_ID, Session, SessionData};
 use std::sync::Mutex;
 
 /// In-memory session interface.
@@ -18,55 +18,117 @@ use std::sync::Mutex;
 /// Logging in has a very high cost in terms of flood wait errors,
 /// so the state really should be persisted by other means.
 #[derive(Default)]
-pub struct MemorySession(Mutex<SessionData>);
+pub struct MemorySession(Mutex<MemoryData>);
+
+#[derive(Default)]
+struct MemoryData {
+    session_data: SessionData,
+    /// Identifier of the cached self-user, so that it can be found without a scan.
+    self_id: Option<PeerId>,
+}
 
 impl From<SessionData> for MemorySession {
-    /// Constructs a memory session from the entirety of the session data,
-    /// unlike the blanket `From` implementation which cannot import all values
+    /// Constructs a memory session from the entirety of the session data.
     fn from(session_data: SessionData) -> Self {
-        Self(Mutex::new(session_data))
+        let self_id = session_data
+            .peer_infos
+            .values()
+            .find(|peer| is_self(peer))
+            .map(PeerInfo::id);
+        Self(Mutex::new(MemoryData {
+            session_data,
+            self_id,
+        }))
     }
 }
 
+fn is_self(peer: &PeerInfo) -> bool {
+    matches!(
+        peer,
+        PeerInfo::User {
+            is_self: Some(true),
+            ..
+        }
+    )
+}
+
 impl Session for MemorySession {
     fn home_dc_id(&self) -> i32 {
-        self.0.lock().unwrap().home_dc
+        self.0.lock().unwrap().session_data.home_dc
     }
 
     fn set_home_dc_id(&self, dc_id: i32) {
-        self.0.lock().unwrap().home_dc = dc_id;
+        self.0.lock().unwrap().session_data.home_dc = dc_id;
     }
 
     fn dc_option(&self, dc_id: i32) -> Option<DcOption> {
-        self.0.lock().unwrap().dc_options.get(&dc_id).cloned()
+        self.0
+            .lock()
+            .unwrap()
+            .session_data
+            .dc_options
+            .get(&dc_id)
+            .cloned()
     }
 
     fn set_dc_option(&self, dc_option: &DcOption) {
         self.0
             .lock()
             .unwrap()
+            .session_data
             .dc_options
             .insert(dc_option.id, dc_option.clone());
     }
 
+    fn dc_options(&self) -> Vec<DcOption> {
+        self.0
+            .lock()
+            .unwrap()
+            .session_data
+            .dc_options
+            .values()
+            .filter(|dc_option| dc_option.id != ENCRYPTION_PARAMS_DC_ID)
+            .cloned()
+            .collect()
+    }
+
     fn peer(&self, peer: PeerId) -> Option<PeerInfo> {
-        self.0.lock().unwrap().peer_infos.get(&peer).cloned()
+        let data = self.0.lock().unwrap();
+        let peer = if peer.kind() == PeerKind::UserSelf {
+            data.self_id?
+        } else {
+            peer
+        };
+        data.session_data.peer_infos.get(&peer).cloned()
     }
 
     fn cache_peer(&self, peer: &PeerInfo) {
+        let mut data = self.0.lock().unwrap();
+        if is_self(peer) {
+            data.self_id = Some(peer.id());
+        } else if data.self_id == Some(peer.id()) {
+            data.self_id = None;
+        }
+        data.session_data.peer_infos.insert(peer.id(), peer.clone());
+    }
+
+    fn peers(&self) -> Vec<PeerInfo> {
         self.0
             .lock()
             .unwrap()
+            .session_data
             .peer_infos
-            .insert(peer.id(), peer.clone());
+            .values()
+            .cloned()
+            .collect()
     }
 
     fn updates_state(&self) -> UpdatesState {
-        self.0.lock().unwrap().updates_state.clone()
+        self.0.lock().unwrap().session_data.updates_state.clone()
     }
 
     fn set_update_state(&self, update: UpdateState) {
-        let mut data = self.0.lock().unwrap();
+        let data = &mut self.0.lock().unwrap().session_data;
 
         match update {
             UpdateState::All(updates_state) => {
@@ -87,3 +149,40 @@ impl Session for MemorySession {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_self_user() {
+        let session = MemorySession::default();
+        assert_eq!(session.peer(PeerId::self_user()), None);
+
+        let peer = PeerInfo::User {
+            id: 1,
+            auth: None,
+            bot: Some(false),
+            is_self: Some(true),
+        };
+        session.cache_peer(&PeerInfo::User {
+            id: 2,
+            auth: None,
+            bot: Some(false),
+            is_self: None,
+        });
+        session.cache_peer(&peer);
+        assert_eq!(session.peer(PeerId::self_user()), Some(peer.clone()));
+
+        let session = MemorySession::from(SessionData::from(session));
+        assert_eq!(session.peer(PeerId::self_user()), Some(peer));
+
+        session.cache_peer(&PeerInfo::User {
+            id: 1,
+            auth: None,
+            bot: Some(false),
+            is_self: Some(false),
+        });
+        assert_eq!(session.peer(PeerId::self_user()), None);
+    }
+}
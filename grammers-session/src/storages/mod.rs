@@ -11,8 +11,17 @@
 //! Some may require certain features to be enabled. If none fit
 //! your needs, you can also implement [`crate::Session`] yourself.
 
+mod async_adaptor;
+mod encrypted;
 mod memory;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod rows;
 mod sqlite;
 
+pub use async_adaptor::AsyncSessionAdaptor;
+pub use encrypted::{EncryptedSession, EncryptionError, EncryptionKey};
 pub use memory::MemorySession;
+#[cfg(feature = "postgres")]
+pub use postgres::PostgresSession;
 pub use sqlite::SqliteSession;
@@ -0,0 +1,439 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use super::rows::{DcOptionFlag, dc_option_flags, peer_info, peer_subtype};
+use crate::types::{ChannelState, DcOption, PeerAuth, PeerInfo, UpdateState};
+use crate::{AsyncSession, SessionData, SessionFuture};
+use tokio::sync::Mutex;
+use tokio_postgres::Client;
+
+/// Arbitrary key for the advisory lock held while the tables are created,
+/// so that concurrent sessions don't race to create them.
+const SCHEMA_LOCK: i64 = 0x6772_616d_6d65_7273;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS dc_home (
+        account TEXT NOT NULL,
+        dc_id INTEGER NOT NULL,
+        PRIMARY KEY(account));
+    CREATE TABLE IF NOT EXISTS dc_option (
+        account TEXT NOT NULL,
+        dc_id INTEGER NOT NULL,
+        ipv4 TEXT NOT NULL,
+        ipv6 TEXT NOT NULL,
+        auth_key BYTEA,
+        flags SMALLINT NOT NULL DEFAULT 0,
+        PRIMARY KEY(account, dc_id));
+    CREATE TABLE IF NOT EXISTS peer_info (
+        account TEXT NOT NULL,
+        peer_id BIGINT NOT NULL,
+        hash BIGINT,
+        subtype SMALLINT,
+        PRIMARY KEY(account, peer_id));
+    CREATE TABLE IF NOT EXISTS update_state (
+        account TEXT NOT NULL,
+        pts INTEGER NOT NULL,
+        qts INTEGER NOT NULL,
+        date INTEGER NOT NULL,
+        seq INTEGER NOT NULL,
+        PRIMARY KEY(account));
+    CREATE TABLE IF NOT EXISTS channel_state (
+        account TEXT NOT NULL,
+        peer_id BIGINT NOT NULL,
+        pts INTEGER NOT NULL,
+        PRIMARY KEY(account, peer_id));
+";
+
+/// PostgreSQL-based storage, for use with an [`super::AsyncSessionAdaptor`].
+///
+/// Every row is keyed by the name of the account it belongs to,
+/// so that the sessions of many accounts can share the same database.
+///
+/// ```no_run
+/// # async fn f() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// use grammers_session::storages::{AsyncSessionAdaptor, PostgresSession};
+///
+/// let (client, connection) =
+///     tokio_postgres::connect("host=localhost user=postgres", tokio_postgres::NoTls).await?;
+/// tokio::spawn(connection);
+///
+/// let storage = PostgresSession::new(client, "my-bot").await?;
+/// let session = AsyncSessionAdaptor::load(storage).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct PostgresSession {
+    client: Mutex<Client>,
+    account: String,
+}
+
+impl PostgresSession {
+    /// Use the database behind the connected client to store the session of the given account,
+    /// creating the tables if they don't exist yet.
+    ///
+    /// The connection itself must be driven separately, as described in [`tokio_postgres`].
+    pub async fn new(
+        mut client: Client,
+        account: impl Into<String>,
+    ) -> Result<Self, tokio_postgres::Error> {
+        let transaction = client.transaction().await?;
+        transaction
+            .execute("SELECT pg_advisory_xact_lock($1)", &[&SCHEMA_LOCK])
+            .await?;
+        transaction.batch_execute(SCHEMA).await?;
+        transaction.commit().await?;
+
+        Ok(Self {
+            client: Mutex::new(client),
+            account: account.into(),
+        })
+    }
+
+    /// The account whose session is stored.
+    pub fn account(&self) -> &str {
+        &self.account
+    }
+}
+
+impl AsyncSession for PostgresSession {
+    fn load(&self) -> SessionFuture<'_, SessionData> {
+        Box::pin(async move {
+            let client = self.client.lock().await;
+            let account = &self.account;
+            let mut data = SessionData::default();
+
+            if let Some(row) = client
+                .query_opt("SELECT dc_id FROM dc_home WHERE account = $1", &[account])
+                .await?
+            {
+                data.home_dc = row.get("dc_id");
+            }
+
+            for row in client
+                .query("SELECT * FROM dc_option WHERE account = $1", &[account])
+                .await?
+            {
+                let flags = row.get::<_, i16>("flags") as u8;
+                let dc_option = DcOption {
+                    id: row.get("dc_id"),
+                    ipv4: row.get::<_, &str>("ipv4").parse()?,
+                    ipv6: row.get::<_, &str>("ipv6").parse()?,
+                    media_only: flags & DcOptionFlag::MediaOnly as u8 != 0,
+                    cdn: flags & DcOptionFlag::Cdn as u8 != 0,
+                    test: flags & DcOptionFlag::Test as u8 != 0,
+                    r#static: flags & DcOptionFlag::Static as u8 != 0,
+                    auth_key: row
+                        .get::<_, Option<Vec<u8>>>("auth_key")
+                        .map(|auth_key| auth_key.try_into())
+                        .transpose()
+                        .map_err(|auth_key: Vec<u8>| {
+                            format!("stored auth key has invalid length {}", auth_key.len())
+                        })?,
+                };
+                data.dc_options.insert(dc_option.id, dc_option);
+            }
+
+            for row in client
+                .query("SELECT * FROM peer_info WHERE account = $1", &[account])
+                .await?
+            {
+                let peer = peer_info(
+                    row.get("peer_id"),
+                    row.get("hash"),
+                    row.get::<_, Option<i16>>("subtype").map(|s| s as u8),
+                );
+                data.peer_infos.insert(peer.id(), peer);
+            }
+
+            if let Some(row) = client
+                .query_opt("SELECT * FROM update_state WHERE account = $1", &[account])
+                .await?
+            {
+                data.updates_state.pts = row.get("pts");
+                data.updates_state.qts = row.get("qts");
+                data.updates_state.date = row.get("date");
+                data.updates_state.seq = row.get("seq");
+            }
+
+            data.updates_state.channels = client
+                .query("SELECT * FROM channel_state WHERE account = $1", &[account])
+                .await?
+                .into_iter()
+                .map(|row| ChannelState {
+                    id: row.get("peer_id"),
+                    pts: row.get("pts"),
+                })
+                .collect();
+
+            Ok(data)
+        })
+    }
+
+    fn set_home_dc_id(&self, dc_id: i32) -> SessionFuture<'_, ()> {
+        Box::pin(async move {
+            self.client
+                .lock()
+                .await
+                .execute(
+                    "INSERT INTO dc_home VALUES ($1, $2)
+                    ON CONFLICT (account) DO UPDATE SET dc_id = EXCLUDED.dc_id",
+                    &[&self.account, &dc_id],
+                )
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn set_dc_option<'a>(&'a self, dc_option: &'a DcOption) -> SessionFuture<'a, ()> {
+        Box::pin(async move {
+            self.client
+                .lock()
+                .await
+                .execute(
+                    "INSERT INTO dc_option VALUES ($1, $2, $3, $4, $5, $6)
+                    ON CONFLICT (account, dc_id) DO UPDATE SET
+                        ipv4 = EXCLUDED.ipv4,
+                        ipv6 = EXCLUDED.ipv6,
+                        auth_key = EXCLUDED.auth_key,
+                        flags = EXCLUDED.flags",
+                    &[
+                        &self.account,
+                        &dc_option.id,
+                        &dc_option.ipv4.to_string(),
+                        &dc_option.ipv6.to_string(),
+                        &dc_option.auth_key.as_ref().map(|auth_key| &auth_key[..]),
+                        &(dc_option_flags(dc_option) as i16),
+                    ],
+                )
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn cache_peer<'a>(&'a self, peer: &'a PeerInfo) -> SessionFuture<'a, ()> {
+        Box::pin(async move {
+            let hash = (peer.auth() != PeerAuth::default()).then(|| peer.auth().hash());
+            let subtype = peer_subtype(peer).map(|subtype| subtype as i16);
+            self.client
+                .lock()
+                .await
+                .execute(
+                    "INSERT INTO peer_info VALUES ($1, $2, $3, $4)
+                    ON CONFLICT (account, peer_id) DO UPDATE SET
+                        hash = EXCLUDED.hash,
+                        subtype = EXCLUDED.subtype",
+                    &[
+                        &self.account,
+                        &peer.id().bot_api_dialog_id(),
+                        &hash,
+                        &subtype,
+                    ],
+                )
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn set_update_state(&self, update: UpdateState) -> SessionFuture<'_, ()> {
+        Box::pin(async move {
+            let mut client = self.client.lock().await;
+            let account = &self.account;
+
+            match update {
+                UpdateState::All(updates_state) => {
+                    let transaction = client.transaction().await?;
+                    transaction
+                        .execute(
+                            "INSERT INTO update_state VALUES ($1, $2, $3, $4, $5)
+                            ON CONFLICT (account) DO UPDATE SET
+                                pts = EXCLUDED.pts,
+                                qts = EXCLUDED.qts,
+                                date = EXCLUDED.date,
+                                seq = EXCLUDED.seq",
+                            &[
+                                account,
+                                &updates_state.pts,
+                                &updates_state.qts,
+                                &updates_state.date,
+                                &updates_state.seq,
+                            ],
+                        )
+                        .await?;
+                    transaction
+                        .execute("DELETE FROM channel_state WHERE account = $1", &[account])
+                        .await?;
+                    for channel in updates_state.channels {
+                        transaction
+                            .execute(
+                                "INSERT INTO channel_state VALUES ($1, $2, $3)",
+                                &[account, &channel.id, &channel.pts],
+                            )
+                            .await?;
+                    }
+                    transaction.commit().await?;
+                }
+                UpdateState::Primary { pts, date, seq } => {
+                    client
+                        .execute(
+                            "INSERT INTO update_state VALUES ($1, $2, 0, $3, $4)
+                            ON CONFLICT (account) DO UPDATE SET
+                                pts = EXCLUDED.pts,
+                                date = EXCLUDED.date,
+                                seq = EXCLUDED.seq",
+                            &[account, &pts, &date, &seq],
+                        )
+                        .await?;
+                }
+                UpdateState::Secondary { qts } => {
+                    client
+                        .execute(
+                            "INSERT INTO update_state VALUES ($1, 0, $2, 0, 0)
+                            ON CONFLICT (account) DO UPDATE SET qts = EXCLUDED.qts",
+                            &[account, &qts],
+                        )
+                        .await?;
+                }
+                UpdateState::Channel { id, pts } => {
+                    client
+                        .execute(
+                            "INSERT INTO channel_state VALUES ($1, $2, $3)
+                            ON CONFLICT (account, peer_id) DO UPDATE SET pts = EXCLUDED.pts",
+                            &[account, &id, &pts],
+                        )
+                        .await?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KNOWN_DC_OPTIONS;
+    use crate::types::{ChannelKind, PeerId, UpdatesState};
+
+    /// Connect to the database at `DATABASE_URL`, such as `"host=localhost user=postgres"`.
+    ///
+    /// The tests are skipped if the variable is not set.
+    async fn connect() -> Option<Client> {
+        let url = std::env::var("DATABASE_URL").ok()?;
+        let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        Some(client)
+    }
+
+    fn assert_empty(data: SessionData) {
+        let default = SessionData::default();
+        assert_eq!(data.home_dc, default.home_dc);
+        assert_eq!(data.dc_options, default.dc_options);
+        assert_eq!(data.peer_infos, default.peer_infos);
+        assert_eq!(data.updates_state, default.updates_state);
+    }
+
+    #[tokio::test]
+    async fn exercise_postgres_session() {
+        let Some(client) = connect().await else {
+            return;
+        };
+        let account = format!("grammers-test-{}", std::process::id());
+        let session = PostgresSession::new(client, &account).await.unwrap();
+        assert_empty(session.load().await.unwrap());
+
+        session.set_home_dc_id(4).await.unwrap();
+        let dc_option = DcOption {
+            id: 203,
+            media_only: true,
+            cdn: true,
+            auth_key: Some([2; 256]),
+            ..KNOWN_DC_OPTIONS[0].clone()
+        };
+        session.set_dc_option(&dc_option).await.unwrap();
+        let peers = [
+            PeerInfo::User {
+                id: 1,
+                auth: Some(PeerAuth::from_hash(2)),
+                bot: Some(true),
+                is_self: Some(true),
+            },
+            PeerInfo::Channel {
+                id: 3,
+                auth: Some(PeerAuth::from_hash(-4)),
+                kind: Some(ChannelKind::Gigagroup),
+            },
+        ];
+        for peer in &peers {
+            session.cache_peer(peer).await.unwrap();
+        }
+        session
+            .set_update_state(UpdateState::All(UpdatesState {
+                pts: 1,
+                qts: 2,
+                date: 3,
+                seq: 4,
+                channels: vec![ChannelState { id: 3, pts: 5 }],
+            }))
+            .await
+            .unwrap();
+        session
+            .set_update_state(UpdateState::Secondary { qts: 6 })
+            .await
+            .unwrap();
+        session
+            .set_update_state(UpdateState::Channel { id: 3, pts: 7 })
+            .await
+            .unwrap();
+
+        let data = session.load().await.unwrap();
+        assert_eq!(data.home_dc, 4);
+        assert_eq!(data.dc_options[&203], dc_option);
+        assert_eq!(data.peer_infos.len(), peers.len());
+        for peer in peers {
+            assert_eq!(data.peer_infos[&peer.id()], peer);
+        }
+        assert_eq!(data.peer_infos.get(&PeerId::user(3)), None);
+        assert_eq!(
+            data.updates_state,
+            UpdatesState {
+                pts: 1,
+                qts: 6,
+                date: 3,
+                seq: 4,
+                channels: vec![ChannelState { id: 3, pts: 7 }],
+            }
+        );
+
+        // Other accounts sharing the database must not see the data.
+        let client = session.client.into_inner();
+        let other = PostgresSession::new(client, format!("{account}-other"))
+            .await
+            .unwrap();
+        assert_empty(other.load().await.unwrap());
+
+        let client = other.client.into_inner();
+        for table in [
+            "dc_home",
+            "dc_option",
+            "peer_info",
+            "update_state",
+            "channel_state",
+        ] {
+            client
+                .execute(
+                    &format!("DELETE FROM {table} WHERE account = $1"),
+                    &[&account],
+                )
+                .await
+                .unwrap();
+        }
+    }
+}
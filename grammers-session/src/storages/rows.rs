@@ -0,0 +1,91 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//! Representation of the session values in the rows of SQL databases,
+//! shared by the storages backed by one.
+
+use crate::types::{ChannelKind, DcOption, PeerAuth, PeerId, PeerInfo, PeerKind};
+
+#[repr(u8)]
+pub(super) enum PeerSubtype {
+    UserSelf = 1,
+    UserBot = 2,
+    UserSelfBot = 3,
+    Megagroup = 4,
+    Broadcast = 8,
+    Gigagroup = 12,
+}
+
+#[repr(u8)]
+pub(super) enum DcOptionFlag {
+    MediaOnly = 1,
+    Cdn = 2,
+    Test = 4,
+    Static = 8,
+}
+
+/// Packs the flags of the datacenter option as stored in the database.
+pub(super) fn dc_option_flags(dc_option: &DcOption) -> u8 {
+    [
+        (dc_option.media_only, DcOptionFlag::MediaOnly),
+        (dc_option.cdn, DcOptionFlag::Cdn),
+        (dc_option.test, DcOptionFlag::Test),
+        (dc_option.r#static, DcOptionFlag::Static),
+    ]
+    .into_iter()
+    .filter(|(set, _)| *set)
+    .fold(0, |flags, (_, flag)| flags | flag as u8)
+}
+
+/// Subtype of the peer as stored in the database, if any.
+pub(super) fn peer_subtype(peer: &PeerInfo) -> Option<PeerSubtype> {
+    match peer {
+        PeerInfo::User { bot, is_self, .. } => {
+            match (bot.unwrap_or_default(), is_self.unwrap_or_default()) {
+                (true, true) => Some(PeerSubtype::UserSelfBot),
+                (true, false) => Some(PeerSubtype::UserBot),
+                (false, true) => Some(PeerSubtype::UserSelf),
+                (false, false) => None,
+            }
+        }
+        PeerInfo::Chat { .. } => None,
+        PeerInfo::Channel { kind, .. } => kind.map(|kind| match kind {
+            ChannelKind::Megagroup => PeerSubtype::Megagroup,
+            ChannelKind::Broadcast => PeerSubtype::Broadcast,
+            ChannelKind::Gigagroup => PeerSubtype::Gigagroup,
+        }),
+    }
+}
+
+/// Rebuilds the peer information from the values stored in the database.
+pub(super) fn peer_info(peer_id: i64, hash: Option<i64>, subtype: Option<u8>) -> PeerInfo {
+    let peer = PeerId::from_bot_api_dialog_id(peer_id);
+    match peer.kind() {
+        PeerKind::User | PeerKind::UserSelf => PeerInfo::User {
+            id: peer.bare_id(),
+            auth: hash.map(PeerAuth::from_hash),
+            bot: subtype.map(|s| s & PeerSubtype::UserBot as u8 != 0),
+            is_self: subtype.map(|s| s & PeerSubtype::UserSelf as u8 != 0),
+        },
+        PeerKind::Chat => PeerInfo::Chat { id: peer.bare_id() },
+        PeerKind::Channel => PeerInfo::Channel {
+            id: peer.bare_id(),
+            auth: hash.map(PeerAuth::from_hash),
+            kind: subtype.and_then(|s| {
+                if (s & PeerSubtype::Gigagroup as u8) == PeerSubtype::Gigagroup as _ {
+                    Some(ChannelKind::Gigagroup)
+                } else if s & PeerSubtype::Broadcast as u8 != 0 {
+                    Some(ChannelKind::Broadcast)
+                } else if s & PeerSubtype::Megagroup as u8 != 0 {
+                    Some(ChannelKind::Megagroup)
+                } else {
+                    None
+                }
+            }),
+        },
+    }
+}
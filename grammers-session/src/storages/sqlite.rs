@@ -6,33 +6,78 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use super::rows::{DcOptionFlag, PeerSubtype, dc_option_flags, peer_info, peer_subtype};
 use crate::types::{
-    ChannelKind, ChannelState, DcOption, PeerAuth, PeerId, PeerInfo, PeerKind, UpdateState,
-    UpdatesState,
+    ChannelState, DcOption, PeerAuth, PeerId, PeerInfo, PeerKind, UpdateState, UpdatesState,
 };
-use crate::{DEFAULT_DC, KNOWN_DC_OPTIONS, Session};
+use crate::{
+    DEFAULT_DC, ENCRYPTION_PARAMS_DC_ID, KNOWN_DC_OPTIONS, KNOWN_TEST_DC_OPTIONS, Session,
+    SessionFuture,
+};
+use log::error;
 use std::path::Path;
 use std::sync::Mutex;
 
-const VERSION: i64 = 1;
+const VERSION: i64 = 3;
 
 struct Database(sqlite::Connection);
 
-struct TransactionGuard<'c>(&'c sqlite::Connection);
+/// Open transaction, rolled back on drop unless it was committed.
+struct TransactionGuard<'c> {
+    connection: &'c sqlite::Connection,
+    committed: bool,
+}
 
 /// SQLite-based storage. This is the recommended option.
+///
+/// Because most [`Session`] methods cannot fail, errors from the database are logged and
+/// reported by the next [`Session::flush`] instead. Reads that fail panic, because carrying
+/// on as if nothing was stored could overwrite data that is, such as an authorization key.
 pub struct SqliteSession {
     database: Mutex<Database>,
+    test: bool,
+    error: Mutex<Option<sqlite::Error>>,
+}
+
+/// Reads the datacenter option in the current row.
+fn read_dc_option(stmt: &sqlite::Statement) -> sqlite::Result<DcOption> {
+    let flags = stmt.read::<i64, _>("flags")? as u8;
+    Ok(DcOption {
+        id: stmt.read::<i64, _>("dc_id")? as _,
+        ipv4: stmt
+            .read::<String, _>("ipv4")?
+            .parse()
+            .map_err(|_| invalid_data("stored IPv4 address is invalid"))?,
+        ipv6: stmt
+            .read::<String, _>("ipv6")?
+            .parse()
+            .map_err(|_| invalid_data("stored IPv6 address is invalid"))?,
+        media_only: flags & DcOptionFlag::MediaOnly as u8 != 0,
+        cdn: flags & DcOptionFlag::Cdn as u8 != 0,
+        test: flags & DcOptionFlag::Test as u8 != 0,
+        r#static: flags & DcOptionFlag::Static as u8 != 0,
+        auth_key: stmt
+            .read::<Option<Vec<u8>>, _>("auth_key")?
+            .map(|auth_key| auth_key.try_into())
+            .transpose()
+            .map_err(|_| invalid_data("stored auth key has an invalid length"))?,
+    })
+}
+
+fn invalid_data(message: &str) -> sqlite::Error {
+    sqlite::Error {
+        code: None,
+        message: Some(message.to_owned()),
+    }
 }
 
-#[repr(u8)]
-enum PeerSubtype {
-    UserSelf = 1,
-    UserBot = 2,
-    UserSelfBot = 3,
-    Megagroup = 4,
-    Broadcast = 8,
-    Gigagroup = 12,
+/// Reads the peer information in the current row.
+fn read_peer_info(stmt: &sqlite::Statement) -> sqlite::Result<PeerInfo> {
+    Ok(peer_info(
+        stmt.read::<i64, _>("peer_id")?,
+        stmt.read::<Option<i64>, _>("hash")?,
+        stmt.read::<Option<i64>, _>("subtype")?.map(|s| s as u8),
+    ))
 }
 
 impl Database {
@@ -48,6 +93,14 @@ impl Database {
             self.migrate_v0_to_v1()?;
             user_version += 1;
         }
+        if user_version == 1 {
+            self.migrate_v1_to_v2()?;
+            user_version += 1;
+        }
+        if user_version == 2 {
+            self.migrate_v2_to_v3()?;
+            user_version += 1;
+        }
         if user_version == VERSION {
             // Can't bind PRAGMA parameters, but `VERSION` is not user-controlled input.
             self.0.execute(format!("PRAGMA user_version = {VERSION}"))?;
@@ -56,7 +109,7 @@ impl Database {
     }
 
     fn migrate_v0_to_v1(&self) -> sqlite::Result<()> {
-        let _transaction = self.begin_transaction()?;
+        let transaction = self.begin_transaction()?;
         self.0.execute(
             "CREATE TABLE dc_home (
                 dc_id INTEGER NOT NULL,
@@ -90,13 +143,54 @@ impl Database {
                 pts INTEGER NOT NULL,
                 PRIMARY KEY (peer_id))",
         )?;
+        transaction.commit()
+    }
 
-        Ok(())
+    fn migrate_v1_to_v2(&self) -> sqlite::Result<()> {
+        let transaction = self.begin_transaction()?;
+        self.0
+            .execute("ALTER TABLE dc_option ADD COLUMN flags INTEGER NOT NULL DEFAULT 0")?;
+        transaction.commit()
+    }
+
+    fn migrate_v2_to_v3(&self) -> sqlite::Result<()> {
+        let transaction = self.begin_transaction()?;
+        self.0.execute(
+            "CREATE TABLE environment (
+                test INTEGER NOT NULL)",
+        )?;
+        transaction.commit()
+    }
+
+    /// Make sure the database belongs to the desired environment, claiming it if it's new.
+    fn check_environment(&self, test: bool) -> sqlite::Result<()> {
+        let stored = self.fetch_one("SELECT * FROM environment LIMIT 1", &[], |stmt| {
+            Ok(stmt.read::<i64, _>("test")? != 0)
+        })?;
+        match stored {
+            Some(stored) if stored == test => Ok(()),
+            Some(_) => Err(sqlite::Error {
+                code: None,
+                message: Some(format!(
+                    "session belongs to the {} environment",
+                    if test { "production" } else { "test" }
+                )),
+            }),
+            None => {
+                let mut stmt = self.0.prepare("INSERT INTO environment VALUES (:test)")?;
+                stmt.bind((":test", test as i64))?;
+                stmt.next()?;
+                Ok(())
+            }
+        }
     }
 
     fn begin_transaction(&self) -> sqlite::Result<TransactionGuard<'_>> {
         self.0.execute("BEGIN TRANSACTION")?;
-        Ok(TransactionGuard(&self.0))
+        Ok(TransactionGuard {
+            connection: &self.0,
+            committed: false,
+        })
     }
 
     fn fetch_one<T, F: FnOnce(sqlite::Statement) -> sqlite::Result<T>>(
@@ -130,9 +224,20 @@ impl Database {
     }
 }
 
+impl TransactionGuard<'_> {
+    fn commit(mut self) -> sqlite::Result<()> {
+        self.connection.execute("COMMIT")?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
 impl Drop for TransactionGuard<'_> {
     fn drop(&mut self) {
-        self.0.execute("COMMIT").unwrap();
+        if !self.committed {
+            // Nothing else can be done if even this fails.
+            let _ = self.connection.execute("ROLLBACK");
+        }
     }
 }
 
@@ -140,52 +245,85 @@ impl SqliteSession {
     /// Open a connection to the SQLite database at `path`,
     /// creating one if it doesn't exist.
     pub fn open<P: AsRef<Path>>(path: P) -> sqlite::Result<Self> {
+        Self::open_in(path, false)
+    }
+
+    /// Like [`SqliteSession::open`], but for Telegram's test environment.
+    ///
+    /// Databases remember which environment they were created for, and
+    /// opening them for the other environment will fail.
+    pub fn open_test<P: AsRef<Path>>(path: P) -> sqlite::Result<Self> {
+        Self::open_in(path, true)
+    }
+
+    fn open_in<P: AsRef<Path>>(path: P, test: bool) -> sqlite::Result<Self> {
         let database = Database(sqlite::Connection::open(path)?);
         database.init()?;
+        database.check_environment(test)?;
         Ok(SqliteSession {
             database: Mutex::new(database),
+            test,
+            error: Mutex::new(None),
         })
     }
+
+    /// Run the query against the database, panicking if it fails.
+    fn read<T>(&self, query: impl FnOnce(&Database) -> sqlite::Result<T>) -> T {
+        let db = self.database.lock().unwrap();
+        query(&db).unwrap_or_else(|e| panic!("failed to read from the sqlite session: {e}"))
+    }
+
+    /// Run the operation against the database, recording its error for the next flush, if any.
+    fn write(&self, operation: impl FnOnce(&Database) -> sqlite::Result<()>) {
+        let db = self.database.lock().unwrap();
+        if let Err(e) = operation(&db) {
+            error!("failed to write to the sqlite session: {e}");
+            self.error.lock().unwrap().get_or_insert(e);
+        }
+    }
+
+    /// Statically-known datacenter options for the environment of this session.
+    fn known_dc_options(&self) -> &'static [DcOption] {
+        if self.test {
+            &KNOWN_TEST_DC_OPTIONS
+        } else {
+            &KNOWN_DC_OPTIONS
+        }
+    }
 }
 
 impl Session for SqliteSession {
     fn home_dc_id(&self) -> i32 {
-        let db = self.database.lock().unwrap();
-        db.fetch_one("SELECT * FROM dc_home LIMIT 1", &[], |stmt| {
-            Ok(stmt.read::<i64, _>("dc_id")? as i32)
+        self.read(|db| {
+            db.fetch_one("SELECT * FROM dc_home LIMIT 1", &[], |stmt| {
+                Ok(stmt.read::<i64, _>("dc_id")? as i32)
+            })
         })
-        .unwrap()
         .unwrap_or(DEFAULT_DC)
     }
 
     fn set_home_dc_id(&self, dc_id: i32) {
-        let db = self.database.lock().unwrap();
-        let _transaction = db.begin_transaction().unwrap();
-        db.0.execute("DELETE FROM dc_home").unwrap();
-        let mut stmt = db.0.prepare("INSERT INTO dc_home VALUES (:dc_id)").unwrap();
-        stmt.bind((":dc_id", dc_id as i64)).unwrap();
-        stmt.next().unwrap();
+        self.write(|db| {
+            let transaction = db.begin_transaction()?;
+            db.0.execute("DELETE FROM dc_home")?;
+            let mut stmt = db.0.prepare("INSERT INTO dc_home VALUES (:dc_id)")?;
+            stmt.bind((":dc_id", dc_id as i64))?;
+            stmt.next()?;
+            drop(stmt);
+            transaction.commit()
+        });
     }
 
     fn dc_option(&self, dc_id: i32) -> Option<DcOption> {
-        let db = self.database.lock().unwrap();
-        db.fetch_one(
-            "SELECT * FROM dc_option WHERE dc_id = :dc_id LIMIT 1",
-            &[(":dc_id", sqlite::Value::Integer(dc_id as _))],
-            |stmt| {
-                Ok(DcOption {
-                    id: stmt.read::<i64, _>("dc_id")? as _,
-                    ipv4: stmt.read::<String, _>("ipv4")?.parse().unwrap(),
-                    ipv6: stmt.read::<String, _>("ipv6")?.parse().unwrap(),
-                    auth_key: stmt
-                        .read::<Option<Vec<u8>>, _>("auth_key")?
-                        .map(|auth_key| auth_key.try_into().unwrap()),
-                })
-            },
-        )
-        .unwrap()
+        self.read(|db| {
+            db.fetch_one(
+                "SELECT * FROM dc_option WHERE dc_id = :dc_id LIMIT 1",
+                &[(":dc_id", sqlite::Value::Integer(dc_id as _))],
+                |stmt| read_dc_option(&stmt),
+            )
+        })
         .or_else(|| {
-            KNOWN_DC_OPTIONS
+            self.known_dc_options()
                 .iter()
                 .find(|dc_option| dc_option.id == dc_id)
                 .cloned()
@@ -193,196 +331,181 @@ impl Session for SqliteSession {
     }
 
     fn set_dc_option(&self, dc_option: &DcOption) {
-        let db = self.database.lock().unwrap();
-        let mut stmt = db
-            .0
-            .prepare("INSERT OR REPLACE INTO dc_option VALUES (:dc_id, :ipv4, :ipv6, :auth_key)")
-            .unwrap();
-        stmt.bind((":dc_id", dc_option.id as i64)).unwrap();
-        stmt.bind((":ipv4", dc_option.ipv4.to_string().as_str()))
-            .unwrap();
-        stmt.bind((":ipv6", dc_option.ipv6.to_string().as_str()))
-            .unwrap();
-        if let Some(auth_key) = dc_option.auth_key {
-            stmt.bind((":auth_key", auth_key.as_slice())).unwrap();
-        }
-        stmt.next().unwrap();
+        self.write(|db| {
+            let mut stmt = db.0.prepare(
+                "INSERT OR REPLACE INTO dc_option VALUES (:dc_id, :ipv4, :ipv6, :auth_key, :flags)",
+            )?;
+            stmt.bind((":dc_id", dc_option.id as i64))?;
+            stmt.bind((":ipv4", dc_option.ipv4.to_string().as_str()))?;
+            stmt.bind((":ipv6", dc_option.ipv6.to_string().as_str()))?;
+            if let Some(auth_key) = dc_option.auth_key {
+                stmt.bind((":auth_key", auth_key.as_slice()))?;
+            }
+            stmt.bind((":flags", dc_option_flags(dc_option) as i64))?;
+            stmt.next()?;
+            Ok(())
+        });
     }
 
-    fn peer(&self, peer: PeerId) -> Option<PeerInfo> {
-        let db = self.database.lock().unwrap();
-        let map_stmt = |stmt: sqlite::Statement| {
-            let subtype = stmt.read::<Option<i64>, _>("subtype")?.map(|s| s as u8);
-            Ok(match peer.kind() {
-                PeerKind::User | PeerKind::UserSelf => PeerInfo::User {
-                    id: PeerId::user(stmt.read::<i64, _>("peer_id")?).bare_id(),
-                    auth: stmt
-                        .read::<Option<i64>, _>("hash")?
-                        .map(PeerAuth::from_hash),
-                    bot: subtype.map(|s| s & PeerSubtype::UserBot as u8 != 0),
-                    is_self: subtype.map(|s| s & PeerSubtype::UserSelf as u8 != 0),
-                },
-                PeerKind::Chat => PeerInfo::Chat { id: peer.bare_id() },
-                PeerKind::Channel => PeerInfo::Channel {
-                    id: peer.bare_id(),
-                    auth: stmt
-                        .read::<Option<i64>, _>("hash")?
-                        .map(PeerAuth::from_hash),
-                    kind: subtype.and_then(|s| {
-                        if (s & PeerSubtype::Gigagroup as u8) == PeerSubtype::Gigagroup as _ {
-                            Some(ChannelKind::Gigagroup)
-                        } else if s & PeerSubtype::Broadcast as u8 != 0 {
-                            Some(ChannelKind::Broadcast)
-                        } else if s & PeerSubtype::Megagroup as u8 != 0 {
-                            Some(ChannelKind::Megagroup)
-                        } else {
-                            None
-                        }
-                    }),
-                },
-            })
-        };
-
-        if peer.kind() == PeerKind::UserSelf {
-            db.fetch_one(
-                "SELECT * FROM peer_info WHERE subtype & :type LIMIT 1",
-                &[(":type", sqlite::Value::Integer(PeerSubtype::UserSelf as _))],
-                map_stmt,
-            )
-            .unwrap()
-        } else {
-            db.fetch_one(
-                "SELECT * FROM peer_info WHERE peer_id = :peer_id LIMIT 1",
-                &[(":peer_id", sqlite::Value::Integer(peer.bot_api_dialog_id()))],
-                map_stmt,
+    fn dc_options(&self) -> Vec<DcOption> {
+        let mut dc_options = self.read(|db| {
+            db.fetch_all(
+                "SELECT * FROM dc_option WHERE dc_id != :reserved",
+                &[(
+                    ":reserved",
+                    sqlite::Value::Integer(ENCRYPTION_PARAMS_DC_ID as _),
+                )],
+                read_dc_option,
             )
-            .unwrap()
-        }
+        });
+        let known_dc_options = self
+            .known_dc_options()
+            .iter()
+            .filter(|known| dc_options.iter().all(|dc_option| dc_option.id != known.id))
+            .cloned()
+            .collect::<Vec<_>>();
+        dc_options.extend(known_dc_options);
+        dc_options
+    }
+
+    fn peer(&self, peer: PeerId) -> Option<PeerInfo> {
+        let map_stmt = |stmt: sqlite::Statement| read_peer_info(&stmt);
+
+        self.read(|db| {
+            if peer.kind() == PeerKind::UserSelf {
+                db.fetch_one(
+                    "SELECT * FROM peer_info WHERE subtype & :type LIMIT 1",
+                    &[(":type", sqlite::Value::Integer(PeerSubtype::UserSelf as _))],
+                    map_stmt,
+                )
+            } else {
+                db.fetch_one(
+                    "SELECT * FROM peer_info WHERE peer_id = :peer_id LIMIT 1",
+                    &[(":peer_id", sqlite::Value::Integer(peer.bot_api_dialog_id()))],
+                    map_stmt,
+                )
+            }
+        })
     }
 
     fn cache_peer(&self, peer: &PeerInfo) {
-        let db = self.database.lock().unwrap();
-        let mut stmt =
-            db.0.prepare("INSERT OR REPLACE INTO peer_info VALUES (:peer_id, :hash, :subtype)")
-                .unwrap();
-        stmt.bind((":peer_id", peer.id().bot_api_dialog_id()))
-            .unwrap();
-        if peer.auth() != PeerAuth::default() {
-            stmt.bind((":hash", peer.auth().hash())).unwrap();
-        }
-        let subtype = match peer {
-            PeerInfo::User { bot, is_self, .. } => {
-                match (bot.unwrap_or_default(), is_self.unwrap_or_default()) {
-                    (true, true) => Some(PeerSubtype::UserSelfBot),
-                    (true, false) => Some(PeerSubtype::UserBot),
-                    (false, true) => Some(PeerSubtype::UserSelf),
-                    (false, false) => None,
-                }
+        self.write(|db| {
+            let mut stmt = db
+                .0
+                .prepare("INSERT OR REPLACE INTO peer_info VALUES (:peer_id, :hash, :subtype)")?;
+            stmt.bind((":peer_id", peer.id().bot_api_dialog_id()))?;
+            if peer.auth() != PeerAuth::default() {
+                stmt.bind((":hash", peer.auth().hash()))?;
             }
-            PeerInfo::Chat { .. } => None,
-            PeerInfo::Channel { kind, .. } => kind.map(|kind| match kind {
-                ChannelKind::Megagroup => PeerSubtype::Megagroup,
-                ChannelKind::Broadcast => PeerSubtype::Broadcast,
-                ChannelKind::Gigagroup => PeerSubtype::Gigagroup,
-            }),
-        };
-        if let Some(subtype) = subtype {
-            stmt.bind((":subtype", subtype as i64)).unwrap();
-        }
-        stmt.next().unwrap();
+            if let Some(subtype) = peer_subtype(peer) {
+                stmt.bind((":subtype", subtype as i64))?;
+            }
+            stmt.next()?;
+            Ok(())
+        });
+    }
+
+    fn peers(&self) -> Vec<PeerInfo> {
+        self.read(|db| db.fetch_all("SELECT * FROM peer_info", &[], read_peer_info))
     }
 
     fn updates_state(&self) -> UpdatesState {
-        let db = self.database.lock().unwrap();
-        let mut state = db
-            .fetch_one("SELECT * FROM update_state LIMIT 1", &[], |stmt| {
-                Ok(UpdatesState {
-                    pts: stmt.read::<i64, _>("pts")? as _,
-                    qts: stmt.read::<i64, _>("qts")? as _,
-                    date: stmt.read::<i64, _>("date")? as _,
-                    seq: stmt.read::<i64, _>("seq")? as _,
-                    channels: Vec::new(),
-                })
-            })
-            .unwrap()
-            .unwrap_or_default();
-        state.channels = db
-            .fetch_all("SELECT * FROM channel_state", &[], |stmt| {
+        self.read(|db| {
+            let mut state = db
+                .fetch_one("SELECT * FROM update_state LIMIT 1", &[], |stmt| {
+                    Ok(UpdatesState {
+                        pts: stmt.read::<i64, _>("pts")? as _,
+                        qts: stmt.read::<i64, _>("qts")? as _,
+                        date: stmt.read::<i64, _>("date")? as _,
+                        seq: stmt.read::<i64, _>("seq")? as _,
+                        channels: Vec::new(),
+                    })
+                })?
+                .unwrap_or_default();
+            state.channels = db.fetch_all("SELECT * FROM channel_state", &[], |stmt| {
                 Ok(ChannelState {
                     id: stmt.read::<i64, _>("peer_id")?,
                     pts: stmt.read::<i64, _>("pts")? as _,
                 })
-            })
-            .unwrap();
-        state
+            })?;
+            Ok(state)
+        })
     }
 
     fn set_update_state(&self, update: UpdateState) {
-        let db = self.database.lock().unwrap();
-        let _transaction = db.begin_transaction().unwrap();
-
-        match update {
-            UpdateState::All(updates_state) => {
-                db.0.execute("DELETE FROM update_state").unwrap();
-                let mut stmt =
-                    db.0.prepare("INSERT INTO update_state VALUES (:pts, :qts, :date, :seq)")
-                        .unwrap();
-                stmt.bind((":pts", updates_state.pts as i64)).unwrap();
-                stmt.bind((":qts", updates_state.qts as i64)).unwrap();
-                stmt.bind((":date", updates_state.date as i64)).unwrap();
-                stmt.bind((":seq", updates_state.seq as i64)).unwrap();
-                stmt.next().unwrap();
-
-                db.0.execute("DELETE FROM channel_state").unwrap();
-                for channel in updates_state.channels {
+        self.write(|db| {
+            let transaction = db.begin_transaction()?;
+
+            match update {
+                UpdateState::All(updates_state) => {
+                    db.0.execute("DELETE FROM update_state")?;
                     let mut stmt =
-                        db.0.prepare("INSERT INTO channel_state VALUES (:peer_id, :pts)")
-                            .unwrap();
-                    stmt.bind((":peer_id", channel.id as i64)).unwrap();
-                    stmt.bind((":pts", channel.pts as i64)).unwrap();
-                    stmt.next().unwrap();
+                        db.0.prepare("INSERT INTO update_state VALUES (:pts, :qts, :date, :seq)")?;
+                    stmt.bind((":pts", updates_state.pts as i64))?;
+                    stmt.bind((":qts", updates_state.qts as i64))?;
+                    stmt.bind((":date", updates_state.date as i64))?;
+                    stmt.bind((":seq", updates_state.seq as i64))?;
+                    stmt.next()?;
+
+                    db.0.execute("DELETE FROM channel_state")?;
+                    for channel in updates_state.channels {
+                        let mut stmt =
+                            db.0.prepare("INSERT INTO channel_state VALUES (:peer_id, :pts)")?;
+                        stmt.bind((":peer_id", channel.id as i64))?;
+                        stmt.bind((":pts", channel.pts as i64))?;
+                        stmt.next()?;
+                    }
+                }
+                UpdateState::Primary { pts, date, seq } => {
+                    let previous =
+                        db.fetch_one("SELECT * FROM update_state LIMIT 1", &[], |_| Ok(()))?;
+
+                    let mut stmt = if previous.is_some() {
+                        db.0.prepare(
+                            "UPDATE update_state SET pts = :pts, date = :date, seq = :seq",
+                        )?
+                    } else {
+                        db.0.prepare("INSERT INTO update_state VALUES (:pts, 0, :date, :seq)")?
+                    };
+                    stmt.bind((":pts", pts as i64))?;
+                    stmt.bind((":date", date as i64))?;
+                    stmt.bind((":seq", seq as i64))?;
+                    stmt.next()?;
+                }
+                UpdateState::Secondary { qts } => {
+                    let previous =
+                        db.fetch_one("SELECT * FROM update_state LIMIT 1", &[], |_| Ok(()))?;
+
+                    let mut stmt = if previous.is_some() {
+                        db.0.prepare("UPDATE update_state SET qts = :qts")?
+                    } else {
+                        db.0.prepare("INSERT INTO update_state VALUES (0, :qts, 0, 0)")?
+                    };
+                    stmt.bind((":qts", qts as i64))?;
+                    stmt.next()?;
+                }
+                UpdateState::Channel { id, pts } => {
+                    let mut stmt = db
+                        .0
+                        .prepare("INSERT OR REPLACE INTO channel_state VALUES (:peer_id, :pts)")?;
+                    stmt.bind((":peer_id", id))?;
+                    stmt.bind((":pts", pts as i64))?;
+                    stmt.next()?;
                 }
             }
-            UpdateState::Primary { pts, date, seq } => {
-                let previous = db
-                    .fetch_one("SELECT * FROM update_state LIMIT 1", &[], |_| Ok(()))
-                    .unwrap();
-
-                let mut stmt = if previous.is_some() {
-                    db.0.prepare("UPDATE update_state SET pts = :pts, date = :date, seq = :seq")
-                        .unwrap()
-                } else {
-                    db.0.prepare("INSERT INTO update_state VALUES (:pts, 0, :date, :seq)")
-                        .unwrap()
-                };
-                stmt.bind((":pts", pts as i64)).unwrap();
-                stmt.bind((":date", date as i64)).unwrap();
-                stmt.bind((":seq", seq as i64)).unwrap();
-                stmt.next().unwrap();
-            }
-            UpdateState::Secondary { qts } => {
-                let previous = db
-                    .fetch_one("SELECT * FROM update_state LIMIT 1", &[], |_| Ok(()))
-                    .unwrap();
-
-                let mut stmt = if previous.is_some() {
-                    db.0.prepare("UPDATE update_state SET qts = :qts").unwrap()
-                } else {
-                    db.0.prepare("INSERT INTO update_state VALUES (0, :qts, 0, 0)")
-                        .unwrap()
-                };
-                stmt.bind((":qts", qts as i64)).unwrap();
-                stmt.next().unwrap();
-            }
-            UpdateState::Channel { id, pts } => {
-                let mut stmt =
-                    db.0.prepare("INSERT OR REPLACE INTO channel_state VALUES (:peer_id, :pts)")
-                        .unwrap();
-                stmt.bind((":peer_id", id)).unwrap();
-                stmt.bind((":pts", pts as i64)).unwrap();
-                stmt.next().unwrap();
+
+            transaction.commit()
+        });
+    }
+
+    fn flush(&self) -> SessionFuture<'_, ()> {
+        let error = self.error.lock().unwrap().take();
+        Box::pin(async move {
+            match error {
+                Some(e) => Err(e.into()),
+                None => Ok(()),
             }
-        }
+        })
     }
 }
 
@@ -393,6 +516,9 @@ mod tests {
     use {DcOption, KNOWN_DC_OPTIONS, PeerInfo, Session, UpdateState};
 
     use super::*;
+    use crate::SessionData;
+    use crate::storages::MemorySession;
+    use crate::types::ChannelKind;
 
     #[test]
     fn exercise_sqlite_session() {
@@ -415,10 +541,24 @@ mod tests {
                 + 1,
             ipv4: SocketAddrV4::new(Ipv4Addr::from_bits(0), 1),
             ipv6: SocketAddrV6::new(Ipv6Addr::from_bits(0), 1, 0, 0),
+            media_only: true,
+            cdn: false,
+            test: true,
+            r#static: false,
             auth_key: Some([1; 256]),
         };
         assert_eq!(session.dc_option(new_dc_option.id), None);
         session.set_dc_option(&new_dc_option);
+        session.set_dc_option(&DcOption {
+            id: ENCRYPTION_PARAMS_DC_ID,
+            ..new_dc_option.clone()
+        });
+        assert!(
+            session
+                .dc_options()
+                .iter()
+                .all(|dc_option| dc_option.id != ENCRYPTION_PARAMS_DC_ID)
+        );
         assert_eq!(session.dc_option(new_dc_option.id), Some(new_dc_option));
 
         assert_eq!(session.peer(PeerId::self_user()), None);
@@ -474,4 +614,105 @@ mod tests {
             }
         );
     }
+
+    #[tokio::test]
+    async fn sqlite_errors_are_reported_on_flush() {
+        let session = SqliteSession::open(":memory:").unwrap();
+        session.set_home_dc_id(DEFAULT_DC + 1);
+        session.flush().await.unwrap();
+
+        session
+            .database
+            .lock()
+            .unwrap()
+            .0
+            .execute("DROP TABLE dc_home")
+            .unwrap();
+        session.set_home_dc_id(DEFAULT_DC + 2);
+        assert!(session.flush().await.is_err());
+        session.flush().await.unwrap();
+
+        // Failed transactions must not be left open.
+        session.set_update_state(UpdateState::Secondary { qts: 1 });
+        assert_eq!(session.updates_state().qts, 1);
+        session.flush().await.unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "failed to read from the sqlite session")]
+    fn sqlite_read_errors_are_not_hidden() {
+        let session = SqliteSession::open(":memory:").unwrap();
+        session
+            .database
+            .lock()
+            .unwrap()
+            .0
+            .execute("DROP TABLE dc_option")
+            .unwrap();
+
+        // Falling back to the known option would mean forgetting the stored authorization key.
+        session.dc_option(DEFAULT_DC);
+    }
+
+    #[test]
+    fn copy_memory_session_to_sqlite() {
+        let memory = MemorySession::default();
+        memory.set_home_dc_id(4);
+        memory.set_dc_option(&DcOption {
+            id: 203,
+            auth_key: Some([2; 256]),
+            ..KNOWN_DC_OPTIONS[0].clone()
+        });
+        memory.cache_peer(&PeerInfo::User {
+            id: 1,
+            auth: Some(PeerAuth::from_hash(2)),
+            bot: Some(false),
+            is_self: Some(true),
+        });
+        memory.cache_peer(&PeerInfo::Channel {
+            id: 3,
+            auth: Some(PeerAuth::from_hash(4)),
+            kind: Some(ChannelKind::Megagroup),
+        });
+        memory.set_update_state(UpdateState::Channel { id: 3, pts: 5 });
+
+        let sqlite = SqliteSession::open(":memory:").unwrap();
+        SessionData::from(memory).import_to(&sqlite);
+        let memory = MemorySession::default();
+        SessionData::from(sqlite).import_to(&memory);
+
+        let data = SessionData::from(memory);
+        assert_eq!(data.home_dc, 4);
+        assert_eq!(data.dc_options.len(), KNOWN_DC_OPTIONS.len() + 1);
+        assert_eq!(data.dc_options[&203].auth_key, Some([2; 256]));
+        assert_eq!(data.peer_infos.len(), 2);
+        assert_eq!(
+            data.peer_infos[&PeerId::channel(3)].auth(),
+            PeerAuth::from_hash(4)
+        );
+        assert_eq!(
+            data.updates_state.channels,
+            [ChannelState { id: 3, pts: 5 }]
+        );
+    }
+
+    #[test]
+    fn sqlite_session_environment() {
+        let path = std::env::temp_dir().join(format!(
+            "grammers-session-environment-{}.session",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let session = SqliteSession::open_test(&path).unwrap();
+        assert_eq!(
+            session.dc_option(KNOWN_TEST_DC_OPTIONS[0].id),
+            Some(KNOWN_TEST_DC_OPTIONS[0].clone())
+        );
+        drop(session);
+
+        assert!(SqliteSession::open(&path).is_err());
+        assert!(SqliteSession::open_test(&path).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,443 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Conversions between [`SessionData`] and the string sessions used by other libraries.
+
+use crate::SessionData;
+use crate::types::{DcOption, PeerId, PeerInfo};
+use base64::engine::general_purpose::{self, GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::{DecodePaddingMode, Engine};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+/// Libraries differ on whether they pad their strings, so both are accepted.
+const DECODER: GeneralPurpose = GeneralPurpose::new(
+    &base64::alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+const TELETHON_VERSION: char = '1';
+
+const NATIVE_VERSION: u8 = 1;
+
+const NATIVE_FLAG_BOT: u8 = 1;
+
+/// Error returned when converting to or from a string session fails.
+#[derive(Debug, PartialEq, Eq)]
+pub enum StringSessionError {
+    /// The string is not a valid session in the expected format.
+    Malformed,
+    /// The home datacenter has no authorization key, so there is nothing worth exporting.
+    MissingAuthKey,
+    /// The format requires the logged-in user, but it is not cached in the session.
+    MissingSelfUser,
+    /// The session cannot be represented in the format, such as a datacenter whose
+    /// identifier does not fit, or one from the test environment where that is not recorded.
+    Unsupported,
+}
+
+impl std::error::Error for StringSessionError {}
+
+impl fmt::Display for StringSessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "malformed string session"),
+            Self::MissingAuthKey => write!(f, "session has no auth key for its home dc"),
+            Self::MissingSelfUser => write!(f, "session has no cached self user"),
+            Self::Unsupported => write!(f, "session cannot be represented in this format"),
+        }
+    }
+}
+
+/// Identifier of the datacenter in the single byte that other libraries use for it.
+fn short_dc_id(dc_option: &DcOption) -> Result<u8, StringSessionError> {
+    u8::try_from(dc_option.id).map_err(|_| StringSessionError::Unsupported)
+}
+
+/// Sequential reader over the decoded bytes of a string session.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], StringSessionError> {
+        let (bytes, rest) = self
+            .0
+            .split_first_chunk()
+            .ok_or(StringSessionError::Malformed)?;
+        self.0 = rest;
+        Ok(*bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, StringSessionError> {
+        self.bytes().map(|[b]| b)
+    }
+
+    fn finish(self) -> Result<(), StringSessionError> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(StringSessionError::Malformed)
+        }
+    }
+}
+
+impl SessionData {
+    /// Parses a [Telethon `StringSession`](https://docs.telethon.dev/en/stable/concepts/sessions.html#string-sessions).
+    ///
+    /// Only the home datacenter, its address and its authorization key are included in these.
+    /// Telethon does not record whether the session belongs to the test environment,
+    /// so production is assumed.
+    pub fn from_telethon_string(string: &str) -> Result<Self, StringSessionError> {
+        let bytes = string
+            .strip_prefix(TELETHON_VERSION)
+            .and_then(|string| DECODER.decode(string).ok())
+            .ok_or(StringSessionError::Malformed)?;
+
+        // Layout: dc_id, ip (either 4 or 16 bytes), port, auth_key; all big-endian.
+        let mut reader = Reader(&bytes);
+        let dc_id = reader.u8()? as i32;
+        let ip = match bytes.len() {
+            263 => IpAddr::from(reader.bytes::<4>()?),
+            275 => IpAddr::from(reader.bytes::<16>()?),
+            _ => return Err(StringSessionError::Malformed),
+        };
+        let port = u16::from_be_bytes(reader.bytes()?);
+        let auth_key = reader.bytes()?;
+        reader.finish()?;
+
+        let mut data = Self::default();
+        let dc_option = data.home_dc_option(dc_id, false);
+        match ip {
+            IpAddr::V4(ip) => dc_option.ipv4 = SocketAddrV4::new(ip, port),
+            IpAddr::V6(ip) => dc_option.ipv6 = SocketAddrV6::new(ip, port, 0, 0),
+        }
+        dc_option.auth_key = Some(auth_key);
+        Ok(data)
+    }
+
+    /// Formats the session as a Telethon `StringSession`, with the IPv4 address of the home
+    /// datacenter, unless only the IPv6 one is known.
+    ///
+    /// Sessions for the test environment cannot be exported, as the format can't tell them apart.
+    pub fn to_telethon_string(&self) -> Result<String, StringSessionError> {
+        let (dc_option, auth_key) = self.home_auth_key()?;
+        if dc_option.test {
+            return Err(StringSessionError::Unsupported);
+        }
+
+        let mut bytes = vec![short_dc_id(dc_option)?];
+        if dc_option.ipv4.ip().is_unspecified() {
+            bytes.extend(dc_option.ipv6.ip().octets());
+            bytes.extend(dc_option.ipv6.port().to_be_bytes());
+        } else {
+            bytes.extend(dc_option.ipv4.ip().octets());
+            bytes.extend(dc_option.ipv4.port().to_be_bytes());
+        }
+        bytes.extend(auth_key);
+
+        Ok(format!(
+            "{TELETHON_VERSION}{}",
+            general_purpose::URL_SAFE.encode(bytes)
+        ))
+    }
+
+    /// Parses a [Pyrogram session string](https://docs.pyrogram.org/topics/storage-engines#session-strings),
+    /// in either its current format or one of the older ones.
+    ///
+    /// Only the home datacenter, its authorization key and the logged-in user are included in
+    /// these. The hash of the logged-in user is not known, but it is not needed to use the API.
+    pub fn from_pyrogram_string(string: &str) -> Result<Self, StringSessionError> {
+        let bytes = DECODER
+            .decode(string)
+            .map_err(|_| StringSessionError::Malformed)?;
+
+        // Layout: dc_id, api_id (not in older versions), test_mode, auth_key, user_id (32 bits
+        // in the oldest version), is_bot; all big-endian.
+        let mut reader = Reader(&bytes);
+        let dc_id = reader.u8()? as i32;
+        if bytes.len() == 271 {
+            let _api_id = reader.bytes::<4>()?;
+        }
+        let test = reader.u8()? != 0;
+        let auth_key = reader.bytes()?;
+        let user_id = match bytes.len() {
+            263 => u32::from_be_bytes(reader.bytes()?) as i64,
+            267 | 271 => i64::from_be_bytes(reader.bytes()?),
+            _ => return Err(StringSessionError::Malformed),
+        };
+        let bot = reader.u8()? != 0;
+        reader.finish()?;
+
+        let mut data = if test { Self::test() } else { Self::default() };
+        data.home_dc_option(dc_id, test).auth_key = Some(auth_key);
+        data.insert_self_user(user_id, bot)?;
+        Ok(data)
+    }
+
+    /// Formats the session as a Pyrogram session string, in its current format.
+    ///
+    /// Pyrogram also stores the identifier of the application, which sessions know nothing
+    /// about, so it must be the `api_id` the session will be used with.
+    pub fn to_pyrogram_string(&self, api_id: i32) -> Result<String, StringSessionError> {
+        let (dc_option, auth_key) = self.home_auth_key()?;
+        let (user_id, bot) = self.self_user()?;
+
+        let mut bytes = vec![short_dc_id(dc_option)?];
+        bytes.extend(api_id.to_be_bytes());
+        bytes.push(dc_option.test as u8);
+        bytes.extend(auth_key);
+        bytes.extend(user_id.to_be_bytes());
+        bytes.push(bot as u8);
+
+        Ok(general_purpose::URL_SAFE_NO_PAD.encode(bytes))
+    }
+
+    /// Parses a string produced by [`Self::to_native_string`].
+    pub fn from_native_string(string: &str) -> Result<Self, StringSessionError> {
+        let bytes = DECODER
+            .decode(string)
+            .map_err(|_| StringSessionError::Malformed)?;
+
+        let mut reader = Reader(&bytes);
+        if reader.u8()? != NATIVE_VERSION {
+            return Err(StringSessionError::Malformed);
+        }
+        let flags = reader.u8()?;
+        let home_dc = i32::from_le_bytes(reader.bytes()?);
+        let user_id = i64::from_le_bytes(reader.bytes()?);
+        let dc_count = reader.u8()?;
+
+        let mut dc_options = Vec::with_capacity(dc_count as usize);
+        for _ in 0..dc_count {
+            let id = i32::from_le_bytes(reader.bytes()?);
+            let ipv4 = Ipv4Addr::from(reader.bytes::<4>()?);
+            let ipv4_port = u16::from_le_bytes(reader.bytes()?);
+            let ipv6 = Ipv6Addr::from(reader.bytes::<16>()?);
+            let ipv6_port = u16::from_le_bytes(reader.bytes()?);
+            let dc_flags = reader.u8()?;
+            let auth_key = reader.bytes()?;
+            dc_options.push(DcOption {
+                id,
+                ipv4: SocketAddrV4::new(ipv4, ipv4_port),
+                ipv6: SocketAddrV6::new(ipv6, ipv6_port, 0, 0),
+                media_only: dc_flags & 1 != 0,
+                cdn: dc_flags & 2 != 0,
+                test: dc_flags & 4 != 0,
+                r#static: dc_flags & 8 != 0,
+                auth_key: Some(auth_key),
+            });
+        }
+        reader.finish()?;
+
+        let mut data = if dc_options.iter().any(|dc_option| dc_option.test) {
+            Self::test()
+        } else {
+            Self::default()
+        };
+        data.home_dc = home_dc;
+        data.dc_options.extend(
+            dc_options
+                .into_iter()
+                .map(|dc_option| (dc_option.id, dc_option)),
+        );
+        if user_id != 0 {
+            data.insert_self_user(user_id, flags & NATIVE_FLAG_BOT != 0)?;
+        }
+        Ok(data)
+    }
+
+    /// Formats the session in a compact string with everything needed to resume it: every
+    /// datacenter option with an authorization key, the home datacenter, and the logged-in
+    /// user if known.
+    ///
+    /// Unlike the strings of other libraries, it can carry more than one authorization key,
+    /// but cached peers and the update state are still left out.
+    pub fn to_native_string(&self) -> Result<String, StringSessionError> {
+        self.home_auth_key()?;
+        let (user_id, bot) = self.self_user().unwrap_or((0, false));
+
+        let mut dc_options = self
+            .dc_options
+            .values()
+            .filter(|dc_option| dc_option.auth_key.is_some())
+            .collect::<Vec<_>>();
+        dc_options.sort_by_key(|dc_option| dc_option.id);
+
+        let mut bytes = vec![NATIVE_VERSION, if bot { NATIVE_FLAG_BOT } else { 0 }];
+        bytes.extend(self.home_dc.to_le_bytes());
+        bytes.extend(user_id.to_le_bytes());
+        bytes.push(u8::try_from(dc_options.len()).map_err(|_| StringSessionError::Unsupported)?);
+        for dc_option in dc_options {
+            bytes.extend(dc_option.id.to_le_bytes());
+            bytes.extend(dc_option.ipv4.ip().octets());
+            bytes.extend(dc_option.ipv4.port().to_le_bytes());
+            bytes.extend(dc_option.ipv6.ip().octets());
+            bytes.extend(dc_option.ipv6.port().to_le_bytes());
+            bytes.push(
+                [
+                    dc_option.media_only,
+                    dc_option.cdn,
+                    dc_option.test,
+                    dc_option.r#static,
+                ]
+                .into_iter()
+                .enumerate()
+                .fold(0, |flags, (i, set)| flags | ((set as u8) << i)),
+            );
+            bytes.extend(dc_option.auth_key.unwrap());
+        }
+
+        Ok(general_purpose::URL_SAFE_NO_PAD.encode(bytes))
+    }
+
+    /// Makes the datacenter home, creating an option without addresses if it is not known.
+    fn home_dc_option(&mut self, dc_id: i32, test: bool) -> &mut DcOption {
+        self.home_dc = dc_id;
+        self.dc_options.entry(dc_id).or_insert_with(|| DcOption {
+            id: dc_id,
+            ipv4: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+            ipv6: SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0),
+            media_only: false,
+            cdn: false,
+            test,
+            r#static: false,
+            auth_key: None,
+        })
+    }
+
+    fn home_auth_key(&self) -> Result<(&DcOption, [u8; 256]), StringSessionError> {
+        self.dc_options
+            .get(&self.home_dc)
+            .and_then(|dc_option| Some((dc_option, dc_option.auth_key?)))
+            .ok_or(StringSessionError::MissingAuthKey)
+    }
+
+    fn self_user(&self) -> Result<(i64, bool), StringSessionError> {
+        self.peer_infos
+            .values()
+            .find_map(|peer| match peer {
+                PeerInfo::User {
+                    id,
+                    bot,
+                    is_self: Some(true),
+                    ..
+                } => Some((*id, bot.unwrap_or_default())),
+                _ => None,
+            })
+            .ok_or(StringSessionError::MissingSelfUser)
+    }
+
+    fn insert_self_user(&mut self, user_id: i64, bot: bool) -> Result<(), StringSessionError> {
+        // https://core.telegram.org/api/bots/ids#user-ids
+        if !(1..=0xffffffffff).contains(&user_id) {
+            return Err(StringSessionError::Malformed);
+        }
+        self.peer_infos.insert(
+            PeerId::user(user_id),
+            PeerInfo::User {
+                id: user_id,
+                auth: None,
+                bot: Some(bot),
+                is_self: Some(true),
+            },
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TELETHON_STRING: &str = "1ApWapzMBuwABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4fICEiIyQlJicoKSorLC0uLzAxMjM0NTY3ODk6Ozw9Pj9AQUJDREVGR0hJSktMTU5PUFFSU1RVVldYWVpbXF1eX2BhYmNkZWZnaGlqa2xtbm9wcXJzdHV2d3h5ent8fX5_gIGCg4SFhoeIiYqLjI2Oj5CRkpOUlZaXmJmam5ydnp-goaKjpKWmp6ipqqusra6vsLGys7S1tre4ubq7vL2-v8DBwsPExcbHyMnKy8zNzs_Q0dLT1NXW19jZ2tvc3d7f4OHi4-Tl5ufo6err7O3u7_Dx8vP09fb3-Pn6-_z9_v8=";
+
+    const PYROGRAM_STRING: &str = "AgAAMDkAAAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8gISIjJCUmJygpKissLS4vMDEyMzQ1Njc4OTo7PD0-P0BBQkNERUZHSElKS0xNTk9QUVJTVFVWV1hZWltcXV5fYGFiY2RlZmdoaWprbG1ub3BxcnN0dXZ3eHl6e3x9fn-AgYKDhIWGh4iJiouMjY6PkJGSk5SVlpeYmZqbnJ2en6ChoqOkpaanqKmqq6ytrq-wsbKztLW2t7i5uru8vb6_wMHCw8TFxsfIycrLzM3Oz9DR0tPU1dbX2Nna29zd3t_g4eLj5OXm5-jp6uvs7e7v8PHy8_T19vf4-fr7_P3-_wAAAAAHW80VAQ";
+
+    fn auth_key() -> [u8; 256] {
+        std::array::from_fn(|i| i as u8)
+    }
+
+    #[test]
+    fn telethon_string_roundtrip() {
+        let data = SessionData::from_telethon_string(TELETHON_STRING).unwrap();
+        assert_eq!(data.home_dc, 2);
+        let dc_option = &data.dc_options[&2];
+        assert_eq!(dc_option.ipv4.to_string(), "149.154.167.51:443");
+        assert_eq!(dc_option.auth_key, Some(auth_key()));
+
+        assert_eq!(data.to_telethon_string().unwrap(), TELETHON_STRING);
+    }
+
+    #[test]
+    fn pyrogram_string_roundtrip() {
+        let data = SessionData::from_pyrogram_string(PYROGRAM_STRING).unwrap();
+        assert_eq!(data.home_dc, 2);
+        assert_eq!(data.dc_options[&2].auth_key, Some(auth_key()));
+        assert_eq!(
+            data.peer_infos[&PeerId::user(123456789)],
+            PeerInfo::User {
+                id: 123456789,
+                auth: None,
+                bot: Some(true),
+                is_self: Some(true),
+            }
+        );
+
+        assert_eq!(data.to_pyrogram_string(12345).unwrap(), PYROGRAM_STRING);
+    }
+
+    #[test]
+    fn native_string_roundtrip() {
+        let mut data = SessionData::from_pyrogram_string(PYROGRAM_STRING).unwrap();
+        data.dc_options.get_mut(&4).unwrap().auth_key = Some([4; 256]);
+
+        let imported = SessionData::from_native_string(&data.to_native_string().unwrap()).unwrap();
+        assert_eq!(imported.home_dc, data.home_dc);
+        assert_eq!(imported.dc_options, data.dc_options);
+        assert_eq!(imported.peer_infos, data.peer_infos);
+    }
+
+    #[test]
+    fn invalid_strings_are_rejected() {
+        assert_eq!(
+            SessionData::default().to_telethon_string(),
+            Err(StringSessionError::MissingAuthKey)
+        );
+        assert_eq!(
+            SessionData::from_telethon_string(&TELETHON_STRING[1..]).err(),
+            Some(StringSessionError::Malformed)
+        );
+        assert_eq!(
+            SessionData::from_pyrogram_string(&PYROGRAM_STRING[..100]).err(),
+            Some(StringSessionError::Malformed)
+        );
+    }
+
+    #[test]
+    fn unrepresentable_sessions_are_rejected() {
+        let mut data = SessionData::default();
+        let dc_option = data.home_dc_option(2, true);
+        dc_option.test = true;
+        dc_option.auth_key = Some(auth_key());
+        assert_eq!(
+            data.to_telethon_string(),
+            Err(StringSessionError::Unsupported)
+        );
+
+        let mut data = SessionData::default();
+        data.home_dc_option(1000, false).auth_key = Some(auth_key());
+        assert_eq!(
+            data.to_telethon_string(),
+            Err(StringSessionError::Unsupported)
+        );
+        data.insert_self_user(1, false).unwrap();
+        assert_eq!(
+            data.to_pyrogram_string(1),
+            Err(StringSessionError::Unsupported)
+        );
+    }
+}
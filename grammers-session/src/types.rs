@@ -31,8 +31,22 @@ pub struct DcOption {
     pub id: i32,
     /// IPv4 address corresponding to this datacenter.
     pub ipv4: SocketAddrV4,
-    /// IPv6 address corresponding to this datacenter. May actually be embedding the [`Self::ipv4`] address.
+    /// IPv6 address corresponding to this datacenter.
+    ///
+    /// May actually be embedding the [`Self::ipv4`] address if the real one is not known.
     pub ipv6: SocketAddrV6,
+    /// Whether the addresses should only be used to download media.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub media_only: bool,
+    /// Whether this datacenter belongs to the [Content Delivery Network](https://core.telegram.org/cdn).
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub cdn: bool,
+    /// Whether this datacenter belongs to the test environment rather than production.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub test: bool,
+    /// Whether the addresses are static, and thus suitable when connecting through a proxy.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub r#static: bool,
     /// Permanent authentication key generated for encrypted communication with this datacenter.
     ///
     /// A logged-in user may or not be bound to this authentication key.
@@ -71,6 +85,7 @@ pub struct ChannelState {
 }
 
 /// Used in [`crate::Session::set_update_state`] to update parts of the overall [`UpdatesState`].
+#[derive(Clone, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum UpdateState {
     /// Updates the entirety of the state.